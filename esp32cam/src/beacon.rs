@@ -0,0 +1,51 @@
+//! Broadcasts a small JSON beacon every second so the client can find the
+//! camera where mDNS multicast is blocked, see `wifi_tank/src/beacon.rs`.
+
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::warn;
+use serde::Serialize;
+
+pub const BEACON_PORT: u16 = 8090;
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize)]
+struct Beacon {
+    role: &'static str,
+    id: String,
+    name: String,
+    version: &'static str,
+    port: u16,
+    partner: Option<String>,
+}
+
+/// Stable device ID from the MAC address, as hex.
+pub fn device_id(mac: [u8; 6]) -> String {
+    mac.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn spawn(id: String, name: String, partner: Option<String>) -> Result<()> {
+    let beacon = serde_json::to_vec(&Beacon {
+        role: "camera",
+        id,
+        name,
+        version: env!("CARGO_PKG_VERSION"),
+        port: 80,
+        partner,
+    })?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+
+    std::thread::Builder::new()
+        .name("beacon".into())
+        .stack_size(4096)
+        .spawn(move || loop {
+            if let Err(err) = socket.send_to(&beacon, ("255.255.255.255", BEACON_PORT)) {
+                warn!("Failed to send beacon: {:?}", err);
+            }
+            std::thread::sleep(BEACON_INTERVAL);
+        })?;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! BLE GATT provisioning service, the same simple service as the rover
+//! (see `wifi_tank/src/ble_provisioning.rs` for the layout).
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use esp32_nimble::{uuid128, BLEAdvertisementData, BLEDevice, NimbleProperties};
+use log::info;
+
+use crate::provisioning::{CredentialStore, WifiCredentials};
+
+const COMMAND_COMMIT: u8 = 0x01;
+const COMMAND_FORGET: u8 = 0x02;
+
+#[derive(Default)]
+struct ProvisioningState {
+    ssid: Vec<u8>,
+    password: Vec<u8>,
+    name: Vec<u8>,
+}
+
+impl ProvisioningState {
+    fn commit(&self, store: &CredentialStore) -> Result<()> {
+        let credentials = WifiCredentials {
+            ssid: String::from_utf8(self.ssid.clone())?,
+            password: String::from_utf8(self.password.clone())?,
+        };
+        if !self.name.is_empty() {
+            store.save_device_name(std::str::from_utf8(&self.name)?)?;
+        }
+        store.add_network(&credentials)
+    }
+}
+
+fn reboot_soon() {
+    std::thread::spawn(|| {
+        // Give the client a chance to read the status first.
+        std::thread::sleep(std::time::Duration::from_millis(1500));
+        esp_idf_hal::reset::restart();
+    });
+}
+
+/// Starts advertising the provisioning service, it keeps running in the
+/// NimBLE host task.
+pub fn start(store: CredentialStore, device_name: &str) -> Result<()> {
+    let device = BLEDevice::take();
+    BLEDevice::set_device_name(device_name)?;
+    let server = device.get_server();
+    let state = Arc::new(Mutex::new(ProvisioningState::default()));
+
+    let service = server.create_service(uuid128!("7e5a0001-2f4b-4c3a-9b8e-1f2d3c4b5a60"));
+    let status = service.lock().create_characteristic(
+        uuid128!("7e5a0006-2f4b-4c3a-9b8e-1f2d3c4b5a60"),
+        NimbleProperties::READ,
+    );
+    status.lock().set_value(b"idle");
+
+    let fields: [(_, fn(&mut ProvisioningState) -> &mut Vec<u8>); 3] = [
+        (uuid128!("7e5a0002-2f4b-4c3a-9b8e-1f2d3c4b5a60"), |s| {
+            &mut s.ssid
+        }),
+        (uuid128!("7e5a0003-2f4b-4c3a-9b8e-1f2d3c4b5a60"), |s| {
+            &mut s.password
+        }),
+        (uuid128!("7e5a0004-2f4b-4c3a-9b8e-1f2d3c4b5a60"), |s| {
+            &mut s.name
+        }),
+    ];
+    for (uuid, field) in fields {
+        let state = state.clone();
+        service
+            .lock()
+            .create_characteristic(uuid, NimbleProperties::WRITE)
+            .lock()
+            .on_write(move |args| {
+                *field(&mut state.lock().unwrap()) = args.recv_data().to_vec();
+            });
+    }
+
+    let command_status = status.clone();
+    service
+        .lock()
+        .create_characteristic(
+            uuid128!("7e5a0005-2f4b-4c3a-9b8e-1f2d3c4b5a60"),
+            NimbleProperties::WRITE,
+        )
+        .lock()
+        .on_write(move |args| {
+            let result = match args.recv_data().first() {
+                Some(&COMMAND_COMMIT) => state
+                    .lock()
+                    .unwrap()
+                    .commit(&store)
+                    .map(|()| "ok: saved, rebooting"),
+                Some(&COMMAND_FORGET) => store.forget_all().map(|()| "ok: forgotten, rebooting"),
+                _ => Err(anyhow::anyhow!("unknown command")),
+            };
+            let status = match result {
+                Ok(status) => {
+                    reboot_soon();
+                    status.to_string()
+                }
+                Err(err) => format!("error: {}", err),
+            };
+            info!("BLE provisioning: {}", status);
+            command_status.lock().set_value(status.as_bytes());
+        });
+
+    let advertising = device.get_advertising();
+    advertising.lock().set_data(
+        BLEAdvertisementData::new()
+            .add_service_uuid(uuid128!("7e5a0001-2f4b-4c3a-9b8e-1f2d3c4b5a60")),
+    )?;
+    // The scan response holds 31 bytes, 29 after the name's own header, and
+    // names can be up to 32 ASCII bytes.
+    let advertised_name = &device_name[..device_name.len().min(29)];
+    advertising
+        .lock()
+        .set_scan_response_data(BLEAdvertisementData::new().name(advertised_name))?;
+    advertising.lock().start()?;
+    info!("BLE provisioning service advertising as {}", device_name);
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! Single JPEG snapshots: `GET /capture`, optionally with `size` and
+//! `quality` query parameters, e.g. `/capture?size=vga&quality=10`.
+//!
+//! The sensor is switched to the requested settings for the one frame and
+//! then put back, with the video stream paused meanwhile (see `stream.rs`).
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_hal::io::Write;
+use esp_idf_svc::http::{server::EspHttpServer, Method};
+use esp_idf_sys::camera;
+use log::{error, warn};
+
+use crate::clock::{self, Utc};
+use crate::espcam::Camera;
+use crate::stream::Frames;
+
+/// Sizes up to the one the camera starts with, as the driver's frame buffer
+/// is allocated for that.
+const FRAME_SIZES: [(&str, camera::framesize_t); 5] = [
+    ("qqvga", camera::framesize_t_FRAMESIZE_QQVGA),
+    ("qvga", camera::framesize_t_FRAMESIZE_QVGA),
+    ("cif", camera::framesize_t_FRAMESIZE_CIF),
+    ("vga", camera::framesize_t_FRAMESIZE_VGA),
+    ("svga", camera::framesize_t_FRAMESIZE_SVGA),
+];
+/// JPEG quality, lower is better. Below 10 frames can overflow the buffer.
+const QUALITY_RANGE: std::ops::RangeInclusive<i32> = 10..=63;
+
+#[derive(Debug, Default)]
+struct Settings {
+    frame_size: Option<camera::framesize_t>,
+    quality: Option<i32>,
+}
+
+impl Settings {
+    fn parse(query: &str) -> Result<Self> {
+        let mut settings = Self::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "size" => {
+                    let (_, frame_size) = FRAME_SIZES
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(value))
+                        .ok_or_else(|| anyhow!("unknown size {:?}", value))?;
+                    settings.frame_size = Some(*frame_size);
+                }
+                "quality" => {
+                    let quality = value
+                        .parse()
+                        .ok()
+                        .filter(|quality| QUALITY_RANGE.contains(quality))
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "quality must be {} to {}",
+                                QUALITY_RANGE.start(),
+                                QUALITY_RANGE.end()
+                            )
+                        })?;
+                    settings.quality = Some(quality);
+                }
+                _ => bail!("unknown parameter {:?}", key),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+struct Snapshot {
+    data: Vec<u8>,
+    /// Since the Unix epoch, if the clock is set (see `clock.rs`).
+    taken: Option<Duration>,
+    /// Since boot, from the frame buffer.
+    uptime: camera::timeval,
+}
+
+impl Snapshot {
+    /// The `X-Timestamp` header and the file name.
+    fn names(&self) -> (String, String) {
+        match self.taken {
+            Some(taken) => (
+                format!("{}.{:06}", taken.as_secs(), taken.subsec_micros()),
+                format!(
+                    "capture-{}-{:03}.jpg",
+                    Utc::from_unix(taken.as_secs()).compact(),
+                    taken.subsec_millis()
+                ),
+            ),
+            None => (
+                format!("{}.{:06}", self.uptime.tv_sec, self.uptime.tv_usec),
+                format!(
+                    "capture-uptime-{}-{:06}.jpg",
+                    self.uptime.tv_sec, self.uptime.tv_usec
+                ),
+            ),
+        }
+    }
+}
+
+fn take(camera: &Camera, settings: &Settings) -> Result<Snapshot> {
+    let sensor = camera.sensor();
+    if let Some(frame_size) = settings.frame_size {
+        sensor.set_framesize(frame_size)?;
+    }
+    if let Some(quality) = settings.quality {
+        sensor.set_quality(quality)?;
+    }
+    // The driver may hold a frame from before, taken with the old settings.
+    drop(camera.get_framebuffer());
+    let fb = camera
+        .get_framebuffer()
+        .ok_or_else(|| anyhow!("failed to capture a frame"))?;
+    Ok(Snapshot {
+        data: fb.data().to_vec(),
+        taken: clock::now(),
+        uptime: fb.timestamp(),
+    })
+}
+
+/// Takes a snapshot with `settings`, then restores the sensor.
+fn snapshot(frames: &Frames, settings: &Settings) -> Result<Snapshot> {
+    let camera = frames.camera();
+    let sensor = camera.sensor();
+    let (frame_size, quality) = (sensor.framesize(), sensor.quality());
+    let snapshot = take(&camera, settings);
+    if let Err(err) = sensor.set_framesize(frame_size) {
+        warn!("Failed to restore the frame size: {:?}", err);
+    }
+    if let Err(err) = sensor.set_quality(quality) {
+        warn!("Failed to restore the JPEG quality: {:?}", err);
+    }
+    snapshot
+}
+
+pub fn register(server: &mut EspHttpServer<'static>, frames: Arc<Frames>) -> Result<()> {
+    server.fn_handler(
+        "/capture",
+        Method::Get,
+        move |request| -> Result<(), anyhow::Error> {
+            let query = request.uri().split_once('?').map_or("", |(_, query)| query);
+            let settings = match Settings::parse(query) {
+                Ok(settings) => settings,
+                Err(err) => {
+                    let mut response = request.into_status_response(400)?;
+                    response.write_all(format!("{}", err).as_bytes())?;
+                    return Ok(());
+                }
+            };
+            let snapshot = match snapshot(&frames, &settings) {
+                Ok(snapshot) => snapshot,
+                Err(err) => {
+                    error!("Snapshot failed: {:?}", err);
+                    let mut response = request.into_status_response(500)?;
+                    response.write_all(format!("{}", err).as_bytes())?;
+                    return Ok(());
+                }
+            };
+
+            let (timestamp, filename) = snapshot.names();
+            let disposition = format!("inline; filename=\"{}\"", filename);
+            let length = snapshot.data.len().to_string();
+            let date = snapshot
+                .taken
+                .map(|taken| Utc::from_unix(taken.as_secs()).http_date());
+            let mut headers = vec![
+                ("Content-Type", "image/jpeg"),
+                ("Content-Length", length.as_str()),
+                ("Content-Disposition", disposition.as_str()),
+                ("X-Timestamp", timestamp.as_str()),
+                ("Cache-Control", "no-store"),
+                ("Access-Control-Allow-Origin", "*"),
+            ];
+            if let Some(date) = &date {
+                headers.push(("Date", date.as_str()));
+                headers.push(("Last-Modified", date.as_str()));
+            }
+            let mut response = request.into_response(200, Some("OK"), &headers)?;
+            response.write_all(&snapshot.data)?;
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,92 @@
+//! Wall-clock time from SNTP, so timestamps mean something across reboots.
+//! Until the first sync the system clock starts from 1970 at boot, and
+//! [`now`] returns `None`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use esp_idf_svc::sntp::EspSntp;
+
+/// Anything earlier means the clock hasn't been set (this is late 2023).
+const CLOCK_SET_AFTER: Duration = Duration::from_secs(1_700_000_000);
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Starts syncing the clock with the default pool servers. Dropping the
+/// handle stops it.
+pub fn start() -> Result<EspSntp<'static>> {
+    Ok(EspSntp::new_default()?)
+}
+
+/// Time since the Unix epoch, `None` until SNTP has set the clock.
+pub fn now() -> Option<Duration> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    (now > CLOCK_SET_AFTER).then_some(now)
+}
+
+/// A Unix time broken down into its UTC date and time.
+pub struct Utc {
+    year: u64,
+    /// 1 to 12.
+    month: u64,
+    day: u64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    /// 0 is Sunday.
+    weekday: u64,
+}
+
+impl Utc {
+    pub fn from_unix(secs: u64) -> Self {
+        let days = secs / 86_400;
+        let time = secs % 86_400;
+        // Howard Hinnant's civil_from_days, with years starting in March.
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let day_of_era = z % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        Self {
+            year: era * 400 + year_of_era + u64::from(month <= 2),
+            month,
+            day: day_of_year - (153 * shifted_month + 2) / 5 + 1,
+            hour: time / 3600,
+            minute: time / 60 % 60,
+            second: time % 60,
+            // 1970-01-01 was a Thursday.
+            weekday: (days + 4) % 7,
+        }
+    }
+
+    /// The HTTP date format, e.g. `Mon, 19 Oct 2026 07:51:56 GMT`.
+    pub fn http_date(&self) -> String {
+        format!(
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[self.weekday as usize],
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// Sorts and fits in a file name, e.g. `20261019-075156`.
+    pub fn compact(&self) -> String {
+        format!(
+            "{}{:02}{:02}-{:02}{:02}{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
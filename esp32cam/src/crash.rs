@@ -0,0 +1,58 @@
+//! Crash reports from the reset reason and, after a panic or exception, the
+//! summary of the ESP-IDF core dump saved to the `coredump` partition. Shown
+//! in `/status` and logged at boot, so they reach the client's `logs` mode.
+
+use std::ffi::CStr;
+
+use esp_idf_svc::sys;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashReport {
+    /// `panic`, `watchdog` or `brownout`.
+    pub reason: &'static str,
+    /// Task that crashed, from the core dump.
+    pub task: Option<String>,
+    /// Program counter and backtrace, from the core dump.
+    pub pc: Option<u32>,
+    pub backtrace: Vec<u32>,
+}
+
+fn core_dump_summary() -> Option<sys::esp_core_dump_summary_t> {
+    if unsafe { sys::esp_core_dump_image_check() } != sys::ESP_OK {
+        return None;
+    }
+    let mut summary: sys::esp_core_dump_summary_t = unsafe { std::mem::zeroed() };
+    (unsafe { sys::esp_core_dump_get_summary(&mut summary) } == sys::ESP_OK).then_some(summary)
+}
+
+/// The crash report for this boot, `None` if the last reset was a normal one.
+pub fn check() -> Option<CrashReport> {
+    let reason = match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT
+        | sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+        | sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        _ => return None,
+    };
+    let mut report = CrashReport {
+        reason,
+        task: None,
+        pc: None,
+        backtrace: Vec::new(),
+    };
+    // The core dump is only written on a panic or exception, an older one
+    // would belong to an earlier crash.
+    if reason == "panic" {
+        if let Some(summary) = core_dump_summary() {
+            let task = unsafe { CStr::from_ptr(summary.exc_task.as_ptr()) };
+            report.task = Some(task.to_string_lossy().into_owned());
+            report.pc = Some(summary.exc_pc);
+            let depth = (summary.exc_bt_info.depth as usize).min(summary.exc_bt_info.bt.len());
+            report.backtrace = summary.exc_bt_info.bt[..depth].to_vec();
+        }
+    }
+    log::warn!("Last reset was a crash: {:?}", report);
+    Some(report)
+}
@@ -214,6 +214,12 @@ impl<'a> CameraSensor<'a> {
             )
         })
     }
+    pub fn framesize(&self) -> camera::framesize_t {
+        unsafe { (*self.sensor).status.framesize }
+    }
+    pub fn quality(&self) -> i32 {
+        unsafe { (*self.sensor).status.quality as i32 }
+    }
     pub fn set_xclk(&self, timer: i32, xclk: i32) -> Result<(), EspError> {
         esp!(unsafe { (*self.sensor).set_xclk.unwrap()(self.sensor, timer, xclk) })
     }
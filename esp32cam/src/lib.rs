@@ -1,2 +1,12 @@
+pub mod beacon;
+pub mod ble_provisioning;
+pub mod capture;
+pub mod clock;
+pub mod crash;
 pub mod espcam;
+pub mod mdns;
+pub mod ota;
+pub mod provisioning;
+pub mod remote_log;
+pub mod stream;
 pub mod wifi_handler;
@@ -1,37 +1,108 @@
+pub mod beacon;
+pub mod ble_provisioning;
+pub mod capture;
+pub mod clock;
+pub mod crash;
 pub mod espcam;
+pub mod mdns;
+pub mod ota;
+pub mod provisioning;
+pub mod remote_log;
+pub mod stream;
 pub mod wifi_handler;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use esp_idf_svc::hal::peripherals::Peripherals;
 use espcam::Camera;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 
+use esp_idf_hal::gpio::AnyIOPin;
 use esp_idf_hal::io::Write;
+use esp_idf_hal::uart::{config::Config as UartConfig, UartDriver};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    http::{server::EspHttpServer, Method},
+    http::{server::EspHttpServer, Headers, Method},
+    nvs::EspDefaultNvsPartition,
 };
-use wifi_handler::my_wifi;
+use provisioning::CredentialStore;
+use serde::Serialize;
+use wifi_handler::{connect_wifi, create_wifi, WifiStatus};
+
+/// Body of `/status`.
+#[derive(Serialize)]
+struct Status<'a> {
+    #[serde(flatten)]
+    wifi: &'a WifiStatus,
+    /// Why the last reset happened, if it was a crash.
+    crash: Option<&'a crash::CrashReport>,
+}
 
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    remote_log::init();
+    let crash_report = crash::check();
 
     let sysloop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
 
     let peripherals = Peripherals::take().unwrap();
-    let wifi_ssid = env!("WIFI_SSID");
-    let wifi_password = env!("WIFI_PASSWORD");
 
-    let _wifi = match my_wifi(wifi_ssid, wifi_password, peripherals.modem, sysloop) {
-        Ok(inner) => inner,
+    let credential_store = CredentialStore::new(nvs.clone())?;
+    let console_uart = UartDriver::new(
+        peripherals.uart0,
+        peripherals.pins.gpio1,
+        peripherals.pins.gpio3,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &UartConfig::default(),
+    )?;
+    provisioning::spawn_console(console_uart, credential_store.clone())?;
+
+    let mut server = EspHttpServer::new(&esp_idf_svc::http::server::Configuration::default())?;
+    ota::register(&mut server)?;
+
+    let device_name = credential_store.device_name();
+    ble_provisioning::start(credential_store.clone(), &device_name)?;
+
+    // Updates and provisioning work from here on, the setup portal included,
+    // so a freshly updated firmware is good whether or not Wi-Fi connects.
+    ota::mark_valid();
+
+    let networks = credential_store.known_networks();
+    let mut wifi = create_wifi(peripherals.modem, sysloop.clone(), Some(nvs))?;
+    wifi.sta_netif_mut().set_hostname(&device_name)?;
+    let network = match connect_wifi(&mut wifi, &networks, sysloop.clone()) {
+        Ok(network) => network,
         Err(err) => {
-            bail!("Could not connect to Wi-Fi network: {:?}", err)
+            log::error!("Could not connect to Wi-Fi network: {:?}", err);
+            provisioning::start_setup_portal(&mut wifi, sysloop, &mut server, credential_store)?;
+            loop {
+                std::thread::sleep(std::time::Duration::from_millis(1000));
+            }
         }
     };
 
+    // Keep the handle alive, dropping it stops the responder.
+    let _mdns = mdns::start(&device_name)?;
+    // Likewise, dropping it stops syncing the clock.
+    let _sntp = clock::start()?;
+    let device_id = beacon::device_id(wifi.sta_netif().get_mac()?);
+    log::info!("Device ID: {}", device_id);
+    beacon::spawn(device_id, device_name.clone(), credential_store.partner())?;
+    remote_log::spawn(device_name.clone())?;
+
+    let wifi_status = Arc::new(Mutex::new(WifiStatus::default()));
+    let wifi = Arc::new(Mutex::new(wifi));
+    wifi_handler::spawn_roaming(
+        wifi.clone(),
+        networks,
+        network,
+        sysloop,
+        wifi_status.clone(),
+    )?;
+
     let camera = Camera::new(
         peripherals.pins.gpio32,
         peripherals.pins.gpio0,
@@ -55,41 +126,29 @@ fn main() -> Result<()> {
     .unwrap();
     // camera.sensor().set_vflip(true)?; // Flip if needed
 
-    let cam_arc = Arc::new(camera);
-    let cam_arc_clone = cam_arc.clone();
-
-    let mut server = EspHttpServer::new(&esp_idf_svc::http::server::Configuration::default())?;
+    let frames = stream::Frames::new(camera);
+    stream::spawn(frames.clone())?;
+    capture::register(&mut server, frames)?;
 
+    // The stream has its own server, see `stream.rs`.
     server.fn_handler(
         "/camera",
         Method::Get,
-        move |request| -> Result<(), anyhow::Error> {
-            let part_boundary = "123456789000000000000987654321";
-            let frame_boundary = format!("\r\n--{}\r\n", part_boundary);
-
-            let content_type = format!("multipart/x-mixed-replace;boundary={}", part_boundary);
-            let headers = [("Content-Type", content_type.as_str())];
-            let mut response = request.into_response(200, Some("OK"), &headers).unwrap();
-            loop {
-                if let Some(fb) = cam_arc_clone.get_framebuffer() {
-                    let data = fb.data();
-                    let frame_part = format!(
-                        "Content-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
-                        data.len()
-                    );
-                    response.write_all(frame_part.as_bytes())?;
-                    response.write_all(data)?;
-                    response.write_all(frame_boundary.as_bytes())?;
-                    response.flush()?;
-                }
-            }
-
+        |request| -> Result<(), anyhow::Error> {
+            let host = request.header("Host").unwrap_or("espressif");
+            let host = host.split_once(':').map_or(host, |(host, _)| host);
+            let location = format!("http://{}:{}/camera", host, stream::STREAM_PORT);
+            request.into_response(
+                307,
+                Some("Temporary Redirect"),
+                &[("Location", location.as_str())],
+            )?;
             Ok(())
         },
     )?;
 
     server.fn_handler("/", Method::Get, |request| -> Result<(), anyhow::Error> {
-        let data = "<html><head><meta name=\"viewport\" content=\"width=device-width; height=device-height;\"><title>esp32cam</title></head><body><img src=\"camera\" alt=\"Failed to load image\" style=\"height: 100%;width: 100%; transform: rotate(180deg);\"></body></html>";
+        let data = "<html><head><meta name=\"viewport\" content=\"width=device-width; height=device-height;\"><title>esp32cam</title></head><body style=\"margin: 0;\"><img src=\"camera\" alt=\"Failed to load image\" style=\"height: 100%;width: 100%; transform: rotate(180deg);\"><div id=\"status\" style=\"position: fixed; top: 0; left: 0; padding: 4px; color: white; background: rgba(0, 0, 0, 0.5); font-family: monospace;\"></div><script>async function update() { try { const s = await (await fetch('status')).json(); document.getElementById('status').textContent = (s.network || 'disconnected') + (s.rssi !== null ? ' ' + s.rssi + ' dBm' : ''); } catch (e) {} } update(); setInterval(update, 5000);</script></body></html>";
 
 
         let headers = [
@@ -101,6 +160,25 @@ fn main() -> Result<()> {
         Ok(())
     })?;
 
+    server.fn_handler(
+        "/status",
+        Method::Get,
+        move |request| -> Result<(), anyhow::Error> {
+            let wifi_status = wifi_status.lock().unwrap();
+            let data = serde_json::to_string(&Status {
+                wifi: &wifi_status,
+                crash: crash_report.as_ref(),
+            })?;
+            let headers = [
+                ("Content-Type", "application/json"),
+                ("Content-Length", &data.len().to_string()),
+            ];
+            let mut response = request.into_response(200, Some("OK"), &headers)?;
+            response.write_all(data.as_bytes())?;
+            Ok(())
+        },
+    )?;
+
     loop {
         std::thread::sleep(std::time::Duration::from_millis(1000));
     }
@@ -0,0 +1,22 @@
+//! mDNS so the camera answers for `<name>.local` and advertises its HTTP
+//! server (with a `board=camera` TXT record) for the client to find.
+
+use anyhow::Result;
+use esp_idf_svc::mdns::EspMdns;
+use log::info;
+
+/// Starts the responder, it stops again when the returned handle is dropped.
+pub fn start(device_name: &str) -> Result<EspMdns> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(device_name)?;
+    mdns.set_instance_name(device_name)?;
+    mdns.add_service(
+        Some(device_name),
+        "_http",
+        "_tcp",
+        80,
+        &[("board", "camera"), ("path", "/camera")],
+    )?;
+    info!("Advertising {}.local over mDNS", device_name);
+    Ok(mdns)
+}
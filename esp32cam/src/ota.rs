@@ -0,0 +1,87 @@
+//! Over-the-air updates: `POST /update` with the raw app image as the body.
+//!
+//! The image is written to the inactive OTA partition and checked by
+//! `esp_ota_end` before it becomes the boot partition. With
+//! `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE` the bootloader falls back to the
+//! previous firmware unless the new one calls [`mark_valid`] after starting.
+
+use anyhow::{bail, Result};
+use esp_idf_hal::io::{Read, Write};
+use esp_idf_svc::{
+    http::{server::EspHttpServer, Headers, Method},
+    ota::EspOta,
+};
+use log::{error, info, warn};
+
+/// First byte of every ESP app image.
+const IMAGE_MAGIC: u8 = 0xe9;
+
+/// Cancels the pending rollback once the firmware has come up properly.
+pub fn mark_valid() {
+    match EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        Ok(()) => info!("Running firmware marked valid"),
+        Err(err) => warn!("Failed to mark running firmware valid: {:?}", err),
+    }
+}
+
+fn receive_update(request: &mut impl Read, len: usize) -> Result<()> {
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut buffer = [0u8; 4096];
+    let mut received = 0;
+    while received < len {
+        let read = match request.read(&mut buffer) {
+            Ok(0) => {
+                update.abort()?;
+                bail!("connection closed after {} of {} bytes", received, len)
+            }
+            Ok(read) => read,
+            Err(err) => {
+                update.abort()?;
+                bail!("read failed: {:?}", err)
+            }
+        };
+        if received == 0 && buffer[0] != IMAGE_MAGIC {
+            update.abort()?;
+            bail!("not an ESP app image")
+        }
+        update.write(&buffer[..read])?;
+        received += read;
+    }
+    // Validates the image and switches the boot partition.
+    update.complete()?;
+    Ok(())
+}
+
+pub fn register(server: &mut EspHttpServer<'static>) -> Result<()> {
+    server.fn_handler(
+        "/update",
+        Method::Post,
+        |mut request| -> Result<(), anyhow::Error> {
+            let Some(len) = request.content_len().filter(|len| *len > 0) else {
+                let mut response = request.into_status_response(411)?;
+                response.write_all(b"Content-Length required")?;
+                return Ok(());
+            };
+            info!("Receiving {} byte firmware update", len);
+
+            match receive_update(&mut request, len as usize) {
+                Ok(()) => {
+                    info!("Firmware update written, rebooting");
+                    let mut response = request.into_ok_response()?;
+                    response.write_all(b"Updated, rebooting...")?;
+                    response.flush()?;
+                    std::thread::sleep(std::time::Duration::from_millis(500));
+                    esp_idf_hal::reset::restart();
+                }
+                Err(err) => {
+                    error!("Firmware update failed: {:?}", err);
+                    let mut response = request.into_status_response(400)?;
+                    response.write_all(format!("{}", err).as_bytes())?;
+                }
+            }
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,392 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use esp_idf_hal::delay::BLOCK;
+use esp_idf_hal::io::{Read, Write};
+use esp_idf_hal::uart::UartDriver;
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    http::{server::EspHttpServer, Method},
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    wifi::{AccessPointConfiguration, AuthMethod, BlockingWifi, Configuration, EspWifi},
+};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+const NVS_NAMESPACE: &str = "wifi";
+/// Used as BLE name (and later hostname) until a device name is provisioned.
+pub const DEFAULT_DEVICE_NAME: &str = "espressif";
+const SETUP_AP_SSID: &str = "esp32cam-setup";
+const MAX_FORM_SIZE: usize = 256;
+
+pub const MAX_NETWORKS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WifiCredentials {
+    pub ssid: String,
+    pub password: String,
+}
+
+impl WifiCredentials {
+    /// Credentials baked in at build time from `.cargo/config.toml`.
+    pub fn build_default() -> Self {
+        Self {
+            ssid: env!("WIFI_SSID").to_string(),
+            password: env!("WIFI_PASSWORD").to_string(),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.ssid.is_empty() || self.ssid.len() > 32 {
+            bail!("SSID must be between 1 and 32 bytes");
+        }
+        if self.password.len() > 64 {
+            bail!("Password must be at most 64 bytes");
+        }
+        Ok(())
+    }
+}
+
+/// Known networks (in priority order) and the device name, persisted in the
+/// default NVS partition.
+#[derive(Clone)]
+pub struct CredentialStore {
+    nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
+}
+
+impl CredentialStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        Ok(Self {
+            nvs: Arc::new(Mutex::new(nvs)),
+        })
+    }
+
+    /// Provisioned networks, highest priority first.
+    pub fn networks(&self) -> Result<Vec<WifiCredentials>> {
+        let nvs = self.nvs.lock().unwrap();
+        let mut buf = vec![0u8; nvs.str_len("networks")?.unwrap_or(0) + 1];
+        Ok(match nvs.get_str("networks", &mut buf)? {
+            Some(json) => serde_json::from_str(json)?,
+            None => Vec::new(),
+        })
+    }
+
+    fn save_networks(&self, networks: &[WifiCredentials]) -> Result<()> {
+        let json = serde_json::to_string(networks)?;
+        self.nvs.lock().unwrap().set_str("networks", &json)?;
+        Ok(())
+    }
+
+    /// Provisioned networks followed by the build-time default as a last
+    /// resort.
+    pub fn known_networks(&self) -> Vec<WifiCredentials> {
+        let mut networks = self.networks().unwrap_or_else(|err| {
+            warn!("Failed to read stored networks: {:?}", err);
+            Vec::new()
+        });
+        if networks.is_empty() {
+            info!("No provisioned networks, using build-time credentials");
+        }
+        let default = WifiCredentials::build_default();
+        if !networks.iter().any(|n| n.ssid == default.ssid) {
+            networks.push(default);
+        }
+        networks
+    }
+
+    /// Adds (or updates) a network as the highest priority, dropping the
+    /// lowest priority one if the list is full.
+    pub fn add_network(&self, credentials: &WifiCredentials) -> Result<()> {
+        credentials.validate()?;
+        let mut networks = self.networks()?;
+        networks.retain(|n| n.ssid != credentials.ssid);
+        networks.insert(0, credentials.clone());
+        networks.truncate(MAX_NETWORKS);
+        self.save_networks(&networks)
+    }
+
+    pub fn forget_network(&self, ssid: &str) -> Result<bool> {
+        let mut networks = self.networks()?;
+        let before = networks.len();
+        networks.retain(|n| n.ssid != ssid);
+        self.save_networks(&networks)?;
+        Ok(networks.len() != before)
+    }
+
+    pub fn forget_all(&self) -> Result<()> {
+        self.nvs.lock().unwrap().remove("networks")?;
+        Ok(())
+    }
+
+    pub fn device_name(&self) -> String {
+        let nvs = self.nvs.lock().unwrap();
+        let mut name_buf = [0u8; 33];
+        match nvs.get_str("name", &mut name_buf) {
+            Ok(Some(name)) => name.to_string(),
+            _ => DEFAULT_DEVICE_NAME.to_string(),
+        }
+    }
+
+    pub fn save_device_name(&self, name: &str) -> Result<()> {
+        if name.is_empty()
+            || name.len() > 32
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            bail!("Device name must be 1 to 32 characters of a-z, 0-9 and -");
+        }
+        self.nvs.lock().unwrap().set_str("name", name)?;
+        Ok(())
+    }
+
+    /// Device ID of the rover this camera is paired with, see `beacon.rs`.
+    pub fn partner(&self) -> Option<String> {
+        let nvs = self.nvs.lock().unwrap();
+        let mut partner_buf = [0u8; 33];
+        match nvs.get_str("partner", &mut partner_buf) {
+            Ok(Some(partner)) => Some(partner.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn save_partner(&self, partner: Option<&str>) -> Result<()> {
+        let mut nvs = self.nvs.lock().unwrap();
+        match partner {
+            Some(partner) => {
+                if partner.is_empty()
+                    || partner.len() > 32
+                    || !partner
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                {
+                    bail!("Partner ID must be 1 to 32 characters of a-z, 0-9 and -");
+                }
+                nvs.set_str("partner", partner)?;
+            }
+            None => {
+                nvs.remove("partner")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits a console line into arguments, double quotes group words so SSIDs
+/// containing spaces can be entered as `wifi "My Network" password`.
+fn split_args(line: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        let (arg, remainder) = if let Some(quoted) = rest.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match rest.find(char::is_whitespace) {
+                Some(end) => (&rest[..end], &rest[end..]),
+                None => (rest, ""),
+            }
+        };
+        args.push(arg);
+        rest = remainder.trim_start();
+    }
+    args
+}
+
+fn handle_console_line(line: &str, store: &CredentialStore) {
+    match split_args(line).as_slice() {
+        [] => {}
+        ["wifi", ssid, password, ..] => {
+            let credentials = WifiCredentials {
+                ssid: ssid.to_string(),
+                password: password.to_string(),
+            };
+            match store.add_network(&credentials) {
+                Ok(()) => {
+                    info!("Stored credentials for {}, rebooting", ssid);
+                    esp_idf_hal::reset::restart();
+                }
+                Err(err) => error!("Failed to store credentials: {:?}", err),
+            }
+        }
+        ["wifi", ..] => warn!("Usage: wifi <ssid> <password>"),
+        ["networks", ..] => {
+            for (priority, network) in store.known_networks().iter().enumerate() {
+                info!("{}: {}", priority, network.ssid);
+            }
+        }
+        ["forget", ssid, ..] => match store.forget_network(ssid) {
+            Ok(true) => {
+                info!("Forgot {}, rebooting", ssid);
+                esp_idf_hal::reset::restart();
+            }
+            Ok(false) => warn!("No stored network named {}", ssid),
+            Err(err) => error!("Failed to erase credentials: {:?}", err),
+        },
+        ["forget"] => match store.forget_all() {
+            Ok(()) => {
+                info!("Forgot all stored networks, rebooting");
+                esp_idf_hal::reset::restart();
+            }
+            Err(err) => error!("Failed to erase credentials: {:?}", err),
+        },
+        ["pair", partner, ..] => match store.save_partner(Some(partner)) {
+            Ok(()) => {
+                info!("Paired with {}, rebooting", partner);
+                esp_idf_hal::reset::restart();
+            }
+            Err(err) => error!("Failed to store partner: {:?}", err),
+        },
+        ["unpair", ..] => match store.save_partner(None) {
+            Ok(()) => {
+                info!("Unpaired, rebooting");
+                esp_idf_hal::reset::restart();
+            }
+            Err(err) => error!("Failed to erase partner: {:?}", err),
+        },
+        ["help", ..] => info!(
+            "Commands: wifi <ssid> <password> | networks | forget [ssid] | pair <rover id> | unpair | help"
+        ),
+        [command, ..] => warn!("Unknown console command {}, try help", command),
+    }
+}
+
+/// Reads newline terminated provisioning commands from the serial port on a
+/// background thread.
+pub fn spawn_console(uart: UartDriver<'static>, store: CredentialStore) -> Result<()> {
+    std::thread::Builder::new()
+        .name("console".into())
+        .stack_size(4096)
+        .spawn(move || {
+            let mut line = Vec::new();
+            let mut buffer = [0u8; 32];
+            loop {
+                let read = match uart.read(&mut buffer, BLOCK) {
+                    Ok(read) => read,
+                    Err(err) => {
+                        warn!("Serial console read error: {:?}", err);
+                        continue;
+                    }
+                };
+                for &byte in &buffer[..read] {
+                    match byte {
+                        b'\r' | b'\n' => {
+                            handle_console_line(&String::from_utf8_lossy(&line), &store);
+                            line.clear();
+                        }
+                        _ if line.len() < 128 => line.push(byte),
+                        _ => line.clear(),
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Decodes an `application/x-www-form-urlencoded` value.
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn form_value(body: &str, key: &str) -> Option<String> {
+    body.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=')?;
+        (k == key).then(|| url_decode(v))
+    })
+}
+
+/// Starts an open access point serving a setup page where the network
+/// credentials can be entered. The board reboots once they are saved.
+pub fn start_setup_portal(
+    wifi: &mut EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    server: &mut EspHttpServer<'static>,
+    store: CredentialStore,
+) -> Result<()> {
+    let mut wifi = BlockingWifi::wrap(wifi, sysloop)?;
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: SETUP_AP_SSID.try_into().unwrap(),
+        auth_method: AuthMethod::None,
+        channel: 1,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+    let ip_info = wifi.wifi().ap_netif().get_ip_info()?;
+    info!(
+        "Setup access point {} started, browse to http://{}/",
+        SETUP_AP_SSID, ip_info.ip
+    );
+
+    server.fn_handler("/", Method::Get, |request| -> Result<(), anyhow::Error> {
+        let data = "<html><head><meta name=\"viewport\" content=\"width=device-width\"><title>esp32cam setup</title></head><body><h1>esp32cam setup</h1><form method=\"post\" action=\"/provision\"><p>SSID <input name=\"ssid\" maxlength=\"32\"></p><p>Password <input name=\"password\" type=\"password\" maxlength=\"64\"></p><p><input type=\"submit\" value=\"Save and reboot\"></p></form></body></html>";
+        let headers = [
+            ("Content-Type", "text/html"),
+            ("Content-Length", &data.len().to_string()),
+        ];
+        let mut response = request.into_response(200, Some("OK"), &headers)?;
+        response.write_all(data.as_bytes())?;
+        Ok(())
+    })?;
+
+    server.fn_handler(
+        "/provision",
+        Method::Post,
+        move |mut request| -> Result<(), anyhow::Error> {
+            let mut body = [0u8; MAX_FORM_SIZE];
+            let mut len = 0;
+            while len < body.len() {
+                let read = request.read(&mut body[len..])?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+            let body = String::from_utf8_lossy(&body[..len]);
+            let credentials = WifiCredentials {
+                ssid: form_value(&body, "ssid").unwrap_or_default(),
+                password: form_value(&body, "password").unwrap_or_default(),
+            };
+
+            match store.add_network(&credentials) {
+                Ok(()) => {
+                    info!("Stored credentials for {}, rebooting", credentials.ssid);
+                    let mut response = request.into_ok_response()?;
+                    response.write_all(b"Saved, rebooting...")?;
+                    response.flush()?;
+                    std::thread::sleep(std::time::Duration::from_millis(500));
+                    esp_idf_hal::reset::restart();
+                }
+                Err(err) => {
+                    let mut response = request.into_status_response(400)?;
+                    response.write_all(format!("{}", err).as_bytes())?;
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    Ok(())
+}
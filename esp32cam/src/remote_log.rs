@@ -0,0 +1,158 @@
+//! Logger that prints through `EspLogger` as before and also broadcasts every
+//! record as JSON on UDP port [`LOG_PORT`], like `wifi_tank/src/remote_log.rs`.
+//! Records wait in a ring buffer until the network is up, the oldest are
+//! dropped when it is full and sending is rate limited.
+
+use std::collections::VecDeque;
+use std::net::UdpSocket;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use esp_idf_svc::log::EspLogger;
+use serde::Serialize;
+
+pub const LOG_PORT: u16 = 8091;
+const BUFFER_RECORDS: usize = 64;
+const MAX_MESSAGE_LENGTH: usize = 256;
+/// Token bucket: up to `RATE_BURST` records at once, refilled at
+/// `RATE_PER_SEC`.
+const RATE_BURST: u32 = 20;
+const RATE_PER_SEC: u32 = 10;
+
+#[derive(Serialize)]
+struct Record {
+    device: String,
+    board: &'static str,
+    level: &'static str,
+    target: String,
+    uptime_ms: u64,
+    msg: String,
+}
+
+struct Buffer {
+    records: VecDeque<Record>,
+    dropped: u32,
+}
+
+static BUFFER: Mutex<Buffer> = Mutex::new(Buffer {
+    records: VecDeque::new(),
+    dropped: 0,
+});
+static PENDING: Condvar = Condvar::new();
+
+struct RemoteLogger {
+    esp: EspLogger,
+}
+
+impl log::Log for RemoteLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.esp.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.esp.log(record);
+
+        let mut msg = record.args().to_string();
+        if msg.len() > MAX_MESSAGE_LENGTH {
+            let mut end = MAX_MESSAGE_LENGTH;
+            while !msg.is_char_boundary(end) {
+                end -= 1;
+            }
+            msg.truncate(end);
+        }
+        let record = Record {
+            // Filled in by the sender, which knows the device name.
+            device: String::new(),
+            board: "camera",
+            level: record.level().as_str(),
+            target: record.target().to_string(),
+            uptime_ms: (unsafe { esp_idf_svc::sys::esp_timer_get_time() } / 1000) as u64,
+            msg,
+        };
+        let Ok(mut buffer) = BUFFER.lock() else {
+            return;
+        };
+        if buffer.records.len() >= BUFFER_RECORDS {
+            buffer.records.pop_front();
+            buffer.dropped += 1;
+        }
+        buffer.records.push_back(record);
+        PENDING.notify_one();
+    }
+
+    fn flush(&self) {
+        self.esp.flush();
+    }
+}
+
+static LOGGER: RemoteLogger = RemoteLogger {
+    esp: EspLogger::new(),
+};
+
+/// Installs the logger in place of `EspLogger::initialize_default`.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        LOGGER.esp.initialize();
+    }
+}
+
+/// Starts sending buffered and new records, once we are on the network.
+pub fn spawn(device_name: String) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+
+    std::thread::Builder::new()
+        .name("remote_log".into())
+        .stack_size(4096)
+        .spawn(move || {
+            let mut tokens = RATE_BURST;
+            let mut refilled = Instant::now();
+            loop {
+                let (mut record, dropped) = {
+                    let mut buffer = PENDING
+                        .wait_while(BUFFER.lock().unwrap(), |buffer| buffer.records.is_empty())
+                        .unwrap();
+                    let record = buffer.records.pop_front().unwrap();
+                    (record, std::mem::take(&mut buffer.dropped))
+                };
+
+                let refill =
+                    (refilled.elapsed().as_millis() as u64 * u64::from(RATE_PER_SEC) / 1000) as u32;
+                if refill > 0 {
+                    tokens = (tokens + refill).min(RATE_BURST);
+                    refilled = Instant::now();
+                }
+                if tokens == 0 {
+                    std::thread::sleep(Duration::from_millis(1000 / u64::from(RATE_PER_SEC)));
+                    tokens = 1;
+                    refilled = Instant::now();
+                }
+                tokens -= 1;
+
+                // Not logged, a failing send would only produce more records
+                // to send.
+                if dropped > 0 {
+                    let notice = Record {
+                        device: device_name.clone(),
+                        board: "camera",
+                        level: "WARN",
+                        target: "remote_log".into(),
+                        uptime_ms: record.uptime_ms,
+                        msg: format!("{} log records dropped", dropped),
+                    };
+                    if let Ok(json) = serde_json::to_vec(&notice) {
+                        let _ = socket.send_to(&json, ("255.255.255.255", LOG_PORT));
+                    }
+                }
+                record.device.clone_from(&device_name);
+                if let Ok(json) = serde_json::to_vec(&record) {
+                    let _ = socket.send_to(&json, ("255.255.255.255", LOG_PORT));
+                }
+            }
+        })?;
+    Ok(())
+}
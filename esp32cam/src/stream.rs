@@ -0,0 +1,234 @@
+//! MJPEG video for several viewers at once. One capture thread grabs frames
+//! while anyone is watching and publishes each to [`Frames`], then every
+//! viewer's thread writes the latest frame to its own connection. A viewer
+//! that can't keep up skips frames instead of holding up capture, and a
+//! viewer disconnecting only ends its own thread.
+//!
+//! The stream has its own server on [`STREAM_PORT`], as the ESP-IDF HTTP
+//! server runs all handlers on one task and a stream would block it. `/camera`
+//! on the main server redirects here.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+use crate::espcam::Camera;
+
+pub const STREAM_PORT: u16 = 81;
+/// Each viewer costs a thread and socket buffers.
+const MAX_VIEWERS: usize = 4;
+const BOUNDARY: &str = "123456789000000000000987654321";
+/// Viewers that stop reading for this long are dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Viewers give up if no frame arrives for this long.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+const CAPTURE_RETRY: Duration = Duration::from_millis(100);
+
+struct Latest {
+    /// Counts published frames, so viewers can tell a new one.
+    sequence: u64,
+    frame: Option<Arc<[u8]>>,
+    viewers: usize,
+}
+
+/// The camera and the last frame captured from it.
+pub struct Frames {
+    camera: Mutex<Camera<'static>>,
+    latest: Mutex<Latest>,
+    /// Signalled for new frames and new viewers.
+    changed: Condvar,
+}
+
+impl Frames {
+    pub fn new(camera: Camera<'static>) -> Arc<Self> {
+        Arc::new(Self {
+            camera: Mutex::new(camera),
+            latest: Mutex::new(Latest {
+                sequence: 0,
+                frame: None,
+                viewers: 0,
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    /// The camera to itself, capture waits until the guard is dropped.
+    pub fn camera(&self) -> MutexGuard<'_, Camera<'static>> {
+        self.camera.lock().unwrap()
+    }
+
+    /// Registers a viewer, unless there are too many already.
+    fn join(self: &Arc<Self>) -> Option<Viewer> {
+        let mut latest = self.latest.lock().unwrap();
+        if latest.viewers >= MAX_VIEWERS {
+            return None;
+        }
+        latest.viewers += 1;
+        self.changed.notify_all();
+        Some(Viewer {
+            frames: self.clone(),
+        })
+    }
+
+    /// Waits for a frame newer than `sequence`, returning it with its own
+    /// sequence number.
+    fn next(&self, sequence: u64) -> Option<(u64, Arc<[u8]>)> {
+        let latest = self.latest.lock().unwrap();
+        let (latest, _) = self
+            .changed
+            .wait_timeout_while(latest, FRAME_TIMEOUT, |latest| {
+                latest.sequence == sequence || latest.frame.is_none()
+            })
+            .unwrap();
+        let frame = latest.frame.clone()?;
+        (latest.sequence != sequence).then_some((latest.sequence, frame))
+    }
+
+    fn capture(&self) {
+        loop {
+            {
+                let latest = self.latest.lock().unwrap();
+                let _latest = self
+                    .changed
+                    .wait_while(latest, |latest| latest.viewers == 0)
+                    .unwrap();
+            }
+            // Copied so the driver gets its buffer back straight away.
+            let frame = self
+                .camera()
+                .get_framebuffer()
+                .map(|fb| Arc::<[u8]>::from(fb.data()));
+            let Some(frame) = frame else {
+                warn!("Failed to capture a frame");
+                std::thread::sleep(CAPTURE_RETRY);
+                continue;
+            };
+            let mut latest = self.latest.lock().unwrap();
+            latest.sequence += 1;
+            latest.frame = Some(frame);
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// A registered viewer, which leaves when dropped.
+struct Viewer {
+    frames: Arc<Frames>,
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        let mut latest = self.frames.latest.lock().unwrap();
+        latest.viewers -= 1;
+        if latest.viewers == 0 {
+            // Not needed until the next viewer, and it may be a while.
+            latest.frame = None;
+        }
+    }
+}
+
+/// Reads up to the end of the request line.
+fn request_line(stream: &mut TcpStream) -> Result<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 256];
+    while !request.contains(&b'\n') {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 || request.len() > 1024 {
+            bail!("no request line");
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let end = request.iter().position(|&b| b == b'\n').unwrap();
+    Ok(String::from_utf8_lossy(&request[..end])
+        .trim_end()
+        .to_string())
+}
+
+fn serve(frames: &Frames, stream: &mut TcpStream) -> Result<()> {
+    let line = request_line(stream)?;
+    if !line.starts_with("GET /camera ") && !line.starts_with("GET /camera?") {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace;boundary={}\r\n\
+         Cache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\n\r\n--{}\r\n",
+        BOUNDARY, BOUNDARY
+    )?;
+    let mut sequence = 0;
+    loop {
+        let Some((next, frame)) = frames.next(sequence) else {
+            bail!("no frame within {:?}", FRAME_TIMEOUT);
+        };
+        sequence = next;
+        write!(
+            stream,
+            "Content-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            frame.len()
+        )?;
+        stream.write_all(&frame)?;
+        write!(stream, "\r\n--{}\r\n", BOUNDARY)?;
+        stream.flush()?;
+    }
+}
+
+fn spawn_viewer(viewer: Viewer, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    std::thread::Builder::new()
+        .name("viewer".into())
+        .stack_size(4096)
+        .spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| "unknown".to_string(), |peer| peer.to_string());
+            info!("Streaming to {}", peer);
+            if let Err(err) = serve(&viewer.frames, &mut stream) {
+                info!("Stopped streaming to {}: {}", peer, err);
+            }
+        })?;
+    Ok(())
+}
+
+/// Starts the capture thread and the stream server.
+pub fn spawn(frames: Arc<Frames>) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", STREAM_PORT))?;
+
+    let capture = frames.clone();
+    std::thread::Builder::new()
+        .name("capture".into())
+        .stack_size(4096)
+        .spawn(move || capture.capture())?;
+
+    std::thread::Builder::new()
+        .name("stream".into())
+        .stack_size(4096)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("Failed to accept a viewer: {:?}", err);
+                        continue;
+                    }
+                };
+                let Some(viewer) = frames.join() else {
+                    warn!("Too many viewers, turning one away");
+                    let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+                    );
+                    continue;
+                };
+                if let Err(err) = spawn_viewer(viewer, stream) {
+                    warn!("Failed to start streaming: {:?}", err);
+                }
+            }
+        })?;
+    Ok(())
+}
@@ -1,60 +1,95 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use anyhow::{bail, Result};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::peripheral,
-    wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
+    nvs::EspDefaultNvsPartition,
+    wifi::{
+        AccessPointInfo, AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi,
+    },
 };
-use log::info;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::provisioning::WifiCredentials;
+
+const ROAM_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Below this signal strength we start looking for a better access point.
+const ROAM_RSSI_THRESHOLD: i8 = -75;
+/// How much stronger another access point must be before we switch to it.
+const ROAM_HYSTERESIS: i8 = 8;
+
+/// Current connection, shown on the status page.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WifiStatus {
+    pub network: Option<String>,
+    pub rssi: Option<i8>,
+}
 
-pub fn my_wifi(
-    ssid: &str,
-    pass: &str,
+pub type SharedWifi = Arc<Mutex<Box<EspWifi<'static>>>>;
+
+pub fn create_wifi(
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
     sysloop: EspSystemEventLoop,
+    nvs: Option<EspDefaultNvsPartition>,
 ) -> Result<Box<EspWifi<'static>>> {
+    Ok(Box::new(EspWifi::new(modem, sysloop, nvs)?))
+}
+
+/// Signal strength of the access point we are currently associated with.
+pub fn current_rssi() -> Option<i8> {
+    let mut info: esp_idf_sys::wifi_ap_record_t = Default::default();
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) })
+        .ok()
+        .map(|()| info.rssi)
+}
+
+/// Picks the strongest visible known network, ties go to the higher priority
+/// (earlier) network.
+fn strongest_known<'a, 'b>(
+    networks: &'a [WifiCredentials],
+    ap_infos: &'b [AccessPointInfo],
+) -> Option<(&'a WifiCredentials, &'b AccessPointInfo)> {
+    let mut best: Option<(&WifiCredentials, &AccessPointInfo)> = None;
+    for network in networks {
+        for ap in ap_infos
+            .iter()
+            .filter(|ap| ap.ssid == network.ssid.as_str())
+        {
+            match best {
+                Some((_, best_ap)) if best_ap.signal_strength >= ap.signal_strength => {}
+                _ => best = Some((network, ap)),
+            }
+        }
+    }
+    best
+}
+
+fn connect_to(
+    wifi: &mut BlockingWifi<&mut EspWifi<'static>>,
+    network: &WifiCredentials,
+    channel: Option<u8>,
+) -> Result<()> {
     let mut auth_method = AuthMethod::WPA2Personal;
-    if ssid.is_empty() {
+    if network.ssid.is_empty() {
         bail!("Missing WiFi name")
     }
-    if pass.is_empty() {
+    if network.password.is_empty() {
         auth_method = AuthMethod::None;
         info!("Wifi password is empty");
     }
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
-
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
-
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
-
-    info!("Starting wifi...");
-
-    wifi.start()?;
-
-    info!("Scanning...");
-
-    let ap_infos = wifi.scan()?;
-
-    let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
-
-    let channel = if let Some(ours) = ours {
-        info!(
-            "Found configured access point {} on channel {}",
-            ssid, ours.channel
-        );
-        Some(ours.channel)
-    } else {
-        info!(
-            "Configured access point {} not found during scanning, will go with unknown channel",
-            ssid
-        );
-        None
-    };
 
     wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid
+        ssid: network
+            .ssid
+            .as_str()
             .try_into()
             .expect("SSID could not be converted to heapless String"),
-        password: pass
+        password: network
+            .password
+            .as_str()
             .try_into()
             .expect("Password could not be converted to heapless String"),
         channel,
@@ -62,7 +97,7 @@ pub fn my_wifi(
         ..Default::default()
     }))?;
 
-    info!("Connecting wifi...");
+    info!("Connecting wifi to {}...", network.ssid);
 
     wifi.connect()?;
 
@@ -78,5 +113,123 @@ pub fn my_wifi(
         wifi.wifi().sta_netif().get_hostname()?
     );
 
-    Ok(Box::new(esp_wifi))
+    Ok(())
+}
+
+/// Connects to the strongest known network, falling back to trying each one
+/// in priority order (hidden networks don't show up in scans).
+pub fn connect_wifi(
+    esp_wifi: &mut EspWifi<'static>,
+    networks: &[WifiCredentials],
+    sysloop: EspSystemEventLoop,
+) -> Result<WifiCredentials> {
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
+
+    if !wifi.is_started()? {
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+
+        info!("Starting wifi...");
+
+        wifi.start()?;
+    }
+
+    info!("Scanning...");
+
+    let ap_infos = wifi.scan()?;
+
+    if let Some((network, ap)) = strongest_known(networks, &ap_infos) {
+        info!(
+            "Found known access point {} on channel {} ({} dBm)",
+            network.ssid, ap.channel, ap.signal_strength
+        );
+        match connect_to(&mut wifi, network, Some(ap.channel)) {
+            Ok(()) => return Ok(network.clone()),
+            Err(err) => warn!("Failed to connect to {}: {:?}", network.ssid, err),
+        }
+    }
+
+    for network in networks {
+        info!(
+            "Trying {} with unknown channel, it was not found during scanning",
+            network.ssid
+        );
+        match connect_to(&mut wifi, network, None) {
+            Ok(()) => return Ok(network.clone()),
+            Err(err) => warn!("Failed to connect to {}: {:?}", network.ssid, err),
+        }
+    }
+
+    bail!("Could not connect to any known network")
+}
+
+/// Keeps the camera connected to the strongest known network, reconnecting
+/// when the link drops and roaming when the signal gets weak.
+pub fn spawn_roaming(
+    wifi: SharedWifi,
+    networks: Vec<WifiCredentials>,
+    mut current: WifiCredentials,
+    sysloop: EspSystemEventLoop,
+    status: Arc<Mutex<WifiStatus>>,
+) -> Result<()> {
+    *status.lock().unwrap() = WifiStatus {
+        network: Some(current.ssid.clone()),
+        rssi: current_rssi(),
+    };
+
+    std::thread::Builder::new()
+        .name("roaming".into())
+        .stack_size(8192)
+        .spawn(move || loop {
+            std::thread::sleep(ROAM_CHECK_INTERVAL);
+
+            let mut guard = wifi.lock().unwrap();
+            let connected = guard.is_connected().unwrap_or(false);
+            let rssi = current_rssi();
+            status.lock().unwrap().rssi = rssi;
+            let weak = matches!(rssi, Some(rssi) if rssi < ROAM_RSSI_THRESHOLD);
+            if connected && !weak {
+                continue;
+            }
+
+            let reconnect = if !connected {
+                warn!("Disconnected from {}, reconnecting", current.ssid);
+                true
+            } else {
+                let rssi = rssi.unwrap_or(i8::MIN);
+                info!(
+                    "Weak signal on {} ({} dBm), looking for a better network",
+                    current.ssid, rssi
+                );
+                let better = guard.scan().ok().and_then(|ap_infos| {
+                    strongest_known(&networks, &ap_infos).and_then(|(network, ap)| {
+                        (network.ssid != current.ssid
+                            && ap.signal_strength >= rssi.saturating_add(ROAM_HYSTERESIS))
+                        .then(|| network.clone())
+                    })
+                });
+                match better {
+                    Some(network) => {
+                        info!("Roaming to {}", network.ssid);
+                        guard.disconnect().ok();
+                        true
+                    }
+                    None => false,
+                }
+            };
+
+            if reconnect {
+                *status.lock().unwrap() = WifiStatus::default();
+                match connect_wifi(&mut guard, &networks, sysloop.clone()) {
+                    Ok(network) => {
+                        current = network;
+                        *status.lock().unwrap() = WifiStatus {
+                            network: Some(current.ssid.clone()),
+                            rssi: current_rssi(),
+                        };
+                    }
+                    Err(err) => warn!("Reconnect failed: {:?}", err),
+                }
+            }
+        })?;
+    Ok(())
 }
@@ -0,0 +1,368 @@
+//! Accessory outputs: PWM headlights, a passive buzzer playing tone patterns
+//! and a WS2812 RGB LED, which shows the boot state until the driver picks a
+//! colour.
+//!
+//! Accessory messages are `X` followed by a text command, the same on every
+//! transport (see [`AccessoryCommand::parse`]):
+//!
+//! - `light <0-100>` sets the headlight brightness in percent.
+//! - `tone <off|horn|beep|chirp|alarm>` plays a pattern on the buzzer,
+//!   replacing the one playing.
+//! - `led <rrggbb>` sets the RGB LED colour, `led status` hands it back to
+//!   the boot state.
+//! - `look <pan> <tilt>` turns the camera gimbal, with the stick deflection
+//!   from -100 to 100, and `center` points it straight ahead (see
+//!   `gimbal.rs`).
+//!
+//! They are handled by [`accessory_task`] and the gimbal task, so they never
+//! hold up the drive loop.
+
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use esp_hal::gpio::AnyPin;
+use esp_hal::ledc::{
+    channel::{self, ChannelIFace},
+    timer::{self, TimerIFace},
+    Ledc, LowSpeed,
+};
+use esp_hal::peripherals::RMT;
+use esp_hal::prelude::*;
+use esp_hal::rmt::{self, PulseCode, Rmt, TxChannel, TxChannelConfig, TxChannelCreator};
+use esp_hal::Blocking;
+
+use crate::gimbal::{self, Aim};
+use crate::status::{self, BootState};
+
+/// First byte of an accessory message, followed by the text command.
+pub const ACCESSORY_MESSAGE: u8 = b'X';
+/// Low speed LEDC channel of the buzzer, the headlights use channel 0.
+const BUZZER_CHANNEL: channel::Number = channel::Number::Channel1;
+/// How often the RGB LED catches up with the boot state.
+const LED_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+/// Boot state colours are kept dim, a WS2812 at full brightness is blinding.
+const STATUS_BRIGHTNESS: u8 = 32;
+
+/// WS2812 bit timings in 12.5ns RMT ticks (80MHz, no divider).
+const T0H: u16 = 32;
+const T0L: u16 = 68;
+const T1H: u16 = 64;
+const T1L: u16 = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    /// Parses `rrggbb` in hex.
+    fn parse(hex: &str) -> Option<Self> {
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        Some(Self {
+            r: (value >> 16) as u8,
+            g: (value >> 8) as u8,
+            b: value as u8,
+        })
+    }
+
+    fn for_state(state: BootState) -> Self {
+        let (r, g, b) = match state {
+            BootState::Init => (1, 1, 1),
+            BootState::WifiConnecting => (0, 0, 1),
+            BootState::NetUp => (0, 1, 1),
+            BootState::Ready => (0, 1, 0),
+            BootState::Fault(_) => (1, 0, 0),
+        };
+        Self {
+            r: r * STATUS_BRIGHTNESS,
+            g: g * STATUS_BRIGHTNESS,
+            b: b * STATUS_BRIGHTNESS,
+        }
+    }
+}
+
+/// One note: frequency in Hz (0 for a rest) and duration in ms.
+type Note = (u32, u64);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tone {
+    Off,
+    Horn,
+    Beep,
+    Chirp,
+    Alarm,
+}
+
+impl Tone {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Tone::Off),
+            "horn" => Some(Tone::Horn),
+            "beep" => Some(Tone::Beep),
+            "chirp" => Some(Tone::Chirp),
+            "alarm" => Some(Tone::Alarm),
+            _ => None,
+        }
+    }
+
+    fn notes(self) -> &'static [Note] {
+        match self {
+            Tone::Off => &[],
+            Tone::Horn => &[(392, 250), (0, 60), (392, 400)],
+            Tone::Beep => &[(2000, 100)],
+            Tone::Chirp => &[(1200, 50), (1800, 50), (2400, 80)],
+            Tone::Alarm => &[
+                (880, 200),
+                (660, 200),
+                (880, 200),
+                (660, 200),
+                (880, 200),
+                (660, 200),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessoryCommand {
+    /// Brightness in percent.
+    Headlights(u8),
+    Tone(Tone),
+    /// `None` shows the boot state again.
+    Led(Option<Rgb>),
+    Gimbal(Aim),
+}
+
+impl AccessoryCommand {
+    /// Parses the text after [`ACCESSORY_MESSAGE`].
+    pub fn parse(text: &[u8]) -> Option<Self> {
+        let text = core::str::from_utf8(text).ok()?.trim();
+        let (name, argument) = text.split_once(' ').unwrap_or((text, ""));
+        let argument = argument.trim();
+        match name {
+            "light" => argument
+                .parse()
+                .ok()
+                .filter(|brightness| *brightness <= 100)
+                .map(AccessoryCommand::Headlights),
+            "tone" => Tone::parse(argument).map(AccessoryCommand::Tone),
+            "led" if argument == "status" => Some(AccessoryCommand::Led(None)),
+            "led" => Rgb::parse(argument).map(|colour| AccessoryCommand::Led(Some(colour))),
+            "look" => {
+                let (pan, tilt) = argument.split_once(' ')?;
+                let deflection = |value: &str| {
+                    value
+                        .trim()
+                        .parse::<i8>()
+                        .ok()
+                        .filter(|value| (-100..=100).contains(value))
+                };
+                Some(AccessoryCommand::Gimbal(Aim::Look {
+                    pan: deflection(pan)?,
+                    tilt: deflection(tilt)?,
+                }))
+            }
+            "center" if argument.is_empty() => Some(AccessoryCommand::Gimbal(Aim::Center)),
+            _ => None,
+        }
+    }
+}
+
+static COMMANDS: Channel<CriticalSectionRawMutex, AccessoryCommand, 4> = Channel::new();
+
+/// Queues a command for [`accessory_task`], returns false if the queue is
+/// full. Gimbal commands go straight to the gimbal, only the latest matters.
+pub fn request(command: AccessoryCommand) -> bool {
+    if let AccessoryCommand::Gimbal(aim) = command {
+        gimbal::aim(aim);
+        return true;
+    }
+    COMMANDS.try_send(command).is_ok()
+}
+
+/// Headlight PWM output, duty in percent.
+pub type Headlights = channel::Channel<'static, LowSpeed, AnyPin>;
+
+/// Sets up the headlights on low speed channel 0, initially off. `None` if
+/// the channel couldn't be configured.
+pub fn headlights(
+    ledc: &'static Ledc<'static>,
+    timer: &'static timer::Timer<'static, LowSpeed>,
+    pin: AnyPin,
+) -> Option<Headlights> {
+    let mut channel = ledc.get_channel(channel::Number::Channel0, pin);
+    channel
+        .configure(channel::config::Config {
+            timer,
+            duty_pct: 0,
+            pin_config: channel::config::PinConfig::PushPull,
+        })
+        .inspect_err(|e| log::error!("Failed to set up the headlights: {:?}", e))
+        .ok()?;
+    Some(channel)
+}
+
+/// A passive buzzer with its own LEDC timer, retuned for every note.
+pub struct Buzzer {
+    timer: timer::Timer<'static, LowSpeed>,
+    pin: AnyPin,
+}
+
+impl Buzzer {
+    pub fn new(mut timer: timer::Timer<'static, LowSpeed>, pin: AnyPin) -> Self {
+        // Configured again for every note, so this is only worth a warning.
+        if let Err(e) = timer.configure(buzzer_timer(1000)) {
+            log::warn!("Failed to configure the buzzer timer: {:?}", e);
+        }
+        let mut buzzer = Self { timer, pin };
+        buzzer.play(0);
+        buzzer
+    }
+
+    /// Plays a square wave at `frequency` Hz until the next call, 0 is
+    /// silent.
+    fn play(&mut self, frequency: u32) {
+        if frequency != 0 {
+            if let Err(e) = self.timer.configure(buzzer_timer(frequency)) {
+                log::warn!("Can't play {}Hz on the buzzer: {:?}", frequency, e);
+                return;
+            }
+        }
+        // The channel keeps the timer borrowed, so it is set up again for
+        // every note, it keeps running when dropped.
+        let mut channel = channel::Channel::new(BUZZER_CHANNEL, &mut self.pin);
+        let result = channel.configure(channel::config::Config {
+            timer: &self.timer,
+            duty_pct: if frequency == 0 { 0 } else { 50 },
+            pin_config: channel::config::PinConfig::PushPull,
+        });
+        if let Err(e) = result {
+            log::warn!("Failed to drive the buzzer: {:?}", e);
+        }
+    }
+}
+
+fn buzzer_timer(frequency: u32) -> timer::config::Config<timer::LSClockSource> {
+    timer::config::Config {
+        // Fine enough for the lowest notes with the 80MHz clock.
+        duty: timer::config::Duty::Duty10Bit,
+        clock_source: timer::LSClockSource::APBClk,
+        frequency: frequency.Hz(),
+    }
+}
+
+/// A single WS2812 on RMT channel 0.
+pub struct RgbLed {
+    /// `None` if the RMT couldn't be set up, the LED is then left alone.
+    channel: Option<rmt::Channel<Blocking, 0>>,
+}
+
+impl RgbLed {
+    pub fn new(rmt: RMT, pin: AnyPin) -> Self {
+        let config = TxChannelConfig {
+            clk_divider: 1,
+            idle_output: true,
+            idle_output_level: false,
+            ..TxChannelConfig::default()
+        };
+        let channel = Rmt::new(rmt, 80.MHz())
+            .and_then(|rmt| rmt.channel0.configure(pin, config))
+            .inspect_err(|e| log::error!("Failed to set up the RGB LED: {:?}", e))
+            .ok();
+        Self { channel }
+    }
+
+    fn show(&mut self, colour: Rgb) {
+        let Some(channel) = self.channel.take() else {
+            return;
+        };
+        // 24 bits, green first, then the end marker.
+        let mut pulses = [0u32; 25];
+        let bits = (colour.g as u32) << 16 | (colour.r as u32) << 8 | colour.b as u32;
+        for (i, pulse) in pulses[..24].iter_mut().enumerate() {
+            let (high, low) = if bits & (1 << (23 - i)) != 0 {
+                (T1H, T1L)
+            } else {
+                (T0H, T0L)
+            };
+            *pulse = PulseCode {
+                level1: true,
+                length1: high,
+                level2: false,
+                length2: low,
+            }
+            .into();
+        }
+        self.channel = match channel.transmit(&pulses).wait() {
+            Ok(channel) => Some(channel),
+            Err((e, channel)) => {
+                log::warn!("Failed to set the RGB LED: {:?}", e);
+                Some(channel)
+            }
+        };
+    }
+}
+
+/// Starts the next note of the pattern, returning when it ends.
+fn next_note(buzzer: &mut Buzzer, notes: &mut &'static [Note]) -> Option<Instant> {
+    match notes.split_first() {
+        Some((&(frequency, ms), rest)) => {
+            buzzer.play(frequency);
+            *notes = rest;
+            Some(Instant::now() + Duration::from_millis(ms))
+        }
+        None => {
+            buzzer.play(0);
+            None
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn accessory_task(headlights: Option<Headlights>, mut buzzer: Buzzer, mut led: RgbLed) {
+    let mut notes: &'static [Note] = &[];
+    let mut note_end = None;
+    let mut colour = None;
+    let mut shown = None;
+    let mut ticker = Ticker::every(LED_REFRESH_INTERVAL);
+    loop {
+        let note_done = async {
+            match note_end {
+                Some(end) => Timer::at(end).await,
+                None => core::future::pending().await,
+            }
+        };
+        match select3(COMMANDS.receive(), note_done, ticker.next()).await {
+            Either3::First(command) => {
+                log::info!("Accessory: {:?}", command);
+                match command {
+                    AccessoryCommand::Headlights(brightness) => {
+                        // Only fails for duties above 100%.
+                        if let Some(headlights) = &headlights {
+                            let _ = headlights.set_duty(brightness);
+                        }
+                    }
+                    AccessoryCommand::Tone(tone) => {
+                        notes = tone.notes();
+                        note_end = next_note(&mut buzzer, &mut notes);
+                    }
+                    AccessoryCommand::Led(picked) => colour = picked,
+                    AccessoryCommand::Gimbal(aim) => gimbal::aim(aim),
+                }
+            }
+            Either3::Second(()) => note_end = next_note(&mut buzzer, &mut notes),
+            Either3::Third(()) => {}
+        }
+        let wanted = colour.unwrap_or_else(|| Rgb::for_state(status::get()));
+        if shown != Some(wanted) {
+            led.show(wanted);
+            shown = Some(wanted);
+        }
+    }
+}
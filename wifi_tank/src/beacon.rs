@@ -0,0 +1,94 @@
+//! Broadcasts a small JSON beacon every second so the client can find the
+//! rover where mDNS multicast is blocked. The camera sends the same beacon
+//! (see `esp32cam/src/beacon.rs`), the `partner` field pairs the two.
+
+use core::fmt::Write as _;
+
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{Duration, Ticker};
+use serde::Serialize;
+
+use crate::udp_control::CONTROL_PORT;
+use crate::WifiDriver;
+
+pub const BEACON_PORT: u16 = 8090;
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize)]
+struct Beacon<'a> {
+    role: &'a str,
+    id: &'a str,
+    name: &'a str,
+    version: &'a str,
+    port: u16,
+    partner: Option<&'a str>,
+}
+
+/// Stable device ID from the factory MAC address, as hex.
+pub fn device_id() -> heapless::String<12> {
+    let mut id = heapless::String::new();
+    for byte in esp_hal::efuse::Efuse::get_mac_address() {
+        let _ = write!(id, "{:02x}", byte);
+    }
+    id
+}
+
+#[embassy_executor::task]
+pub async fn beacon_task(
+    stack: &'static Stack<WifiDriver>,
+    device_name: &'static str,
+    partner: Option<heapless::String<32>>,
+) {
+    let id = device_id();
+    log::info!("Device ID: {}", id);
+    stack.wait_config_up().await;
+    let mut beacon = [0; 256];
+    let len = match serde_json_core::to_slice(
+        &Beacon {
+            role: "rover",
+            id: &id,
+            name: device_name,
+            version: env!("CARGO_PKG_VERSION"),
+            port: CONTROL_PORT,
+            partner: partner.as_deref(),
+        },
+        &mut beacon,
+    ) {
+        Ok(len) => len,
+        Err(e) => {
+            log::error!("Failed to encode the beacon, not broadcasting: {:?}", e);
+            return;
+        }
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 512];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(BEACON_PORT) {
+        log::error!("Failed to bind the beacon port, not broadcasting: {:?}", e);
+        return;
+    }
+
+    let mut ticker = Ticker::every(BEACON_INTERVAL);
+    loop {
+        // Use the subnet broadcast address, it follows DHCP renewals.
+        let broadcast = stack
+            .config_v4()
+            .and_then(|config| config.address.broadcast())
+            .unwrap_or(Ipv4Address::BROADCAST);
+        let destination = IpEndpoint::new(broadcast.into(), BEACON_PORT);
+        if let Err(e) = socket.send_to(&beacon[..len], destination).await {
+            log::warn!("Failed to send beacon: {:?}", e);
+        }
+        ticker.next().await;
+    }
+}
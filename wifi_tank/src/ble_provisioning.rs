@@ -0,0 +1,233 @@
+//! Simple BLE GATT provisioning service, shared with the `esp32cam` board.
+//!
+//! Service `7e5a0001-2f4b-4c3a-9b8e-1f2d3c4b5a60` with the write
+//! characteristics SSID (`..0002`), password (`..0003`), device name
+//! (`..0004`) and command (`..0005`, `0x01` commit, `0x02` forget), plus a
+//! readable status string (`..0006`). The board reboots after a successful
+//! commit or forget.
+
+use core::cell::RefCell;
+
+use bleps::{
+    ad_structure::{
+        create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
+    },
+    async_attribute_server::AttributeServer,
+    asynch::Ble,
+    attribute_server::NotificationData,
+    gatt, Uuid,
+};
+use embassy_time::{Duration, Timer};
+use esp_wifi::ble::controller::asynch::BleConnector;
+
+use crate::settings::{self, SettingsStore, WifiCredentials};
+
+const COMMAND_COMMIT: u8 = 0x01;
+const COMMAND_FORGET: u8 = 0x02;
+/// A scan response holds 31 bytes, less 2 for the name's length and type.
+const MAX_ADVERTISED_NAME: usize = 29;
+
+// 7e5a0001-2f4b-4c3a-9b8e-1f2d3c4b5a60 in little endian byte order.
+const SERVICE_UUID: [u8; 16] = [
+    0x60, 0x5a, 0x4b, 0x3c, 0x2d, 0x1f, 0x8e, 0x9b, 0x3a, 0x4c, 0x4b, 0x2f, 0x01, 0x00, 0x5a, 0x7e,
+];
+
+#[derive(Default)]
+struct ProvisioningState {
+    ssid: heapless::Vec<u8, 32>,
+    password: heapless::Vec<u8, 64>,
+    name: heapless::Vec<u8, 32>,
+    status: heapless::String<64>,
+    reboot: bool,
+}
+
+impl ProvisioningState {
+    fn set_status(&mut self, status: &str) {
+        self.status.clear();
+        let _ = self.status.push_str(status);
+        log::info!("BLE provisioning: {}", status);
+    }
+
+    fn commit(&mut self, store: &mut SettingsStore) {
+        let (Ok(ssid), Ok(password)) = (
+            core::str::from_utf8(&self.ssid),
+            core::str::from_utf8(&self.password),
+        ) else {
+            return self.set_status("error: credentials are not valid UTF-8");
+        };
+        if ssid.is_empty() {
+            return self.set_status("error: no SSID written");
+        }
+        let Some(credentials) = WifiCredentials::new(ssid, password) else {
+            return self.set_status("error: SSID or password too long");
+        };
+
+        let mut settings = store.load();
+        settings.add_network(credentials);
+        if !self.name.is_empty() {
+            let name = core::str::from_utf8(&self.name).ok();
+            match name.and_then(settings::parse_device_name) {
+                Some(name) => settings.device_name = Some(name),
+                None => return self.set_status("error: invalid device name"),
+            }
+        }
+
+        match store.save(&settings) {
+            Ok(()) => {
+                self.set_status("ok: saved, rebooting");
+                self.reboot = true;
+            }
+            Err(_) => self.set_status("error: failed to write flash"),
+        }
+    }
+
+    fn forget(&mut self, store: &mut SettingsStore) {
+        let mut settings = store.load();
+        settings.networks.clear();
+        match store.save(&settings) {
+            Ok(()) => {
+                self.set_status("ok: forgotten, rebooting");
+                self.reboot = true;
+            }
+            Err(_) => self.set_status("error: failed to write flash"),
+        }
+    }
+}
+
+fn store_value<const N: usize>(target: &mut heapless::Vec<u8, N>, offset: usize, data: &[u8]) {
+    // Long writes arrive in several chunks, offset 0 starts a new value.
+    if offset == 0 {
+        target.clear();
+    }
+    if target.extend_from_slice(data).is_err() {
+        log::warn!("BLE provisioning value too long, truncated");
+    }
+}
+
+/// The name for the scan response. Device names can be up to 32 bytes, longer
+/// ones are sent shortened and clients match on the service UUID anyway.
+fn local_name(name: &str) -> AdStructure<'_> {
+    if name.len() <= MAX_ADVERTISED_NAME {
+        return AdStructure::CompleteLocalName(name);
+    }
+    let mut end = MAX_ADVERTISED_NAME;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    AdStructure::ShortenedLocalName(&name[..end])
+}
+
+#[embassy_executor::task]
+pub async fn ble_provisioning_task(
+    connector: BleConnector<'static>,
+    mut store: SettingsStore,
+    device_name: &'static str,
+) {
+    let mut ble = Ble::new(connector, esp_wifi::current_millis);
+    let state = RefCell::new(ProvisioningState::default());
+    state.borrow_mut().set_status("idle");
+
+    loop {
+        if let Err(e) = ble.init().await {
+            log::warn!("BLE init failed: {:?}", e);
+            Timer::after(Duration::from_secs(1)).await;
+            continue;
+        }
+        let (Ok(advertising_data), Ok(scan_response_data)) = (
+            create_advertising_data(&[
+                AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+                AdStructure::ServiceUuids128(&[Uuid::Uuid128(SERVICE_UUID)]),
+            ]),
+            create_advertising_data(&[local_name(device_name)]),
+        ) else {
+            log::error!("BLE advertising data too long, provisioning disabled");
+            return;
+        };
+        let advertising = ble.cmd_set_le_advertising_parameters().await.is_ok()
+            && ble
+                .cmd_set_le_advertising_data(advertising_data)
+                .await
+                .is_ok()
+            && ble
+                .cmd_set_le_scan_rsp_data(scan_response_data)
+                .await
+                .is_ok()
+            && ble.cmd_set_le_advertise_enable(true).await.is_ok();
+        if !advertising {
+            log::warn!("Failed to start BLE advertising, retrying");
+            Timer::after(Duration::from_secs(1)).await;
+            continue;
+        }
+        log::info!("BLE provisioning service advertising as {}", device_name);
+
+        let mut ssid_write = |offset: usize, data: &[u8]| {
+            store_value(&mut state.borrow_mut().ssid, offset, data);
+        };
+        let mut password_write = |offset: usize, data: &[u8]| {
+            store_value(&mut state.borrow_mut().password, offset, data);
+        };
+        let mut name_write = |offset: usize, data: &[u8]| {
+            store_value(&mut state.borrow_mut().name, offset, data);
+        };
+        let mut command_write = |_offset: usize, data: &[u8]| {
+            let mut state = state.borrow_mut();
+            match data.first() {
+                Some(&COMMAND_COMMIT) => state.commit(&mut store),
+                Some(&COMMAND_FORGET) => state.forget(&mut store),
+                _ => state.set_status("error: unknown command"),
+            }
+        };
+        let mut status_read = |offset: usize, data: &mut [u8]| {
+            let state = state.borrow();
+            let status = state.status.as_bytes();
+            let remaining = status.get(offset..).unwrap_or_default();
+            let len = remaining.len().min(data.len());
+            data[..len].copy_from_slice(&remaining[..len]);
+            len
+        };
+
+        gatt!([service {
+            uuid: "7e5a0001-2f4b-4c3a-9b8e-1f2d3c4b5a60",
+            characteristics: [
+                characteristic {
+                    uuid: "7e5a0002-2f4b-4c3a-9b8e-1f2d3c4b5a60",
+                    write: ssid_write,
+                },
+                characteristic {
+                    uuid: "7e5a0003-2f4b-4c3a-9b8e-1f2d3c4b5a60",
+                    write: password_write,
+                },
+                characteristic {
+                    uuid: "7e5a0004-2f4b-4c3a-9b8e-1f2d3c4b5a60",
+                    write: name_write,
+                },
+                characteristic {
+                    uuid: "7e5a0005-2f4b-4c3a-9b8e-1f2d3c4b5a60",
+                    write: command_write,
+                },
+                characteristic {
+                    uuid: "7e5a0006-2f4b-4c3a-9b8e-1f2d3c4b5a60",
+                    read: status_read,
+                },
+            ],
+        },]);
+
+        let mut rng = bleps::no_rng::NoRng;
+        let mut server = AttributeServer::new(&mut ble, &mut gatt_attributes, &mut rng);
+
+        // We never notify, the notifier is only used to reboot once the client
+        // has had a chance to read the status.
+        let mut notifier = || async {
+            while !state.borrow().reboot {
+                Timer::after(Duration::from_millis(100)).await;
+            }
+            Timer::after(Duration::from_millis(1500)).await;
+            esp_hal::reset::software_reset();
+            core::future::pending::<NotificationData>().await
+        };
+
+        if let Err(e) = server.run(&mut notifier).await {
+            log::warn!("BLE provisioning server stopped: {:?}", e);
+        }
+    }
+}
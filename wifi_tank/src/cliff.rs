@@ -0,0 +1,74 @@
+//! Downward IR reflectance sensors at the front corners, for driving on
+//! tables. Their digital output (TCRT5000 modules) goes high when nothing
+//! reflects, which means there is no floor under them.
+//!
+//! Losing the floor is reported as a safety event in telemetry and sent to
+//! the drive loop through [`control::report_cliff`], which it handles before
+//! any queued command: it brakes, backs off and refuses to drive forward
+//! again until the driver lets go of forward.
+//!
+//! The sensor pins have no pull resistors, so the sensors are only read when
+//! `cliff_sensors` is enabled in the drive configuration.
+
+use embassy_time::{Duration, Ticker};
+use esp_hal::gpio::{AnyPin, Input};
+
+use crate::control;
+use crate::telemetry;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edge {
+    Left,
+    Right,
+    Both,
+}
+
+impl Edge {
+    fn detect(left: bool, right: bool) -> Option<Self> {
+        match (left, right) {
+            (false, false) => None,
+            (true, false) => Some(Edge::Left),
+            (false, true) => Some(Edge::Right),
+            (true, true) => Some(Edge::Both),
+        }
+    }
+
+    /// Name of the safety event.
+    fn name(self) -> &'static str {
+        match self {
+            Edge::Left => "cliff_left",
+            Edge::Right => "cliff_right",
+            Edge::Both => "cliff_both",
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn cliff_task(left: Input<'static, AnyPin>, right: Input<'static, AnyPin>) {
+    let mut reported = None;
+    let mut ticker = Ticker::every(POLL_INTERVAL);
+    loop {
+        ticker.next().await;
+        let edge = if control::config().cliff_sensors {
+            Edge::detect(left.is_high(), right.is_high())
+        } else {
+            None
+        };
+        if edge == reported {
+            continue;
+        }
+        // Only the floor disappearing is an event, not the other sensor
+        // losing it too.
+        if let (Some(edge), None) = (edge, reported) {
+            log::warn!("Safety event: {}", edge.name());
+            telemetry::update(|t| {
+                t.safety = Some(edge.name());
+                t.safety_events = t.safety_events.wrapping_add(1);
+            });
+        }
+        control::report_cliff(edge.is_some());
+        reported = edge;
+    }
+}
@@ -0,0 +1,226 @@
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration, Timer};
+use esp_wifi::wifi::{
+    AccessPointInfo, AuthMethod, ClientConfiguration, Configuration, WifiController, WifiEvent,
+    WifiState,
+};
+
+use crate::settings::{WifiCredentials, MAX_NETWORKS};
+use crate::status::{self, BootState, Fault};
+use crate::telemetry;
+
+const MAX_SCAN_RESULTS: usize = 16;
+const ROAM_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Below this signal strength we start looking for a better access point.
+const ROAM_RSSI_THRESHOLD: i8 = -75;
+/// How much stronger another access point must be before we switch to it.
+const ROAM_HYSTERESIS: i8 = 8;
+
+type KnownNetworks = heapless::Vec<WifiCredentials, { MAX_NETWORKS + 1 }>;
+
+static SHUTDOWN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static STOPPED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Signal strength of the access point we are currently associated with.
+fn current_rssi() -> Option<i8> {
+    let mut info: esp_wifi_sys::include::wifi_ap_record_t = unsafe { core::mem::zeroed() };
+    let result = unsafe { esp_wifi_sys::include::esp_wifi_sta_get_ap_info(&mut info) };
+    (result == esp_wifi_sys::include::ESP_OK as i32).then_some(info.rssi)
+}
+
+async fn scan(
+    controller: &mut WifiController<'static>,
+) -> heapless::Vec<AccessPointInfo, MAX_SCAN_RESULTS> {
+    match controller.scan_n::<MAX_SCAN_RESULTS>().await {
+        Ok((results, _)) => results,
+        Err(e) => {
+            log::warn!("Wifi scan failed: {:?}", e);
+            heapless::Vec::new()
+        }
+    }
+}
+
+/// Picks the strongest visible known network, ties go to the higher priority
+/// (earlier) network.
+fn strongest_known<'a>(
+    networks: &'a KnownNetworks,
+    scan_results: &[AccessPointInfo],
+) -> Option<(&'a WifiCredentials, i8)> {
+    networks
+        .iter()
+        .filter_map(|network| {
+            scan_results
+                .iter()
+                .filter(|ap| ap.ssid == network.ssid)
+                .map(|ap| ap.signal_strength)
+                .max()
+                .map(|rssi| (network, rssi))
+        })
+        .fold(None, |best, (network, rssi)| match best {
+            Some((_, best_rssi)) if best_rssi >= rssi => best,
+            _ => Some((network, rssi)),
+        })
+}
+
+async fn connect_to(controller: &mut WifiController<'static>, network: &WifiCredentials) -> bool {
+    let config = Configuration::Client(ClientConfiguration {
+        ssid: network.ssid.clone(),
+        bssid: None,
+        auth_method: AuthMethod::WPA2Personal, // WPA2Personal - AP is technically WPA3 too, but seems board doesn't support this
+        password: network.password.clone(),
+        channel: None,
+    });
+    if let Err(e) = controller.set_configuration(&config) {
+        log::warn!("Failed to configure wifi for {}: {:?}", network.ssid, e);
+        return false;
+    }
+    log::info!("Connecting to {}", network.ssid);
+    match controller.connect().await {
+        Ok(()) => {
+            log::info!("Connected to {}", network.ssid);
+            telemetry::update(|t| {
+                t.network = Some(network.ssid.clone());
+                t.rssi = current_rssi();
+            });
+            true
+        }
+        Err(e) => {
+            log::info!("Failed to connect to {}: {:?}", network.ssid, e);
+            false
+        }
+    }
+}
+
+/// Connects to the best known network, returning the one we joined.
+async fn connect_best(
+    controller: &mut WifiController<'static>,
+    networks: &KnownNetworks,
+) -> Option<WifiCredentials> {
+    let scan_results = scan(controller).await;
+    if let Some((network, rssi)) = strongest_known(networks, &scan_results) {
+        log::info!("Strongest known network is {} ({} dBm)", network.ssid, rssi);
+        if connect_to(controller, network).await {
+            return Some(network.clone());
+        }
+    }
+
+    // Hidden networks don't show up in scans, so fall back to trying each
+    // known network in priority order.
+    for network in networks {
+        if connect_to(controller, network).await {
+            return Some(network.clone());
+        }
+    }
+    None
+}
+
+/// Keeps the rover connected to the strongest known network, reconnecting
+/// when the link drops and roaming when the signal gets weak.
+async fn maintain_connection(controller: &mut WifiController<'static>, networks: &KnownNetworks) {
+    status::set(BootState::WifiConnecting);
+    while !matches!(controller.is_started(), Ok(true)) {
+        log::info!("Starting wifi");
+        match controller.start().await {
+            Ok(()) => status::set(BootState::WifiConnecting),
+            Err(e) => {
+                log::error!("Failed to start wifi: {:?}", e);
+                status::set(BootState::Fault(Fault::WifiStart));
+                Timer::after(Duration::from_secs(5)).await;
+            }
+        }
+    }
+
+    loop {
+        let Some(current) = connect_best(controller, networks).await else {
+            // Provisioning mode: keep retrying slowly while the serial console
+            // and BLE wait for new credentials (they reboot the board).
+            log::warn!(
+                "Could not connect to any known network. Provision with: wifi <ssid> <password>"
+            );
+            telemetry::update(|t| {
+                t.network = None;
+                t.rssi = None;
+            });
+            Timer::after(Duration::from_secs(10)).await;
+            continue;
+        };
+
+        // Connected, wait for a disconnect while periodically checking if we
+        // should roam.
+        while esp_wifi::wifi::get_wifi_state() == WifiState::StaConnected {
+            match select(
+                controller.wait_for_event(WifiEvent::StaDisconnected),
+                Timer::after(ROAM_CHECK_INTERVAL),
+            )
+            .await
+            {
+                Either::First(()) => {
+                    log::warn!("Disconnected from {}", current.ssid);
+                    break;
+                }
+                Either::Second(()) => {}
+            }
+
+            let Some(rssi) = current_rssi() else {
+                continue;
+            };
+            telemetry::update(|t| t.rssi = Some(rssi));
+            if rssi >= ROAM_RSSI_THRESHOLD {
+                continue;
+            }
+
+            log::info!(
+                "Weak signal on {} ({} dBm), looking for a better network",
+                current.ssid,
+                rssi
+            );
+            let scan_results = scan(controller).await;
+            if let Some((better, better_rssi)) = strongest_known(networks, &scan_results) {
+                if better.ssid != current.ssid && better_rssi >= rssi + ROAM_HYSTERESIS {
+                    log::info!("Roaming to {} ({} dBm)", better.ssid, better_rssi);
+                    let _ = controller.disconnect().await;
+                    break;
+                }
+            }
+        }
+
+        telemetry::update(|t| {
+            t.network = None;
+            t.rssi = None;
+        });
+        status::set(BootState::WifiConnecting);
+        Timer::after(Duration::from_millis(500)).await;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn connection_task(mut controller: WifiController<'static>, networks: KnownNetworks) {
+    select(
+        maintain_connection(&mut controller, &networks),
+        SHUTDOWN.wait(),
+    )
+    .await;
+
+    log::info!("Stopping wifi");
+    if let Err(e) = controller.stop().await {
+        log::warn!("Failed to stop wifi: {:?}", e);
+    }
+    telemetry::update(|t| {
+        t.network = None;
+        t.rssi = None;
+    });
+    STOPPED.signal(());
+}
+
+/// Asks the connection task to power down the radio and waits (briefly) for
+/// it to finish.
+pub async fn shutdown() {
+    SHUTDOWN.signal(());
+    if with_timeout(Duration::from_secs(2), STOPPED.wait())
+        .await
+        .is_err()
+    {
+        log::warn!("Timed out waiting for wifi to stop");
+    }
+}
@@ -0,0 +1,196 @@
+use core::str::FromStr;
+
+use esp_hal::{peripherals::UART0, uart::UartRx, Async};
+
+use crate::settings::{self, IpConfig, Settings, SettingsStore, StaticIp, WifiCredentials};
+
+const MAX_LINE_LENGTH: usize = 128;
+const HELP: &str = "Commands:\n  wifi <ssid> <password>  store network as highest priority and reboot\n  networks                list stored networks in priority order\n  forget [ssid]           erase one (or all) stored networks and reboot\n  idle-timeout <secs>     sleep after this long without commands (0 disables) and reboot\n  wake-interval <secs>    also wake from sleep after this long (0 button only) and reboot\n  pair <camera id>        pair with a camera (shown in its beacon) and reboot\n  unpair                  forget the paired camera and reboot\n  name <name>             set the device name (hostname) and reboot\n  ip                      show the IPv4 configuration\n  ip <addr[/prefix]> [gateway] [dns]  use a static IPv4 address and reboot\n  ip dhcp | ip default    use DHCP, or the build-time configuration, and reboot\n  instructor-pin <pin>    require this PIN to change the speed cap over HTTP and reboot\n  instructor-pin clear    forget the PIN, locking the speed cap, and reboot\n  help                    show this message";
+
+/// Splits a console line into arguments, double quotes group words so SSIDs
+/// containing spaces can be entered as `wifi "My Network" password`.
+fn split_args(line: &str) -> heapless::Vec<&str, 4> {
+    let mut args = heapless::Vec::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        let (arg, remainder) = if let Some(quoted) = rest.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match rest.find(char::is_whitespace) {
+                Some(end) => (&rest[..end], &rest[end..]),
+                None => (rest, ""),
+            }
+        };
+        if args.push(arg).is_err() {
+            break;
+        }
+        rest = remainder.trim_start();
+    }
+    args
+}
+
+fn save_and_reboot(store: &mut SettingsStore, settings: &Settings) {
+    match store.save(settings) {
+        Ok(()) => {
+            log::info!("Settings saved, rebooting");
+            esp_hal::reset::software_reset();
+        }
+        Err(e) => log::error!("Failed to save settings: {:?}", e),
+    }
+}
+
+fn handle_line(line: &str, store: &mut SettingsStore) {
+    let args = split_args(line);
+    match args.as_slice() {
+        [] => {}
+        ["wifi", ssid, password, ..] => {
+            let Some(credentials) = WifiCredentials::new(ssid, password) else {
+                log::warn!("SSID must be at most 32 bytes and password at most 64 bytes");
+                return;
+            };
+            let mut settings = store.load();
+            settings.add_network(credentials);
+            log::info!("Storing credentials for {}", ssid);
+            save_and_reboot(store, &settings);
+        }
+        ["wifi", ..] => log::warn!("Usage: wifi <ssid> <password>"),
+        ["networks", ..] => {
+            for (priority, network) in store.load().known_networks().iter().enumerate() {
+                esp_println::println!("{}: {}", priority, network.ssid);
+            }
+        }
+        ["forget", ssid, ..] => {
+            let mut settings = store.load();
+            if !settings.forget_network(ssid) {
+                log::warn!("No stored network named {}", ssid);
+                return;
+            }
+            log::info!("Forgetting {}", ssid);
+            save_and_reboot(store, &settings);
+        }
+        ["forget"] => {
+            let mut settings = store.load();
+            settings.networks.clear();
+            log::info!("Forgetting all stored networks");
+            save_and_reboot(store, &settings);
+        }
+        ["idle-timeout", secs, ..] => {
+            let Ok(secs) = secs.parse::<u32>() else {
+                log::warn!("Usage: idle-timeout <secs>");
+                return;
+            };
+            let mut settings = store.load();
+            settings.idle_timeout_secs = Some(secs);
+            save_and_reboot(store, &settings);
+        }
+        ["wake-interval", secs, ..] => {
+            let Ok(secs) = secs.parse::<u32>() else {
+                log::warn!("Usage: wake-interval <secs>");
+                return;
+            };
+            let mut settings = store.load();
+            settings.wake_interval_secs = secs;
+            save_and_reboot(store, &settings);
+        }
+        ["pair", partner, ..] => {
+            let Ok(partner) = heapless::String::from_str(partner) else {
+                log::warn!("Camera ID must be at most 32 bytes");
+                return;
+            };
+            let mut settings = store.load();
+            settings.partner = Some(partner);
+            save_and_reboot(store, &settings);
+        }
+        ["unpair", ..] => {
+            let mut settings = store.load();
+            settings.partner = None;
+            save_and_reboot(store, &settings);
+        }
+        ["name", name, ..] => {
+            let Some(name) = settings::parse_device_name(name) else {
+                log::warn!("Names are letters, digits and -, at most 32 bytes");
+                return;
+            };
+            let mut settings = store.load();
+            settings.device_name = Some(name);
+            save_and_reboot(store, &settings);
+        }
+        ["ip"] => esp_println::println!("{}", store.load().ip_config()),
+        ["ip", "dhcp"] => {
+            let mut settings = store.load();
+            settings.ip_config = Some(IpConfig::Dhcp);
+            save_and_reboot(store, &settings);
+        }
+        ["ip", "default"] => {
+            let mut settings = store.load();
+            settings.ip_config = None;
+            save_and_reboot(store, &settings);
+        }
+        ["ip", address, rest @ ..] => {
+            let Some(static_ip) =
+                StaticIp::parse(address, rest.first().copied(), rest.get(1).copied())
+            else {
+                log::warn!("Usage: ip <addr[/prefix]> [gateway] [dns]");
+                return;
+            };
+            let mut settings = store.load();
+            settings.ip_config = Some(IpConfig::Static(static_ip));
+            save_and_reboot(store, &settings);
+        }
+        ["instructor-pin", "clear", ..] => {
+            let mut settings = store.load();
+            settings.instructor_pin = None;
+            save_and_reboot(store, &settings);
+        }
+        ["instructor-pin", pin, ..] => {
+            let Some(pin) = settings::parse_instructor_pin(pin) else {
+                log::warn!(
+                    "Instructor PINs are {} to 16 bytes",
+                    settings::MIN_INSTRUCTOR_PIN_LENGTH
+                );
+                return;
+            };
+            let mut settings = store.load();
+            settings.instructor_pin = Some(pin);
+            save_and_reboot(store, &settings);
+        }
+        ["help", ..] => esp_println::println!("{}", HELP),
+        [command, ..] => log::warn!("Unknown console command {}, try help", command),
+    }
+}
+
+/// Reads newline terminated provisioning commands from the USB serial port.
+#[embassy_executor::task]
+pub async fn console_task(mut rx: UartRx<'static, UART0, Async>, mut store: SettingsStore) {
+    let mut line = heapless::Vec::<u8, MAX_LINE_LENGTH>::new();
+    let mut buffer = [0u8; 32];
+    loop {
+        let read = match rx.read_async(&mut buffer).await {
+            Ok(read) => read,
+            Err(e) => {
+                log::warn!("Serial console read error: {:?}", e);
+                continue;
+            }
+        };
+        for &byte in &buffer[..read] {
+            match byte {
+                b'\r' | b'\n' => {
+                    match core::str::from_utf8(&line) {
+                        Ok(text) => handle_line(text, &mut store),
+                        Err(_) => log::warn!("Ignoring non UTF-8 console input"),
+                    }
+                    line.clear();
+                }
+                _ => {
+                    if line.push(byte).is_err() {
+                        log::warn!("Console line too long, discarding");
+                        line.clear();
+                    }
+                }
+            }
+        }
+    }
+}
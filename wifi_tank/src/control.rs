@@ -0,0 +1,214 @@
+//! Requests from other tasks to the main drive loop, which owns the motors.
+//! Drive commands from every transport (UDP and ESP-NOW) come through here.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use serde::Deserialize;
+
+use crate::metrics;
+use crate::motors::SpeedProfile;
+use crate::obstacle::Obstacle;
+use crate::settings::DriveConfig;
+
+/// Command byte sent by the client, the same on every transport.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriveCommand {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    Stop,
+    Quit,
+    /// Start or keep following the line, see `line.rs`.
+    LineFollow,
+    /// Switch speed profile, which isn't a movement.
+    Profile(SpeedProfile),
+}
+
+impl DriveCommand {
+    pub fn parse(byte: u8) -> Option<Self> {
+        match byte {
+            b'F' => Some(Self::Forward),
+            b'B' => Some(Self::Backward),
+            b'L' => Some(Self::Left),
+            b'R' => Some(Self::Right),
+            b'N' => Some(Self::Stop),
+            b'Q' => Some(Self::Quit),
+            b'A' => Some(Self::LineFollow),
+            b'1' => Some(Self::Profile(SpeedProfile::Turtle)),
+            b'2' => Some(Self::Profile(SpeedProfile::Normal)),
+            b'3' => Some(Self::Profile(SpeedProfile::Turbo)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ControlRequest {
+    /// From the gamepad client, resets the command watchdog.
+    Drive(DriveCommand),
+    /// From the HTTP server.
+    Stop,
+    Configure(DriveConfig),
+    /// From the rangefinders, when the way ahead changes.
+    Obstacle(Obstacle),
+    /// From the IMU, when the rover tips past the tilt limit or rights itself.
+    Tilt(bool),
+}
+
+static REQUESTS: Channel<CriticalSectionRawMutex, ControlRequest, 8> = Channel::new();
+/// Whether the floor is missing under a cliff sensor, kept out of the queue
+/// so it is handled first and never dropped.
+static CLIFF: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+static CONFIG: Mutex<CriticalSectionRawMutex, RefCell<DriveConfig>> =
+    Mutex::new(RefCell::new(DriveConfig::DEFAULT));
+
+/// Queues a request for the drive loop, returns false if the queue is full.
+pub fn request(request: ControlRequest) -> bool {
+    if REQUESTS.try_send(request).is_err() {
+        return false;
+    }
+    // Only once queued, so other tasks never use a configuration the drive
+    // loop won't get.
+    if let ControlRequest::Configure(config) = request {
+        CONFIG.lock(|current| *current.borrow_mut() = config);
+    }
+    true
+}
+
+pub async fn next_request() -> ControlRequest {
+    REQUESTS.receive().await
+}
+
+/// Tells the drive loop the floor disappeared or came back.
+pub fn report_cliff(floor_missing: bool) {
+    CLIFF.signal(floor_missing);
+}
+
+pub async fn next_cliff() -> bool {
+    CLIFF.wait().await
+}
+
+/// Drive configuration update, the body of `POST /config` (see `http.rs`)
+/// and of tuning messages from the client. Missing fields are left unchanged.
+#[derive(Debug, Deserialize)]
+struct ConfigUpdate {
+    watchdog_ms: Option<u32>,
+    max_speed: Option<u8>,
+    turn_speed: Option<u8>,
+    trim: Option<i8>,
+    obstacle_stop_cm: Option<u16>,
+    obstacle_slow_cm: Option<u16>,
+    heading_hold: Option<bool>,
+    tilt_limit_deg: Option<u8>,
+    cliff_sensors: Option<bool>,
+    line_speed: Option<u8>,
+    line_kp: Option<f32>,
+    line_ki: Option<f32>,
+    line_kd: Option<f32>,
+    pan_limit_deg: Option<u8>,
+    tilt_up_deg: Option<u8>,
+    tilt_down_deg: Option<u8>,
+    gimbal_speed_dps: Option<u16>,
+    speed_cap: Option<u8>,
+}
+
+impl ConfigUpdate {
+    /// `config` with the update applied. Only an `instructor` (see `http.rs`)
+    /// may change the speed cap.
+    fn apply(
+        &self,
+        mut config: DriveConfig,
+        instructor: bool,
+    ) -> Result<DriveConfig, &'static str> {
+        if self.speed_cap.is_some() && !instructor {
+            return Err("the speed cap can only be changed with the instructor PIN");
+        }
+        config.watchdog_ms = self.watchdog_ms.unwrap_or(config.watchdog_ms);
+        config.max_speed = self.max_speed.unwrap_or(config.max_speed);
+        config.turn_speed = self.turn_speed.unwrap_or(config.turn_speed);
+        config.trim = self.trim.unwrap_or(config.trim);
+        config.obstacle_stop_cm = self.obstacle_stop_cm.unwrap_or(config.obstacle_stop_cm);
+        config.obstacle_slow_cm = self.obstacle_slow_cm.unwrap_or(config.obstacle_slow_cm);
+        config.heading_hold = self.heading_hold.unwrap_or(config.heading_hold);
+        config.tilt_limit_deg = self.tilt_limit_deg.unwrap_or(config.tilt_limit_deg);
+        config.cliff_sensors = self.cliff_sensors.unwrap_or(config.cliff_sensors);
+        config.line_speed = self.line_speed.unwrap_or(config.line_speed);
+        config.line_kp = self.line_kp.unwrap_or(config.line_kp);
+        config.line_ki = self.line_ki.unwrap_or(config.line_ki);
+        config.line_kd = self.line_kd.unwrap_or(config.line_kd);
+        config.pan_limit_deg = self.pan_limit_deg.unwrap_or(config.pan_limit_deg);
+        config.tilt_up_deg = self.tilt_up_deg.unwrap_or(config.tilt_up_deg);
+        config.tilt_down_deg = self.tilt_down_deg.unwrap_or(config.tilt_down_deg);
+        config.gimbal_speed_dps = self.gimbal_speed_dps.unwrap_or(config.gimbal_speed_dps);
+        config.speed_cap = self.speed_cap.unwrap_or(config.speed_cap);
+        if !config.is_valid() {
+            return Err(
+                "speeds must be 0-100, trim -50-50, obstacle distances 0-400, \
+                        tilt limit 0-90, line gains 0-1000, gimbal limits 0-90 \
+                        and gimbal speed 1-360",
+            );
+        }
+        Ok(config)
+    }
+}
+
+/// Applies a JSON [`ConfigUpdate`] to the configuration in use and queues it
+/// for the drive loop, returning the new configuration.
+pub fn update_config(json: &[u8], instructor: bool) -> Result<DriveConfig, &'static str> {
+    let (update, _) = serde_json_core::from_slice::<ConfigUpdate>(json).map_err(|_| {
+        metrics::PARSE_ERRORS.inc();
+        "invalid JSON configuration"
+    })?;
+    let config = update.apply(config(), instructor)?;
+    if !request(ControlRequest::Configure(config)) {
+        return Err("rover busy, try again");
+    }
+    Ok(config)
+}
+
+/// The drive configuration currently in use.
+pub fn config() -> DriveConfig {
+    CONFIG.lock(|config| *config.borrow())
+}
+
+pub fn init(config: DriveConfig) {
+    CONFIG.lock(|current| *current.borrow_mut() = config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(json: &str) -> ConfigUpdate {
+        serde_json_core::from_slice(json.as_bytes()).unwrap().0
+    }
+
+    #[test]
+    fn speed_cap_needs_the_instructor() {
+        let capped = DriveConfig {
+            speed_cap: 40,
+            ..DriveConfig::DEFAULT
+        };
+        assert!(update(r#"{"speed_cap":100}"#).apply(capped, false).is_err());
+        assert!(update(r#"{"max_speed":100,"speed_cap":100}"#)
+            .apply(capped, false)
+            .is_err());
+        let lifted = update(r#"{"speed_cap":100}"#).apply(capped, true).unwrap();
+        assert_eq!(lifted.speed_cap, 100);
+    }
+
+    #[test]
+    fn other_fields_need_no_instructor() {
+        let capped = DriveConfig {
+            speed_cap: 40,
+            ..DriveConfig::DEFAULT
+        };
+        let config = update(r#"{"max_speed":60}"#).apply(capped, false).unwrap();
+        assert_eq!(config.max_speed, 60);
+        assert_eq!(config.speed_cap, 40);
+    }
+}
@@ -0,0 +1,217 @@
+//! Crash reports: why the rover last reset, with the panic message and
+//! backtrace if it panicked.
+//!
+//! The panic handler (replacing the one in `esp-backtrace`) prints the panic
+//! as before, saves it in RTC fast memory, which survives resets other than
+//! power-on, and resets the board. The watchdog saves the rover's last state
+//! the same way (see `watchdog.rs`). At the next boot [`check`] turns that and
+//! the reset reason into a [`CrashReport`], served at `GET /crash` and sent to
+//! each controller that connects.
+
+use core::cell::RefCell;
+use core::fmt::Write as _;
+use core::str::FromStr;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use esp_hal::macros::ram;
+use esp_hal::rtc_cntl::{get_reset_reason, SocResetReason};
+use esp_hal::Cpu;
+use serde::Serialize;
+
+use crate::{metrics, telemetry};
+
+const RECORD_MAGIC: u32 = 0x4352_5348; // "CRSH"
+const MAX_MESSAGE_LENGTH: usize = 128;
+const MAX_BACKTRACE: usize = 10;
+
+/// Panic details kept in RTC memory across the reset.
+#[derive(Clone, Copy)]
+struct PanicRecord {
+    magic: u32,
+    len: u32,
+    message: [u8; MAX_MESSAGE_LENGTH],
+    backtrace: [u32; MAX_BACKTRACE],
+    crc: u32,
+}
+
+impl PanicRecord {
+    fn crc(&self) -> u32 {
+        let mut crc = esp_hal::rom::crc::crc32_le(!0, &self.len.to_le_bytes());
+        crc = esp_hal::rom::crc::crc32_le(crc, &self.message);
+        for address in self.backtrace {
+            crc = esp_hal::rom::crc::crc32_le(crc, &address.to_le_bytes());
+        }
+        crc
+    }
+
+    fn message(&self) -> Option<heapless::String<MAX_MESSAGE_LENGTH>> {
+        let message = core::str::from_utf8(&self.message[..self.len as usize]).ok()?;
+        heapless::String::from_str(message).ok()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == RECORD_MAGIC
+            && self.len as usize <= MAX_MESSAGE_LENGTH
+            && self.crc == self.crc()
+    }
+}
+
+#[ram(rtc_fast, persistent)]
+static mut PANIC_RECORD: PanicRecord = PanicRecord {
+    magic: 0,
+    len: 0,
+    message: [0; MAX_MESSAGE_LENGTH],
+    backtrace: [0; MAX_BACKTRACE],
+    crc: 0,
+};
+
+/// Formats into a fixed buffer, cutting off what doesn't fit (at a character
+/// boundary, so the result stays valid UTF-8).
+struct Truncating<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl core::fmt::Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut n = s.len().min(self.buffer.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buffer[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Saves `message` (and the current backtrace) for the next boot.
+fn save(message: core::fmt::Arguments, backtrace: bool) {
+    let mut record = PanicRecord {
+        magic: RECORD_MAGIC,
+        len: 0,
+        message: [0; MAX_MESSAGE_LENGTH],
+        backtrace: [0; MAX_BACKTRACE],
+        crc: 0,
+    };
+    let mut writer = Truncating {
+        buffer: &mut record.message,
+        len: 0,
+    };
+    let _ = writer.write_fmt(message);
+    record.len = writer.len as u32;
+    if backtrace {
+        for (slot, address) in record
+            .backtrace
+            .iter_mut()
+            .zip(esp_backtrace::arch::backtrace().into_iter().flatten())
+        {
+            esp_println::println!("0x{:x}", address);
+            *slot = address as u32;
+        }
+    }
+    record.crc = record.crc();
+    unsafe { PANIC_RECORD = record };
+}
+
+/// Saves what the rover was doing when the watchdog fired, the reset follows
+/// shortly.
+pub fn record_watchdog(message: core::fmt::Arguments) {
+    save(message, false);
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    esp_println::println!("\n\n!! {}\n\nBacktrace:\n", info);
+    save(format_args!("{}", info), true);
+    esp_hal::reset::software_reset();
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashReport {
+    /// `brownout`, `watchdog`, `panic` or `software`.
+    pub reason: &'static str,
+    pub message: Option<heapless::String<MAX_MESSAGE_LENGTH>>,
+    pub backtrace: heapless::Vec<u32, MAX_BACKTRACE>,
+}
+
+static REPORT: Mutex<CriticalSectionRawMutex, RefCell<Option<CrashReport>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Why the board reset, `power_on` and `deep_sleep` are normal.
+fn reset_reason() -> &'static str {
+    match get_reset_reason(Cpu::ProCpu) {
+        Some(SocResetReason::ChipPowerOn) => "power_on",
+        Some(SocResetReason::CoreDeepSleep) => "deep_sleep",
+        Some(SocResetReason::SysBrownOut) => "brownout",
+        Some(
+            SocResetReason::CoreMwdt0
+            | SocResetReason::CoreMwdt1
+            | SocResetReason::CoreRtcWdt
+            | SocResetReason::Cpu0Mwdt0
+            | SocResetReason::Cpu0RtcWdt
+            | SocResetReason::SysRtcWdt,
+        ) => "watchdog",
+        Some(SocResetReason::CoreSw | SocResetReason::Cpu0Sw) => "software",
+        _ => "other",
+    }
+}
+
+/// Builds the crash report for this boot, if the last reset wasn't a normal
+/// one, and clears the saved panic.
+pub fn check() {
+    let reason = reset_reason();
+    telemetry::update(|t| t.reset_reason = reason);
+
+    let record = unsafe { PANIC_RECORD };
+    unsafe { PANIC_RECORD.magic = 0 };
+    let report = match reason {
+        // A panic resets through software, so only trust a saved panic then.
+        "software" if record.is_valid() => CrashReport {
+            reason: "panic",
+            message: record.message(),
+            backtrace: record
+                .backtrace
+                .iter()
+                .copied()
+                .filter(|&address| address != 0)
+                .collect(),
+        },
+        "watchdog" => CrashReport {
+            reason,
+            // Saved by the watchdog interrupt, if it got that far.
+            message: if record.is_valid() {
+                record.message()
+            } else {
+                None
+            },
+            backtrace: heapless::Vec::new(),
+        },
+        "brownout" => CrashReport {
+            reason,
+            message: None,
+            backtrace: heapless::Vec::new(),
+        },
+        _ => return,
+    };
+    log::warn!("Last reset was a crash: {:?}", report);
+    if report.reason == "watchdog" {
+        metrics::HARDWARE_WATCHDOG_TRIPS.inc();
+    }
+    telemetry::update(|t| t.reset_reason = report.reason);
+    REPORT.lock(|current| *current.borrow_mut() = Some(report));
+}
+
+/// Serializes the crash report into `buffer`, `None` if there isn't one.
+pub fn to_json(buffer: &mut [u8]) -> Option<&[u8]> {
+    let report = REPORT.lock(|report| report.borrow().clone())?;
+    let len = serde_json_core::to_slice(&Message { crash: &report }, buffer).ok()?;
+    Some(&buffer[..len])
+}
+
+/// The report is wrapped so the client can tell it from telemetry.
+#[derive(Serialize)]
+struct Message<'a> {
+    crash: &'a CrashReport,
+}
@@ -0,0 +1,38 @@
+//! Randomness from the hardware RNG for the rest of the firmware: the network
+//! stack seed (ephemeral ports and TCP sequence numbers) and random IDs.
+//!
+//! The ESP32's RNG only mixes in real noise while the radio is running, so
+//! seeds and IDs should be drawn after `esp_wifi::init`.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use esp_hal::rng::Rng;
+
+static RNG: Mutex<CriticalSectionRawMutex, RefCell<Option<Rng>>> = Mutex::new(RefCell::new(None));
+
+pub fn init(rng: Rng) {
+    RNG.lock(|current| *current.borrow_mut() = Some(rng));
+}
+
+/// Fills `buffer` with random bytes.
+pub fn fill(buffer: &mut [u8]) {
+    RNG.lock(|rng| {
+        rng.borrow_mut()
+            .as_mut()
+            .expect("entropy used before init")
+            .read(buffer)
+    });
+}
+
+pub fn u32() -> u32 {
+    let mut bytes = [0; 4];
+    fill(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,99 @@
+//! ESP-NOW transport for driving without an access point, through the USB
+//! dongle in `wifi_tank_dongle`.
+//!
+//! Frames start with [`FRAME_MAGIC`] and a kind byte: [`FRAME_COMMAND`]
+//! carries the same single command byte as the UDP path, [`FRAME_ACCESSORY`]
+//! the text of an accessory message (see `accessories.rs`) and
+//! [`FRAME_TELEMETRY`] the driving telemetry JSON (the full telemetry doesn't
+//! fit in a frame) we send back to the last dongle that sent a command.
+//! ESP-NOW only works while both radios are on the same channel, without an
+//! access point the rover stays on channel 1.
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Ticker};
+use esp_wifi::esp_now::{EspNow, PeerInfo};
+
+use crate::accessories::{self, AccessoryCommand};
+use crate::control::{self, ControlRequest, DriveCommand};
+use crate::{metrics, telemetry};
+
+pub const FRAME_MAGIC: [u8; 2] = *b"WT";
+pub const FRAME_COMMAND: u8 = b'C';
+pub const FRAME_ACCESSORY: u8 = b'X';
+pub const FRAME_TELEMETRY: u8 = b'T';
+const MAX_FRAME_SIZE: usize = 250;
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+#[embassy_executor::task]
+pub async fn espnow_task(esp_now: EspNow<'static>) {
+    let (manager, mut sender, mut receiver) = esp_now.split();
+    let mut dongle: Option<[u8; 6]> = None;
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    let mut ticker = Ticker::every(TELEMETRY_INTERVAL);
+    log::info!("ESP-NOW listening");
+
+    loop {
+        match select(receiver.receive_async(), ticker.next()).await {
+            Either::First(received) => {
+                metrics::ESPNOW_PACKETS.inc();
+                let byte = match received.data().strip_prefix(&FRAME_MAGIC) {
+                    Some(&[FRAME_COMMAND, byte]) => byte,
+                    Some([FRAME_ACCESSORY, text @ ..]) => {
+                        match AccessoryCommand::parse(text) {
+                            Some(command) => {
+                                if !accessories::request(command) {
+                                    log::warn!("Accessories busy, dropping {:?}", command);
+                                }
+                            }
+                            None => {
+                                log::info!("Unknown ESP-NOW accessory command {:?}", text);
+                                metrics::PARSE_ERRORS.inc();
+                            }
+                        }
+                        continue;
+                    }
+                    _ => {
+                        metrics::PARSE_ERRORS.inc();
+                        continue;
+                    }
+                };
+                let Some(command) = DriveCommand::parse(byte) else {
+                    log::info!("Unknown ESP-NOW command {}", byte as char);
+                    metrics::UNKNOWN_COMMANDS.inc();
+                    continue;
+                };
+                let source = received.info.src_address;
+                if dongle != Some(source) {
+                    log::info!("ESP-NOW commands from {:02x?}", source);
+                    if !manager.peer_exists(&source) {
+                        let _ = manager.add_peer(PeerInfo {
+                            peer_address: source,
+                            lmk: None,
+                            channel: None,
+                            encrypt: false,
+                        });
+                    }
+                    dongle = Some(source);
+                }
+                if !control::request(ControlRequest::Drive(command)) {
+                    log::warn!("Drive loop busy, dropping {:?}", command);
+                }
+            }
+            Either::Second(()) => {
+                let Some(dongle) = dongle else {
+                    continue;
+                };
+                frame[..2].copy_from_slice(&FRAME_MAGIC);
+                frame[2] = FRAME_TELEMETRY;
+                // Telemetry larger than one frame is skipped.
+                let Some(json) = telemetry::to_drive_json(&mut frame[3..]) else {
+                    continue;
+                };
+                let len = 3 + json.len();
+                if let Err(e) = sender.send_async(&dongle, &frame[..len]).await {
+                    log::warn!("Failed to send ESP-NOW telemetry: {:?}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Pan/tilt gimbal for the camera, two hobby servos on 50Hz LEDC PWM.
+//!
+//! The driver aims it with `look` accessory messages (see `accessories.rs`)
+//! carrying the right stick's deflection, which turns the gimbal at up to
+//! `gimbal_speed_dps` within the angle limits in [`DriveConfig`]. The stick is
+//! smoothed so the camera doesn't jerk, and the gimbal holds still if the
+//! messages stop. `center` swings it back to straight ahead at the same speed.
+//!
+//! [`DriveConfig`]: crate::settings::DriveConfig
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Ticker};
+use esp_hal::gpio::AnyPin;
+use esp_hal::ledc::{
+    channel::{self, ChannelHW, ChannelIFace},
+    timer::Timer,
+    Ledc, LowSpeed,
+};
+
+use crate::control;
+
+/// One servo frame.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(20);
+const UPDATE_SECS: f32 = 0.02;
+/// The stick counts as released this long after the last `look`.
+const LOOK_TIMEOUT: Duration = Duration::from_millis(250);
+/// How far the turning rate moves toward the stick's every update.
+const SMOOTHING: f32 = 0.25;
+/// Duty resolution of the servo timer, see `main.rs`.
+const DUTY_BITS: u32 = 14;
+const PERIOD_US: f32 = 20_000.0;
+/// Servos covering 180° with 500 to 2500µs pulses.
+const CENTER_US: f32 = 1500.0;
+const US_PER_DEG: f32 = 2000.0 / 180.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aim {
+    /// Stick deflection from -100 to 100, right and up are positive.
+    Look {
+        pan: i8,
+        tilt: i8,
+    },
+    Center,
+}
+
+static AIM: Signal<CriticalSectionRawMutex, Aim> = Signal::new();
+
+/// Aims the gimbal, only the latest aim matters.
+pub fn aim(aim: Aim) {
+    AIM.signal(aim);
+}
+
+/// One servo's PWM output, the duty set directly in timer steps.
+pub type Servo = channel::Channel<'static, LowSpeed, AnyPin>;
+
+/// Sets up a servo on a low speed channel, without pulses until it is
+/// first positioned. `None` if the channel couldn't be configured.
+pub fn servo(
+    ledc: &'static Ledc<'static>,
+    timer: &'static Timer<'static, LowSpeed>,
+    number: channel::Number,
+    pin: AnyPin,
+) -> Option<Servo> {
+    let mut channel = ledc.get_channel(number, pin);
+    channel
+        .configure(channel::config::Config {
+            timer,
+            duty_pct: 0,
+            pin_config: channel::config::PinConfig::PushPull,
+        })
+        .inspect_err(|e| log::error!("Failed to set up a servo: {:?}", e))
+        .ok()?;
+    Some(channel)
+}
+
+/// Positions a servo at `angle` degrees from center.
+fn set_angle(servo: &Servo, angle: f32) {
+    let pulse_us = CENTER_US + angle * US_PER_DEG;
+    servo.set_duty_hw((pulse_us / PERIOD_US * (1 << DUTY_BITS) as f32) as u32);
+}
+
+#[embassy_executor::task]
+pub async fn gimbal_task(pan_servo: Servo, tilt_servo: Servo) {
+    let servos = [&pan_servo, &tilt_servo];
+    // Pan then tilt, in degrees.
+    let mut position = [0.0f32; 2];
+    let mut stick = [0.0f32; 2];
+    let mut rate = [0.0f32; 2];
+    let mut centering = false;
+    let mut last_look = Instant::now();
+    for servo in servos {
+        set_angle(servo, 0.0);
+    }
+    let mut ticker = Ticker::every(UPDATE_INTERVAL);
+    loop {
+        ticker.next().await;
+        match AIM.try_take() {
+            Some(Aim::Look { pan, tilt }) => {
+                stick = [pan, tilt].map(|deflection| deflection as f32 / 100.0);
+                centering = false;
+                last_look = Instant::now();
+            }
+            Some(Aim::Center) => {
+                stick = [0.0; 2];
+                rate = [0.0; 2];
+                centering = true;
+            }
+            None => {}
+        }
+        if last_look.elapsed() > LOOK_TIMEOUT {
+            stick = [0.0; 2];
+        }
+
+        let config = control::config();
+        let step = config.gimbal_speed_dps as f32 * UPDATE_SECS;
+        let limits = [
+            (-(config.pan_limit_deg as f32), config.pan_limit_deg as f32),
+            (-(config.tilt_down_deg as f32), config.tilt_up_deg as f32),
+        ];
+        for (i, servo) in servos.into_iter().enumerate() {
+            let before = position[i];
+            if centering {
+                position[i] -= position[i].clamp(-step, step);
+            } else {
+                rate[i] += (stick[i] - rate[i]) * SMOOTHING;
+                position[i] += rate[i] * step;
+            }
+            // Also catches the limits being narrowed.
+            position[i] = position[i].clamp(limits[i].0, limits[i].1);
+            if position[i] != before {
+                set_angle(servo, position[i]);
+            }
+        }
+        if centering && position == [0.0; 2] {
+            centering = false;
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Minimal HTTP/1.1 server for checking and tuning the rover with curl:
+//!
+//! - `GET /status` returns the telemetry JSON
+//! - `GET /crash` returns the crash report from the last reset, if any
+//! - `GET /metrics` returns the runtime metrics in the Prometheus text format
+//! - `GET /config` returns the drive configuration JSON
+//! - `POST /config` updates (some of) the drive configuration and stores it,
+//!   the speed cap only with the instructor PIN in an `X-Instructor-Pin` header
+//! - `POST /stop` stops the motors
+//!
+//! One connection is handled at a time and closed after the response.
+
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+
+use crate::control::{self, ControlRequest};
+use crate::settings::{Settings, SettingsStore};
+use crate::WifiDriver;
+use crate::{crash, metrics, telemetry};
+
+pub const HTTP_PORT: u16 = 80;
+const MAX_REQUEST_SIZE: usize = 1024;
+const MAX_METRICS_SIZE: usize = 2048;
+const INSTRUCTOR_PIN_HEADER: &str = "x-instructor-pin";
+/// Answering a wrong PIN this slowly makes guessing impractical, as one
+/// connection is handled at a time.
+const WRONG_PIN_DELAY: Duration = Duration::from_secs(1);
+
+struct Response<'a> {
+    status: &'static str,
+    content_type: &'static str,
+    body: &'a [u8],
+}
+
+impl<'a> Response<'a> {
+    fn json(body: &'a [u8]) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn metrics(body: &'a str) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: body.as_bytes(),
+        }
+    }
+
+    fn text(status: &'static str, body: &'a str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: body.as_bytes(),
+        }
+    }
+}
+
+/// Value of a header in a request head, `name` in lower case.
+fn header<'h>(head: &'h str, name: &str) -> Option<&'h str> {
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Whether the request has the instructor PIN, which changing the speed cap
+/// needs. Without a PIN in the settings nobody has it.
+fn is_instructor(head: &str, settings: &Settings) -> bool {
+    match (
+        &settings.instructor_pin,
+        header(head, INSTRUCTOR_PIN_HEADER),
+    ) {
+        (Some(pin), Some(given)) => pin.as_str() == given,
+        _ => false,
+    }
+}
+
+/// Reads the request head and body, returning the method, path, head and
+/// body.
+async fn read_request<'b>(
+    socket: &mut TcpSocket<'_>,
+    buffer: &'b mut [u8; MAX_REQUEST_SIZE],
+) -> Option<(&'b str, &'b str, &'b str, &'b [u8])> {
+    let mut len = 0;
+    let head_end = loop {
+        if let Some(end) = buffer[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if len == buffer.len() {
+            return None;
+        }
+        match socket.read(&mut buffer[len..]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => len += read,
+        }
+    };
+
+    let body_start = head_end + 4;
+    let head = core::str::from_utf8(&buffer[..head_end]).ok()?;
+    let content_length = header(head, "content-length")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    let body_end = body_start.checked_add(content_length)?;
+    if body_end > buffer.len() {
+        return None;
+    }
+    while len < body_end {
+        match socket.read(&mut buffer[len..body_end]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => len += read,
+        }
+    }
+
+    let head = core::str::from_utf8(&buffer[..head_end]).ok()?;
+    let mut request_line = head.split("\r\n").next()?.split(' ');
+    Some((
+        request_line.next()?,
+        request_line.next()?,
+        head,
+        &buffer[body_start..body_end],
+    ))
+}
+
+fn update_config(
+    body: &[u8],
+    instructor: bool,
+    mut settings: Settings,
+    store: &mut SettingsStore,
+) -> Result<(), &'static str> {
+    let config = control::update_config(body, instructor)?;
+    settings.drive = config;
+    store
+        .save(&settings)
+        .map_err(|_| "failed to store configuration")?;
+    log::info!("Drive configuration updated: {:?}", config);
+    Ok(())
+}
+
+async fn write_response(socket: &mut TcpSocket<'_>, response: Response<'_>) {
+    let mut head = heapless::String::<128>::new();
+    let _ = core::fmt::write(
+        &mut head,
+        format_args!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.content_type,
+            response.body.len()
+        ),
+    );
+    if socket.write_all(head.as_bytes()).await.is_err()
+        || socket.write_all(response.body).await.is_err()
+    {
+        log::warn!("Failed to send HTTP response");
+    }
+    let _ = socket.flush().await;
+}
+
+#[embassy_executor::task]
+pub async fn http_task(stack: &'static Stack<WifiDriver>, mut store: SettingsStore) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut request_buffer = [0; MAX_REQUEST_SIZE];
+    let mut json_buffer = [0; 512];
+    let mut metrics_text = heapless::String::<MAX_METRICS_SIZE>::new();
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(5)));
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            log::warn!("HTTP accept failed: {:?}", e);
+            continue;
+        }
+
+        let response = match read_request(&mut socket, &mut request_buffer).await {
+            None => Response::text("400 Bad Request", "malformed request\n"),
+            Some(("GET", "/status", _, _)) => match telemetry::to_json(&mut json_buffer) {
+                Some(json) => Response::json(json),
+                None => Response::text("500 Internal Server Error", "telemetry too large\n"),
+            },
+            Some(("GET", "/crash", _, _)) => match crash::to_json(&mut json_buffer) {
+                Some(json) => Response::json(json),
+                None => Response::text("404 Not Found", "no crash report\n"),
+            },
+            Some(("GET", "/metrics", _, _)) => {
+                metrics_text.clear();
+                match metrics::render(&mut metrics_text) {
+                    Ok(()) => Response::metrics(&metrics_text),
+                    Err(_) => Response::text("500 Internal Server Error", "metrics too large\n"),
+                }
+            }
+            Some(("GET", "/config", _, _)) => {
+                match serde_json_core::to_slice(&control::config(), &mut json_buffer) {
+                    Ok(len) => Response::json(&json_buffer[..len]),
+                    Err(_) => Response::text("500 Internal Server Error", "config too large\n"),
+                }
+            }
+            Some(("POST", "/config", head, body)) => {
+                let settings = store.load();
+                let instructor = is_instructor(head, &settings);
+                if !instructor && header(head, INSTRUCTOR_PIN_HEADER).is_some() {
+                    log::warn!("Wrong instructor PIN over HTTP");
+                    Timer::after(WRONG_PIN_DELAY).await;
+                }
+                match update_config(body, instructor, settings, &mut store) {
+                    Ok(()) => Response::text("200 OK", "ok\n"),
+                    Err(e) => Response::text("400 Bad Request", e),
+                }
+            }
+            Some(("POST", "/stop", _, _)) => {
+                if control::request(ControlRequest::Stop) {
+                    Response::text("200 OK", "stopped\n")
+                } else {
+                    Response::text("503 Service Unavailable", "rover busy, try again\n")
+                }
+            }
+            Some((_, "/status" | "/crash" | "/metrics" | "/config" | "/stop", _, _)) => {
+                Response::text("405 Method Not Allowed", "method not allowed\n")
+            }
+            Some(_) => Response::text("404 Not Found", "not found\n"),
+        };
+        write_response(&mut socket, response).await;
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings;
+
+    const HEAD: &str = "POST /config HTTP/1.1\r\nHost: wifitank\r\nContent-Length: 18";
+
+    fn with_pin(pin: Option<&str>) -> Settings {
+        Settings {
+            instructor_pin: pin.and_then(settings::parse_instructor_pin),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn headers_are_case_insensitive() {
+        assert_eq!(header(HEAD, "content-length"), Some("18"));
+        assert_eq!(header(HEAD, "host"), Some("wifitank"));
+        assert_eq!(header(HEAD, "x-instructor-pin"), None);
+    }
+
+    #[test]
+    fn instructor_needs_the_stored_pin() {
+        let head = "POST /config HTTP/1.1\r\nX-Instructor-Pin: 246810";
+        assert!(is_instructor(head, &with_pin(Some("246810"))));
+        assert!(!is_instructor(head, &with_pin(Some("135790"))));
+        assert!(!is_instructor(HEAD, &with_pin(Some("246810"))));
+        // Without a PIN set the cap can't be changed over HTTP at all.
+        assert!(!is_instructor(head, &with_pin(None)));
+        assert!(!is_instructor(HEAD, &with_pin(None)));
+    }
+}
@@ -0,0 +1,295 @@
+//! I2C IMU (MPU6050 or BMI160, whichever answers) for heading hold and the
+//! tilt cutoff.
+//!
+//! Gyro and accelerometer samples are fused with a complementary filter into
+//! pitch and roll, the heading is the integrated gyro yaw rate, relative to
+//! where the rover pointed at boot. The gyro is calibrated at boot, so the
+//! rover should be still then. Mount the chip flat with its X axis pointing
+//! forward.
+//!
+//! The drive loop reads the heading with [`heading`]. Tipping past
+//! `tilt_limit_deg` (see [`DriveConfig`]) is sent to it as
+//! [`ControlRequest::Tilt`].
+//!
+//! [`DriveConfig`]: crate::settings::DriveConfig
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use esp_hal::i2c::Error;
+use libm::{atan2f, fabsf, roundf, sqrtf};
+
+use crate::control::{self, ControlRequest};
+use crate::{telemetry, I2cBus};
+
+const ADDRESSES: [u8; 2] = [0x68, 0x69];
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+const CALIBRATION_SAMPLES: u16 = 100;
+/// Telemetry is updated every this many samples.
+const TELEMETRY_SAMPLES: u16 = 10;
+/// How much pitch and roll trust the gyro over the accelerometer.
+const GYRO_WEIGHT: f32 = 0.98;
+/// The rover counts as upright again this far below the tilt limit.
+const TILT_HYSTERESIS_DEG: f32 = 5.0;
+
+const MPU6050_WHO_AM_I: u8 = 0x75;
+const MPU6050_ID: u8 = 0x68;
+const MPU6050_PWR_MGMT_1: u8 = 0x6b;
+const MPU6050_CONFIG: u8 = 0x1a;
+const MPU6050_GYRO_CONFIG: u8 = 0x1b;
+const MPU6050_ACCEL_CONFIG: u8 = 0x1c;
+const MPU6050_ACCEL_XOUT_H: u8 = 0x3b;
+
+const BMI160_CHIP_ID: u8 = 0x00;
+const BMI160_ID: u8 = 0xd1;
+const BMI160_DATA_GYR: u8 = 0x0c;
+const BMI160_GYR_RANGE: u8 = 0x43;
+const BMI160_CMD: u8 = 0x7e;
+const BMI160_ACC_NORMAL: u8 = 0x11;
+const BMI160_GYR_NORMAL: u8 = 0x15;
+
+/// Both chips are set to ±500°/s and ±2g.
+const GYRO_LSB_PER_DPS: f32 = 65.5;
+const ACCEL_LSB_PER_G: f32 = 16384.0;
+
+#[derive(Debug, Clone, Copy)]
+enum Chip {
+    Mpu6050,
+    Bmi160,
+}
+
+/// One reading, gyro in °/s and acceleration in g, both as X, Y, Z.
+struct Sample {
+    gyro: [f32; 3],
+    accel: [f32; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Attitude {
+    /// Clockwise from the heading at boot, 0 to 360°.
+    heading: f32,
+    /// Nose up is positive.
+    pitch: f32,
+    /// Right side down is positive.
+    roll: f32,
+}
+
+static ATTITUDE: Mutex<CriticalSectionRawMutex, Cell<Option<Attitude>>> =
+    Mutex::new(Cell::new(None));
+
+/// The current heading, `None` without an IMU.
+pub fn heading() -> Option<f32> {
+    ATTITUDE.lock(|attitude| attitude.get()).map(|a| a.heading)
+}
+
+struct Imu {
+    bus: &'static I2cBus,
+    chip: Chip,
+    address: u8,
+}
+
+impl Imu {
+    /// Looks for either chip at both of their addresses.
+    async fn detect(bus: &'static I2cBus) -> Option<Self> {
+        let mut i2c = bus.lock().await;
+        for address in ADDRESSES {
+            let mut id = [0];
+            let chip = if i2c
+                .write_read(address, &[MPU6050_WHO_AM_I], &mut id)
+                .await
+                .is_ok()
+                && id[0] == MPU6050_ID
+            {
+                Chip::Mpu6050
+            } else if i2c
+                .write_read(address, &[BMI160_CHIP_ID], &mut id)
+                .await
+                .is_ok()
+                && id[0] == BMI160_ID
+            {
+                Chip::Bmi160
+            } else {
+                continue;
+            };
+            return Some(Self { bus, chip, address });
+        }
+        None
+    }
+
+    async fn write_register(&mut self, register: u8, value: u8) -> Result<(), Error> {
+        self.bus
+            .lock()
+            .await
+            .write(self.address, &[register, value])
+            .await
+    }
+
+    async fn configure(&mut self) -> Result<(), Error> {
+        match self.chip {
+            Chip::Mpu6050 => {
+                // Wake up, clocked from the X gyro.
+                self.write_register(MPU6050_PWR_MGMT_1, 0x01).await?;
+                // 44Hz low pass filter.
+                self.write_register(MPU6050_CONFIG, 0x03).await?;
+                self.write_register(MPU6050_GYRO_CONFIG, 0x08).await?;
+                self.write_register(MPU6050_ACCEL_CONFIG, 0x00).await?;
+            }
+            Chip::Bmi160 => {
+                // Both sensors start suspended, ±2g is the default range.
+                self.write_register(BMI160_CMD, BMI160_ACC_NORMAL).await?;
+                Timer::after(Duration::from_millis(5)).await;
+                self.write_register(BMI160_CMD, BMI160_GYR_NORMAL).await?;
+                Timer::after(Duration::from_millis(80)).await;
+                self.write_register(BMI160_GYR_RANGE, 0x02).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<Sample, Error> {
+        let mut raw = [0i16; 6];
+        match self.chip {
+            Chip::Mpu6050 => {
+                // Accelerometer, temperature then gyro, big endian.
+                let mut data = [0u8; 14];
+                self.bus
+                    .lock()
+                    .await
+                    .write_read(self.address, &[MPU6050_ACCEL_XOUT_H], &mut data)
+                    .await?;
+                for (i, value) in raw.iter_mut().enumerate() {
+                    let offset = if i < 3 { i * 2 } else { 8 + (i - 3) * 2 };
+                    *value = i16::from_be_bytes([data[offset], data[offset + 1]]);
+                }
+            }
+            Chip::Bmi160 => {
+                // Gyro then accelerometer, little endian.
+                let mut data = [0u8; 12];
+                self.bus
+                    .lock()
+                    .await
+                    .write_read(self.address, &[BMI160_DATA_GYR], &mut data)
+                    .await?;
+                for (i, value) in raw.iter_mut().enumerate() {
+                    let offset = if i < 3 { 6 + i * 2 } else { (i - 3) * 2 };
+                    *value = i16::from_le_bytes([data[offset], data[offset + 1]]);
+                }
+            }
+        }
+        Ok(Sample {
+            accel: [0, 1, 2].map(|i| raw[i] as f32 / ACCEL_LSB_PER_G),
+            gyro: [3, 4, 5].map(|i| raw[i] as f32 / GYRO_LSB_PER_DPS),
+        })
+    }
+
+    /// Configures the chip and averages the gyro while the rover is still,
+    /// returning the gyro offset.
+    async fn start(&mut self) -> Result<[f32; 3], Error> {
+        self.configure().await?;
+        let mut sum = [0.0; 3];
+        let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+        for _ in 0..CALIBRATION_SAMPLES {
+            let sample = self.read().await?;
+            for (sum, rate) in sum.iter_mut().zip(sample.gyro) {
+                *sum += rate;
+            }
+            ticker.next().await;
+        }
+        Ok(sum.map(|sum| sum / CALIBRATION_SAMPLES as f32))
+    }
+}
+
+/// Pitch and roll from gravity alone.
+fn accel_angles(accel: [f32; 3]) -> (f32, f32) {
+    let [x, y, z] = accel;
+    let pitch = atan2f(x, sqrtf(y * y + z * z)).to_degrees();
+    let roll = atan2f(y, z).to_degrees();
+    (pitch, roll)
+}
+
+#[embassy_executor::task]
+pub async fn imu_task(bus: &'static I2cBus) {
+    let Some(mut imu) = Imu::detect(bus).await else {
+        log::info!("No IMU found, heading hold and tilt cutoff disabled");
+        return;
+    };
+    log::info!("Found {:?} at 0x{:02x}", imu.chip, imu.address);
+    let bias = match imu.start().await {
+        Ok(bias) => bias,
+        Err(e) => {
+            log::error!("Failed to set up the IMU: {:?}", e);
+            return;
+        }
+    };
+
+    let mut attitude: Option<Attitude> = None;
+    let mut tilted = false;
+    let mut reported = false;
+    let mut samples = 0u16;
+    let mut last = Instant::now();
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+    loop {
+        ticker.next().await;
+        let sample = match imu.read().await {
+            Ok(sample) => sample,
+            Err(e) => {
+                log::warn!("Failed to read the IMU: {:?}", e);
+                continue;
+            }
+        };
+        let dt = last.elapsed().as_micros() as f32 / 1_000_000.0;
+        last = Instant::now();
+
+        // Rates around the X (forward), Y (left) and Z (up) axes, which are
+        // right side down, nose down and counter-clockwise.
+        let [roll_rate, pitch_rate, yaw_rate] = [0, 1, 2].map(|i| sample.gyro[i] - bias[i]);
+        let (accel_pitch, accel_roll) = accel_angles(sample.accel);
+        let current = match attitude {
+            None => Attitude {
+                heading: 0.0,
+                pitch: accel_pitch,
+                roll: accel_roll,
+            },
+            Some(previous) => {
+                let mut heading = (previous.heading - yaw_rate * dt) % 360.0;
+                if heading < 0.0 {
+                    heading += 360.0;
+                }
+                Attitude {
+                    heading,
+                    pitch: GYRO_WEIGHT * (previous.pitch - pitch_rate * dt)
+                        + (1.0 - GYRO_WEIGHT) * accel_pitch,
+                    roll: GYRO_WEIGHT * (previous.roll + roll_rate * dt)
+                        + (1.0 - GYRO_WEIGHT) * accel_roll,
+                }
+            }
+        };
+        attitude = Some(current);
+        ATTITUDE.lock(|shared| shared.set(attitude));
+
+        let limit = control::config().tilt_limit_deg as f32;
+        let tilt = fabsf(current.pitch).max(fabsf(current.roll));
+        tilted = if limit == 0.0 {
+            false
+        } else if tilted {
+            tilt > limit - TILT_HYSTERESIS_DEG
+        } else {
+            tilt > limit
+        };
+        // Kept until the drive loop has taken it, like the obstacle changes.
+        if tilted != reported && control::request(ControlRequest::Tilt(tilted)) {
+            reported = tilted;
+        }
+
+        samples += 1;
+        if samples == TELEMETRY_SAMPLES {
+            samples = 0;
+            telemetry::update(|t| {
+                t.heading = Some(roundf(current.heading) as u16 % 360);
+                t.pitch = Some(roundf(current.pitch) as i16);
+                t.roll = Some(roundf(current.roll) as i16);
+            });
+        }
+    }
+}
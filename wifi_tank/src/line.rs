@@ -0,0 +1,140 @@
+//! Line following: an array of IR reflectance sensors across the front of
+//! the rover, read through a PCF8574 I/O expander on the I2C bus shared with
+//! the IMU, and a PID controller steering onto the dark line.
+//!
+//! [`line_task`] keeps the line position up to date for [`position`]. The
+//! drive loop runs a [`LineFollower`] while the driver sends
+//! [`DriveCommand::LineFollow`], which also keeps the command watchdog fed.
+//! Any manual command hands control straight back.
+//!
+//! [`DriveCommand::LineFollow`]: crate::control::DriveCommand::LineFollow
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::settings::DriveConfig;
+use crate::I2cBus;
+
+/// PCF8574 with A0 to A2 tied low.
+const EXPANDER_ADDRESS: u8 = 0x20;
+/// Sensors on P0 (leftmost) to P4 (rightmost), high over the dark line.
+const SENSORS: usize = 5;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Give up following after losing the line for this long.
+const LOST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `None` until the sensors answered, then whether the line is seen.
+static POSITION: Mutex<CriticalSectionRawMutex, Cell<Option<Option<f32>>>> =
+    Mutex::new(Cell::new(None));
+
+/// Where the line is, from -1 (under the leftmost sensor) to 1 (under the
+/// rightmost). The outer `None` means there are no line sensors, the inner
+/// one that the line isn't under any of them.
+pub fn position() -> Option<Option<f32>> {
+    POSITION.lock(|position| position.get())
+}
+
+/// Weighted average of the sensors that see the line.
+fn line_position(bits: u8) -> Option<f32> {
+    let (sum, count) = (0..SENSORS)
+        .filter(|i| bits & (1 << i) != 0)
+        .fold((0, 0), |(sum, count), i| (sum + i as i32, count + 1));
+    if count == 0 {
+        return None;
+    }
+    let middle = (SENSORS - 1) as f32 / 2.0;
+    Some((sum as f32 / count as f32 - middle) / middle)
+}
+
+#[embassy_executor::task]
+pub async fn line_task(bus: &'static I2cBus) {
+    // All pins high makes them (weakly pulled up) inputs.
+    if bus
+        .lock()
+        .await
+        .write(EXPANDER_ADDRESS, &[0xff])
+        .await
+        .is_err()
+    {
+        log::info!("No line sensors found, line following disabled");
+        return;
+    }
+    log::info!("Line sensors found");
+    let mut ticker = Ticker::every(POLL_INTERVAL);
+    loop {
+        ticker.next().await;
+        let mut bits = [0];
+        if let Err(e) = bus.lock().await.read(EXPANDER_ADDRESS, &mut bits).await {
+            log::warn!("Failed to read the line sensors: {:?}", e);
+            continue;
+        }
+        let position = line_position(bits[0]);
+        POSITION.lock(|shared| shared.set(Some(position)));
+    }
+}
+
+/// PID steering state for one run of line following.
+pub struct LineFollower {
+    integral: f32,
+    last_error: f32,
+    last_update: Instant,
+    lost_since: Option<Instant>,
+}
+
+impl LineFollower {
+    pub fn new() -> Self {
+        Self {
+            integral: 0.0,
+            last_error: 0.0,
+            last_update: Instant::now(),
+            lost_since: None,
+        }
+    }
+
+    /// Left and right forward duty for the current line position, `None`
+    /// when following should stop.
+    pub fn update(&mut self, config: &DriveConfig) -> Option<(u8, u8)> {
+        let now = Instant::now();
+        let dt = (now - self.last_update).as_micros() as f32 / 1_000_000.0;
+        self.last_update = now;
+
+        let error = match position()? {
+            Some(position) => {
+                self.lost_since = None;
+                position
+            }
+            // Keep turning the way the line went.
+            None => {
+                let lost_since = *self.lost_since.get_or_insert(now);
+                if now - lost_since > LOST_TIMEOUT {
+                    log::info!("Lost the line");
+                    return None;
+                }
+                if self.last_error < 0.0 {
+                    -1.0
+                } else {
+                    1.0
+                }
+            }
+        };
+
+        // Limited so a long curve doesn't wind it up.
+        self.integral = (self.integral + error * dt).clamp(-1.0, 1.0);
+        let derivative = if dt > 0.0 {
+            (error - self.last_error) / dt
+        } else {
+            0.0
+        };
+        self.last_error = error;
+        let steer =
+            config.line_kp * error + config.line_ki * self.integral + config.line_kd * derivative;
+
+        // The line to the right means turning right, so the left side speeds
+        // up.
+        let speed = config.line_speed as f32;
+        let duty = |duty: f32| duty.clamp(0.0, 100.0) as u8;
+        Some((duty(speed + steer), duty(speed - steer)))
+    }
+}
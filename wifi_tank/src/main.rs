@@ -1,27 +1,75 @@
 #![no_std]
 #![no_main]
 
+mod accessories;
+mod beacon;
+mod ble_provisioning;
+mod cliff;
+mod connection;
+mod console;
+mod control;
+mod crash;
+mod entropy;
+mod espnow;
+mod gimbal;
+mod http;
+mod imu;
+mod line;
+mod mdns;
+mod metrics;
 mod motors;
+mod obstacle;
+mod ota;
+mod remote_log;
+mod settings;
+mod sleep;
+mod status;
+mod telemetry;
+mod udp_control;
+mod watchdog;
 
+use control::{ControlRequest, DriveCommand};
 use core::{mem::MaybeUninit, str::FromStr};
-use embassy_executor::Spawner;
-use embassy_net::udp::{PacketMetadata, UdpSocket};
-use embassy_net::{Config as NetConfig, DhcpConfig, Stack, StackResources};
+use embassy_executor::{SpawnError, Spawner};
+use embassy_futures::select::{select4, Either4};
+use embassy_net::{
+    Config as NetConfig, DhcpConfig, Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Ticker, Timer};
 use esp_backtrace as _;
 use esp_hal::{
-    delay::Delay,
-    gpio::{self},
+    gpio::{Input, Level, Output, Pin, Pull},
+    i2c::I2c,
+    ledc::{self, timer::TimerIFace, HighSpeed, LSGlobalClkSource, Ledc, LowSpeed},
+    peripherals::I2C0,
     prelude::*,
     rng::Rng,
+    rtc_cntl::Rtc,
     timer::timg::TimerGroup,
+    uart::UartRx,
+    Async,
+};
+use esp_wifi::{
+    ble::controller::asynch::BleConnector,
+    esp_now::{self, EspNow},
+    wifi::{self, WifiDevice, WifiStaDevice},
+    EspWifiInitialization,
 };
-use esp_wifi::wifi::{self, AuthMethod, WifiDevice, WifiStaDevice};
+use settings::IpConfig;
 use static_cell::StaticCell;
+use status::Fault;
 
 use esp_alloc as _;
 
 type WifiDriver = WifiDevice<'static, WifiStaDevice>;
-const CLIENT_NAME: &str = "wifitank";
+/// The I2C bus shared by the IMU and the line sensors.
+type I2cBus = Mutex<CriticalSectionRawMutex, I2c<'static, I2C0, Async>>;
+/// The drive loop ramps up the speed, steers (heading hold and line
+/// following) and checks for idling this often.
+const DRIVE_TICK_INTERVAL: Duration = Duration::from_millis(20);
+/// How long to reverse away from an edge.
+const CLIFF_BACK_OFF: Duration = Duration::from_millis(300);
 
 fn init_heap() {
     const HEAP_SIZE: usize = 128 * 1024; // 128KB RAM ought to be enough for anybody
@@ -41,140 +89,396 @@ async fn net_task(stack: &'static Stack<WifiDriver>) -> ! {
     stack.run().await
 }
 
+/// Spawning only fails when a task's pool is exhausted, which is a bug, but
+/// show it as a fault rather than panicking.
+async fn spawned(result: Result<(), SpawnError>) {
+    if let Err(e) = result {
+        log::error!("Failed to spawn task: {:?}", e);
+        status::fatal(Fault::Spawn).await;
+    }
+}
+
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
     let peripherals = esp_hal::init(esp_hal::Config::default());
     let io = esp_hal::gpio::Io::new(peripherals.GPIO, peripherals.IO_MUX);
 
-    let seed = 0x0123_4567_89ab_cdef;
     init_heap();
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     esp_hal_embassy::init(timg0.timer0);
 
-    // Motors
-    let left_v_a_pin = gpio::Output::new(io.pins.gpio13, gpio::Level::High);
-    let left_v_b_pin = gpio::Output::new(io.pins.gpio12, gpio::Level::High);
-    let left_g_a_pin = gpio::Output::new(io.pins.gpio26, gpio::Level::High);
-    let left_g_b_pin = gpio::Output::new(io.pins.gpio25, gpio::Level::High);
-
-    let right_v_a_pin = gpio::Output::new(io.pins.gpio21, gpio::Level::High);
-    let right_v_b_pin = gpio::Output::new(io.pins.gpio19, gpio::Level::High);
-    let right_g_a_pin = gpio::Output::new(io.pins.gpio22, gpio::Level::High);
-    let right_g_b_pin = gpio::Output::new(io.pins.gpio23, gpio::Level::High);
+    remote_log::init();
+    log::info!("Loading");
+    let status_led = Output::new(io.pins.gpio5.degrade(), Level::High);
+    spawned(spawner.spawn(status::led_task(status_led))).await;
 
-    let left_motor =
-        motors::MotorDriver::new(left_v_a_pin, left_v_b_pin, left_g_a_pin, left_g_b_pin);
-    let right_motor =
-        motors::MotorDriver::new(right_v_a_pin, right_v_b_pin, right_g_a_pin, right_g_b_pin);
-    let mut motors = motors::Motors::new(left_motor, right_motor);
+    // Motors, every driver input is a PWM channel so the speed can be limited
+    sleep::release_motor_pins();
+    static LEDC: StaticCell<Ledc> = StaticCell::new();
+    let ledc = LEDC.init(Ledc::new(peripherals.LEDC));
+    // For the accessories on the low speed timers.
+    ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+    let ledc = &*ledc;
+    static MOTOR_TIMER: StaticCell<ledc::timer::Timer<HighSpeed>> = StaticCell::new();
+    let motor_timer = MOTOR_TIMER.init(ledc.get_timer::<HighSpeed>(ledc::timer::Number::Timer0));
+    if let Err(e) = motor_timer.configure(ledc::timer::config::Config {
+        duty: ledc::timer::config::Duty::Duty8Bit,
+        clock_source: ledc::timer::HSClockSource::APBClk,
+        frequency: 1.kHz(),
+    }) {
+        log::error!("Failed to configure the motor PWM timer: {:?}", e);
+        status::fatal(Fault::Pwm).await;
+    }
+    let motor_timer = &*motor_timer;
+    let pwm = |number, pin| motors::pwm_pin(ledc, motor_timer, number, pin);
+    let motors = (|| -> Result<_, ledc::channel::Error> {
+        // Inputs V A, V B, G A and G B of each side.
+        let left_motor = motors::MotorDriver::new(
+            pwm(ledc::channel::Number::Channel0, io.pins.gpio13.degrade())?,
+            pwm(ledc::channel::Number::Channel1, io.pins.gpio12.degrade())?,
+            pwm(ledc::channel::Number::Channel2, io.pins.gpio26.degrade())?,
+            pwm(ledc::channel::Number::Channel3, io.pins.gpio25.degrade())?,
+        );
+        let right_motor = motors::MotorDriver::new(
+            pwm(ledc::channel::Number::Channel4, io.pins.gpio21.degrade())?,
+            pwm(ledc::channel::Number::Channel5, io.pins.gpio19.degrade())?,
+            pwm(ledc::channel::Number::Channel6, io.pins.gpio22.degrade())?,
+            pwm(ledc::channel::Number::Channel7, io.pins.gpio23.degrade())?,
+        );
+        Ok(motors::Motors::new(left_motor, right_motor))
+    })();
+    let mut motors = match motors {
+        Ok(motors) => motors,
+        Err(e) => {
+            log::error!("Failed to set up the motor PWM channels: {:?}", e);
+            status::fatal(Fault::Pwm).await
+        }
+    };
 
-    esp_println::logger::init_logger_from_env();
-    log::info!("Loading");
+    ota::check_boot();
+    crash::check();
+    sleep::record_wake_cause();
     let rng = Rng::new(peripherals.RNG);
-    let delay = Delay::new();
+    entropy::init(rng);
+
+    let mut settings_store = settings::SettingsStore::new();
+    let settings = settings_store.load();
+    let networks = settings.known_networks();
+    if settings.networks.is_empty() {
+        log::info!("No provisioned networks, using build-time credentials");
+    }
+    control::init(settings.drive);
+    motors.configure(settings.drive);
+
+    // Front rangefinder, the echo through a 5V to 3.3V divider.
+    let mut rangefinders = heapless::Vec::new();
+    let _ = rangefinders.push(obstacle::Rangefinder::new(
+        "front",
+        Output::new(io.pins.gpio18.degrade(), Level::Low),
+        Input::new(io.pins.gpio34.degrade(), Pull::None),
+    ));
+    spawned(spawner.spawn(obstacle::obstacle_task(rangefinders))).await;
+
+    // Cliff sensors at the front left and right corners.
+    spawned(spawner.spawn(cliff::cliff_task(
+        Input::new(io.pins.gpio35.degrade(), Pull::None),
+        Input::new(io.pins.gpio36.degrade(), Pull::None),
+    )))
+    .await;
+
+    // Accessories: headlights through a MOSFET on 15, a passive buzzer on 2
+    // and a WS2812 on 0. Any that fail to set up are left out.
+    static HEADLIGHT_TIMER: StaticCell<ledc::timer::Timer<LowSpeed>> = StaticCell::new();
+    let headlight_timer =
+        HEADLIGHT_TIMER.init(ledc.get_timer::<LowSpeed>(ledc::timer::Number::Timer0));
+    let headlights = match headlight_timer.configure(ledc::timer::config::Config {
+        duty: ledc::timer::config::Duty::Duty8Bit,
+        clock_source: ledc::timer::LSClockSource::APBClk,
+        frequency: 1.kHz(),
+    }) {
+        Ok(()) => accessories::headlights(ledc, &*headlight_timer, io.pins.gpio15.degrade()),
+        Err(e) => {
+            log::error!("Failed to configure the headlight timer: {:?}", e);
+            None
+        }
+    };
+    let buzzer = accessories::Buzzer::new(
+        ledc.get_timer::<LowSpeed>(ledc::timer::Number::Timer1),
+        io.pins.gpio2.degrade(),
+    );
+    let rgb_led = accessories::RgbLed::new(peripherals.RMT, io.pins.gpio0.degrade());
+    spawned(spawner.spawn(accessories::accessory_task(headlights, buzzer, rgb_led))).await;
+
+    // Camera gimbal servos, pan on 14 and tilt on 27.
+    static SERVO_TIMER: StaticCell<ledc::timer::Timer<LowSpeed>> = StaticCell::new();
+    let servo_timer = SERVO_TIMER.init(ledc.get_timer::<LowSpeed>(ledc::timer::Number::Timer2));
+    let servos = match servo_timer.configure(ledc::timer::config::Config {
+        duty: ledc::timer::config::Duty::Duty14Bit,
+        clock_source: ledc::timer::LSClockSource::APBClk,
+        frequency: 50.Hz(),
+    }) {
+        Ok(()) => {
+            let servo_timer = &*servo_timer;
+            let servo = |number, pin| gimbal::servo(ledc, servo_timer, number, pin);
+            let pan = servo(ledc::channel::Number::Channel2, io.pins.gpio14.degrade());
+            let tilt = servo(ledc::channel::Number::Channel3, io.pins.gpio27.degrade());
+            pan.zip(tilt)
+        }
+        Err(e) => {
+            log::error!("Failed to configure the servo timer: {:?}", e);
+            None
+        }
+    };
+    match servos {
+        Some((pan, tilt)) => spawned(spawner.spawn(gimbal::gimbal_task(pan, tilt))).await,
+        None => log::warn!("Camera gimbal disabled"),
+    }
 
-    let wifi_ssid = heapless::String::<32>::from_str(env!("WIFI_SSID")).unwrap();
-    let wifi_password = heapless::String::<64>::from_str(env!("WIFI_PASSWORD")).unwrap();
+    // IMU and line sensors, SDA on 32 and SCL on 4.
+    static I2C_BUS: StaticCell<I2cBus> = StaticCell::new();
+    let i2c_bus = &*I2C_BUS.init(Mutex::new(I2c::new_async(
+        peripherals.I2C0,
+        io.pins.gpio32,
+        io.pins.gpio4,
+        400.kHz(),
+    )));
+    spawned(spawner.spawn(imu::imu_task(i2c_bus))).await;
+    spawned(spawner.spawn(line::line_task(i2c_bus))).await;
+
+    let idle_timeout = match settings.idle_timeout_secs() {
+        0 => None,
+        secs => Some(Duration::from_secs(secs.into())),
+    };
+    let wake_interval = match settings.wake_interval_secs {
+        0 => None,
+        secs => Some(core::time::Duration::from_secs(secs.into())),
+    };
+    let mut rtc = Rtc::new(peripherals.LPWR);
+    watchdog::init(&mut rtc);
+    let mut deep_sleep = sleep::DeepSleep::new(rtc, io.pins.gpio33, wake_interval);
+
+    static DEVICE_NAME: StaticCell<heapless::String<32>> = StaticCell::new();
+    let device_name: &'static str = match &settings.device_name {
+        Some(name) => DEVICE_NAME.init(name.clone()),
+        None => settings::DEFAULT_DEVICE_NAME,
+    };
+
+    match UartRx::new_async(peripherals.UART0, io.pins.gpio3) {
+        Ok(console_rx) => {
+            spawned(spawner.spawn(console::console_task(console_rx, settings_store))).await
+        }
+        Err(e) => log::error!("Failed to open the serial console: {:?}", e),
+    }
 
     let timg1 = TimerGroup::new(peripherals.TIMG1);
     log::info!("Pre-wifi init");
-    let wifi_init = esp_wifi::init(
-        esp_wifi::EspWifiInitFor::Wifi,
+    static WIFI_INIT: StaticCell<EspWifiInitialization> = StaticCell::new();
+    let wifi_init = match esp_wifi::init(
+        esp_wifi::EspWifiInitFor::WifiBle,
         timg1.timer0,
         rng,
         peripherals.RADIO_CLK,
-    )
-    .unwrap();
-
-    // TODO: Move this to async task so we can reconnect automatically
-    log::info!("Pre-wifi config: {}", &wifi_ssid);
-    let wifi_config = esp_wifi::wifi::ClientConfiguration {
-        ssid: wifi_ssid,
-        bssid: None,
-        auth_method: AuthMethod::WPA2Personal, // WPA2Personal - AP is technically WPA3 too, but seems board doesn't support this
-        password: wifi_password,
-        channel: None,
+    ) {
+        Ok(wifi_init) => &*WIFI_INIT.init(wifi_init),
+        Err(e) => {
+            log::error!("Failed to initialise the radio: {:?}", e);
+            status::fatal(Fault::WifiInit).await
+        }
     };
+    let session = entropy::u32();
+    log::info!("Session {:08x}", session);
+    telemetry::update(|t| t.session = session);
 
-    log::info!("Post-wifi config");
+    let ble_connector = BleConnector::new(wifi_init, peripherals.BT);
+    spawned(spawner.spawn(ble_provisioning::ble_provisioning_task(
+        ble_connector,
+        settings::SettingsStore::new(),
+        device_name,
+    )))
+    .await;
 
     log::info!("Pre-wifi creation");
-    let (wifi_device, mut wifi_controller): (WifiDevice<WifiStaDevice>, _) =
-        wifi::new_with_config(&wifi_init, peripherals.WIFI, wifi_config).unwrap();
-    log::info!("Pre-wifi start");
-    wifi_controller.start().await.unwrap();
-    delay.delay(1000.millis());
-    log::info!("Pre-wifi connect");
-    for i in 0..20 {
-        if wifi_controller.connect().await.is_ok() {
-            break;
-        }
-        log::info!("Failed to connect, retrying - try {}...", i);
-        delay.delay(1000.millis());
+    // ESP-NOW shares the radio with the station, so the dongle keeps working
+    // when there is no access point.
+    let (wifi, esp_now_token) = esp_now::enable_esp_now_with_wifi(peripherals.WIFI);
+    let (wifi_device, wifi_controller): (WifiDevice<WifiStaDevice>, _) =
+        match wifi::new_with_mode(wifi_init, wifi, WifiStaDevice) {
+            Ok(wifi) => wifi,
+            Err(e) => {
+                log::error!("Failed to create the wifi driver: {:?}", e);
+                status::fatal(Fault::WifiInit).await
+            }
+        };
+    match EspNow::new_with_wifi(wifi_init, esp_now_token) {
+        Ok(esp_now) => spawned(spawner.spawn(espnow::espnow_task(esp_now))).await,
+        Err(e) => log::error!("Failed to start ESP-NOW, only UDP control: {:?}", e),
     }
+    spawned(spawner.spawn(connection::connection_task(wifi_controller, networks))).await;
 
     // embassy-net setup
-    let mut dhcp_config = DhcpConfig::default();
-    dhcp_config.hostname = Some(heapless::String::from_str(CLIENT_NAME).unwrap());
-    let net_config = NetConfig::dhcpv4(dhcp_config);
+    let ip_config = settings.ip_config();
+    log::info!("Hostname {}, IPv4 {}", device_name, ip_config);
+    telemetry::update(|t| {
+        t.hostname = device_name;
+        t.ip_mode = match ip_config {
+            IpConfig::Dhcp => "dhcp",
+            IpConfig::Static(_) => "static",
+        };
+    });
+    let net_config = match ip_config {
+        IpConfig::Dhcp => {
+            let mut dhcp_config = DhcpConfig::default();
+            // Device names are at most 32 bytes (see `settings.rs`), so fit.
+            dhcp_config.hostname = heapless::String::from_str(device_name).ok();
+            NetConfig::dhcpv4(dhcp_config)
+        }
+        IpConfig::Static(static_ip) => NetConfig::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address(static_ip.address), static_ip.prefix_len),
+            gateway: static_ip.gateway.map(Ipv4Address),
+            dns_servers: static_ip.dns.map(Ipv4Address).into_iter().collect(),
+        }),
+    };
 
+    // Random per rover and boot, so rovers sharing a network don't pick the
+    // same ports and sequence numbers. The radio is on by now.
+    let seed = entropy::u64();
     log::info!("Pre-stack assignment");
     static STACK: StaticCell<Stack<WifiDriver>> = StaticCell::new();
-    static RESOURCES: StaticCell<StackResources<4>> = StaticCell::new(); // Increase this if you start getting socket ring errors.
+    static RESOURCES: StaticCell<StackResources<10>> = StaticCell::new(); // Increase this if you start getting socket ring errors.
     let stack = &*STACK.init(Stack::new(
         wifi_device,
         net_config,
-        RESOURCES.init(StackResources::<4>::new()),
+        RESOURCES.init(StackResources::<10>::new()),
         seed,
     ));
     let mac_addr = stack.hardware_address();
     log::info!("Hardware configured. MAC Address is {}", mac_addr);
 
-    spawner.spawn(net_task(stack)).unwrap();
+    spawned(spawner.spawn(net_task(stack))).await;
 
-    stack.wait_config_up().await;
-
-    match stack.config_v4() {
-        Some(a) => log::info!("IP Address appears to be: {}", a.address),
-        None => core::panic!("DHCP completed but no IP address was assigned!"),
-    }
-
-    let mut udp_rx_meta = [PacketMetadata::EMPTY; 16];
-    let mut udp_rx_buffer = [0; 1024];
-    let mut udp_tx_meta = [PacketMetadata::EMPTY; 16];
-    let mut udp_tx_buffer = [0; 1024];
-    let mut msg_buffer = [0; 128];
-
-    let mut udp_socket = UdpSocket::new(
+    spawned(spawner.spawn(udp_control::udp_control_task(stack))).await;
+    spawned(spawner.spawn(remote_log::remote_log_task(stack, device_name))).await;
+    spawned(spawner.spawn(ota::ota_task(stack))).await;
+    spawned(spawner.spawn(http::http_task(stack, settings::SettingsStore::new()))).await;
+    spawned(spawner.spawn(mdns::mdns_task(stack, device_name))).await;
+    spawned(spawner.spawn(beacon::beacon_task(
         stack,
-        &mut udp_rx_meta,
-        &mut udp_rx_buffer,
-        &mut udp_tx_meta,
-        &mut udp_tx_buffer,
-    );
-
-    udp_socket.bind(8080).unwrap();
+        device_name,
+        settings.partner.clone(),
+    )))
+    .await;
 
+    // Drive commands from every transport end up here, this loop owns the
+    // motors.
+    let mut last_command = Instant::now();
+    let mut watchdog_deadline = None;
+    let mut ticker = Ticker::every(DRIVE_TICK_INTERVAL);
+    let mut line_follower: Option<line::LineFollower> = None;
+    // After losing the line, wait for the driver to let go of line following
+    // before starting again.
+    let mut line_lost = false;
+    watchdog::start();
+    // Everything is set up, so an update that got this far is good. Not
+    // waiting for the network, which may never come (ESP-NOW only, no
+    // access point in range) and says nothing about the firmware.
+    ota::mark_valid();
     loop {
-        let (rx_size, from_addr) = udp_socket.recv_from(&mut msg_buffer).await.unwrap();
-        if rx_size == 0 {
-            log::info!("Received empty message from {}", from_addr);
-            continue;
-        }
-        let response = msg_buffer[rx_size - 1] as char;
-        match response {
-            'F' => motors.forward(),
-            'B' => motors.backward(),
-            'L' => motors.left(),
-            'R' => motors.right(),
-            'N' => motors.stop(),
-            'Q' => {
-                // TODO: Deep sleep here?
+        // The ticker wakes the loop often enough to keep this fed.
+        watchdog::feed();
+        // Stops the motors if the controller goes quiet while driving.
+        let watchdog = async {
+            match watchdog_deadline {
+                Some(deadline) => Timer::at(deadline).await,
+                None => core::future::pending().await,
+            }
+        };
+        // Listed first, so a cliff wins over anything else that is ready.
+        let event = select4(
+            control::next_cliff(),
+            control::next_request(),
+            watchdog,
+            ticker.next(),
+        )
+        .await;
+        let started = Instant::now();
+        match event {
+            Either4::First(floor_missing) => {
+                if motors.set_floor_missing(floor_missing) {
+                    line_follower = None;
+                    Timer::after(CLIFF_BACK_OFF).await;
+                    motors.brake();
+                }
+            }
+            Either4::Second(ControlRequest::Drive(command)) => {
+                last_command = Instant::now();
+                watchdog_deadline = match control::config().watchdog_ms {
+                    0 => None,
+                    ms => Some(last_command + Duration::from_millis(ms.into())),
+                };
+                // Any manual command takes over from line following.
+                if !matches!(command, DriveCommand::LineFollow | DriveCommand::Profile(_)) {
+                    line_lost = false;
+                    if line_follower.take().is_some() {
+                        log::info!("Manual control, stopped following the line");
+                    }
+                }
+                match command {
+                    DriveCommand::Forward => motors.forward(),
+                    DriveCommand::Backward => motors.backward(),
+                    DriveCommand::Left => motors.left(),
+                    DriveCommand::Right => motors.right(),
+                    DriveCommand::Stop => motors.stop(),
+                    DriveCommand::Quit => deep_sleep.enter(&mut motors).await,
+                    DriveCommand::Profile(profile) => motors.set_profile(profile),
+                    DriveCommand::LineFollow => {
+                        if line_follower.is_none() && !line_lost {
+                            if line::position().is_some() {
+                                log::info!("Following the line");
+                                line_follower = Some(line::LineFollower::new());
+                            } else {
+                                // Already logged at boot, the client keeps
+                                // asking.
+                                log::debug!("No line sensors, can't follow a line");
+                            }
+                        }
+                    }
+                }
+            }
+            Either4::Second(ControlRequest::Stop) => {
+                line_follower = None;
                 motors.stop();
-                break;
             }
-            _ => log::info!("Unknown command {}", response),
+            Either4::Second(ControlRequest::Configure(config)) => motors.configure(config),
+            Either4::Second(ControlRequest::Obstacle(obstacle)) => motors.set_obstacle(obstacle),
+            Either4::Second(ControlRequest::Tilt(tilted)) => motors.set_tilted(tilted),
+            Either4::Third(()) => {
+                if motors.is_moving() {
+                    log::warn!("No commands from the controller, stopping motors");
+                    metrics::COMMAND_WATCHDOG_TRIPS.inc();
+                    motors.stop();
+                }
+                line_follower = None;
+                watchdog_deadline = None;
+            }
+            Either4::Fourth(()) => {
+                if let Some(follower) = &mut line_follower {
+                    match follower.update(&control::config()) {
+                        Some((left, right)) => motors.follow(left, right),
+                        None => {
+                            line_follower = None;
+                            line_lost = true;
+                            motors.stop();
+                        }
+                    }
+                }
+                motors.ramp_up(DRIVE_TICK_INTERVAL.as_millis() as u32);
+                motors.hold_heading(imu::heading());
+                if idle_timeout.is_some_and(|timeout| last_command.elapsed() > timeout) {
+                    log::info!("No commands received for a while");
+                    deep_sleep.enter(&mut motors).await;
+                }
+            }
         }
-        // TODO: Add timeout to stop motors if nothing received
+        metrics::LOOP_LATENCY.observe(started.elapsed());
     }
 }
@@ -0,0 +1,293 @@
+//! Minimal mDNS responder: answers for `<name>.local` and advertises the
+//! `_wifitank._udp` control port and `_http._tcp` server with DNS-SD.
+//!
+//! Every matching question gets the full set of records for that name,
+//! which is all the client and common browsers (avahi, mdns-sd) need.
+
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{Duration, Timer};
+
+use crate::WifiDriver;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const TTL: u32 = 120;
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+/// Service type, port and TXT record for every advertised service.
+const SERVICES: [(&str, u16, &str); 2] = [
+    (
+        "_wifitank._udp.local",
+        crate::udp_control::CONTROL_PORT,
+        "board=rover",
+    ),
+    ("_http._tcp.local", crate::http::HTTP_PORT, "board=rover"),
+];
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Set on records only we answer for, so caches replace older copies.
+const CACHE_FLUSH: u16 = 0x8000;
+
+type Name = heapless::String<128>;
+
+/// Reads a (possibly compressed) name starting at `pos`, returning the
+/// position after it.
+fn read_name(packet: &[u8], mut pos: usize, name: &mut Name) -> Option<usize> {
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let pointer = ((len & 0x3f) << 8) | *packet.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            jumps += 1;
+            if jumps > 8 {
+                return None;
+            }
+            pos = pointer;
+            continue;
+        }
+        if len == 0 {
+            return Some(end.unwrap_or(pos + 1));
+        }
+        let label = core::str::from_utf8(packet.get(pos + 1..pos + 1 + len)?).ok()?;
+        if !name.is_empty() {
+            name.push('.').ok()?;
+        }
+        name.push_str(label).ok()?;
+        pos += 1 + len;
+    }
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn name(&mut self, labels: &[&str]) -> Option<()> {
+        for label in labels.iter().flat_map(|part| part.split('.')) {
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    /// Writes a record header, then `rdata` with its length filled in after.
+    fn record(
+        &mut self,
+        name: &[&str],
+        kind: u16,
+        class: u16,
+        rdata: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.u16(kind)?;
+        self.u16(class)?;
+        self.bytes(&TTL.to_be_bytes())?;
+        let length_at = self.len;
+        self.u16(0)?;
+        rdata(self)?;
+        let rdata_len = (self.len - length_at - 2) as u16;
+        self.buffer[length_at..length_at + 2].copy_from_slice(&rdata_len.to_be_bytes());
+        Some(())
+    }
+}
+
+/// What a question asked about.
+#[derive(Clone, Copy, PartialEq)]
+enum Subject {
+    Host,
+    ServiceTypes,
+    Service(usize),
+}
+
+struct Responder {
+    device_name: &'static str,
+    stack: &'static Stack<WifiDriver>,
+}
+
+impl Responder {
+    fn subject(&self, name: &str) -> Option<Subject> {
+        let host = name.strip_suffix(".local")?;
+        if host.eq_ignore_ascii_case(self.device_name) {
+            return Some(Subject::Host);
+        }
+        if name.eq_ignore_ascii_case(SERVICE_TYPES) {
+            return Some(Subject::ServiceTypes);
+        }
+        SERVICES
+            .iter()
+            .position(|(service, _, _)| {
+                name.eq_ignore_ascii_case(service)
+                    || name.split_once('.').is_some_and(|(instance, rest)| {
+                        instance.eq_ignore_ascii_case(self.device_name)
+                            && rest.eq_ignore_ascii_case(service)
+                    })
+            })
+            .map(Subject::Service)
+    }
+
+    /// Writes the records for `subject`, returning how many were written.
+    fn write_records(&self, writer: &mut Writer, subject: Subject) -> Option<u16> {
+        let host = [self.device_name, "local"];
+        match subject {
+            Subject::Host => {
+                // Looked up every time, DHCP renewals and roaming change it.
+                let Some(config) = self.stack.config_v4() else {
+                    return Some(0);
+                };
+                writer.record(&host, TYPE_A, CLASS_IN | CACHE_FLUSH, |w| {
+                    w.bytes(config.address.address().as_bytes())
+                })?;
+                Some(1)
+            }
+            Subject::ServiceTypes => {
+                for (service, _, _) in SERVICES {
+                    writer.record(&[SERVICE_TYPES], TYPE_PTR, CLASS_IN, |w| w.name(&[service]))?;
+                }
+                Some(SERVICES.len() as u16)
+            }
+            Subject::Service(index) => {
+                let (service, port, txt) = SERVICES[index];
+                let instance = [self.device_name, service];
+                writer.record(&[service], TYPE_PTR, CLASS_IN, |w| w.name(&instance))?;
+                writer.record(&instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, |w| {
+                    w.u16(0)?; // priority
+                    w.u16(0)?; // weight
+                    w.u16(port)?;
+                    w.name(&host)
+                })?;
+                writer.record(&instance, TYPE_TXT, CLASS_IN | CACHE_FLUSH, |w| {
+                    w.bytes(&[txt.len() as u8])?;
+                    w.bytes(txt.as_bytes())
+                })?;
+                Some(3 + self.write_records(writer, Subject::Host)?)
+            }
+        }
+    }
+
+    /// Builds a response answering `subjects` into `buffer`.
+    fn response<'b>(
+        &self,
+        id: u16,
+        subjects: &[Subject],
+        buffer: &'b mut [u8],
+    ) -> Option<&'b [u8]> {
+        let mut writer = Writer { buffer, len: 0 };
+        writer.u16(id)?;
+        writer.u16(0x8400)?; // response, authoritative
+        writer.bytes(&[0; 8])?;
+        let mut answers = 0;
+        for subject in subjects {
+            answers += self.write_records(&mut writer, *subject)?;
+        }
+        let Writer { buffer, len } = writer;
+        buffer[6..8].copy_from_slice(&answers.to_be_bytes());
+        Some(&buffer[..len])
+    }
+
+    /// Subjects of the questions in a query we should answer.
+    fn parse_query(&self, packet: &[u8]) -> heapless::Vec<Subject, 4> {
+        let mut subjects = heapless::Vec::new();
+        if packet.len() < 12 || packet[2] & 0x80 != 0 {
+            return subjects;
+        }
+        let questions = u16::from_be_bytes([packet[4], packet[5]]);
+        let mut pos = 12;
+        for _ in 0..questions {
+            let mut name = Name::new();
+            let Some(end) = read_name(packet, pos, &mut name) else {
+                break;
+            };
+            pos = end + 4; // type and class
+            if let Some(subject) = self.subject(&name) {
+                if !subjects.contains(&subject) {
+                    let _ = subjects.push(subject);
+                }
+            }
+        }
+        subjects
+    }
+}
+
+#[embassy_executor::task]
+pub async fn mdns_task(stack: &'static Stack<WifiDriver>, device_name: &'static str) {
+    stack.wait_config_up().await;
+    if let Err(e) = stack.join_multicast_group(MDNS_GROUP).await {
+        log::warn!("Failed to join the mDNS multicast group: {:?}", e);
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut packet = [0; 512];
+    let mut response = [0; 512];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        log::error!("Failed to bind the mDNS port, not advertising: {:?}", e);
+        return;
+    }
+
+    let responder = Responder { device_name, stack };
+    let group = IpEndpoint::new(MDNS_GROUP.into(), MDNS_PORT);
+    log::info!("Advertising {}.local over mDNS", device_name);
+
+    // Announce ourselves so browsers pick us up without asking.
+    let everything = [Subject::Service(0), Subject::Service(1)];
+    for _ in 0..2 {
+        if let Some(announcement) = responder.response(0, &everything, &mut response) {
+            let _ = socket.send_to(announcement, group).await;
+        }
+        Timer::after(Duration::from_secs(1)).await;
+    }
+
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        let subjects = responder.parse_query(&packet[..len]);
+        if subjects.is_empty() {
+            continue;
+        }
+        // Queries from a port other than 5353 are one-shot (legacy) queries
+        // expecting a direct reply with the same ID.
+        let (id, destination) = if from.port == MDNS_PORT {
+            (0, group)
+        } else {
+            (u16::from_be_bytes([packet[0], packet[1]]), from)
+        };
+        match responder.response(id, &subjects, &mut response) {
+            Some(reply) => {
+                if let Err(e) = socket.send_to(reply, destination).await {
+                    log::warn!("Failed to send mDNS response: {:?}", e);
+                }
+            }
+            None => log::warn!("mDNS response too large"),
+        }
+    }
+}
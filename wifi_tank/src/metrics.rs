@@ -0,0 +1,223 @@
+//! Runtime metrics, served in the Prometheus text format at `GET /metrics`
+//! (see `http.rs`) for monitoring to scrape.
+//!
+//! Metrics are statics updated from wherever the event happens, interrupts
+//! included, and exported by [`render`] in the order it lists them. Metrics
+//! sharing a name (with different labels) have to be listed together.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Duration;
+
+pub static UDP_PACKETS: Counter = Counter::new(
+    "wifitank_packets_received_total",
+    "transport=\"udp\"",
+    "Control messages received",
+);
+pub static ESPNOW_PACKETS: Counter = Counter::new(
+    "wifitank_packets_received_total",
+    "transport=\"espnow\"",
+    "Control messages received",
+);
+pub static PARSE_ERRORS: Counter = Counter::new(
+    "wifitank_parse_errors_total",
+    "",
+    "Messages that couldn't be decoded: empty or malformed packets, \
+     accessory commands and configuration JSON",
+);
+pub static UNKNOWN_COMMANDS: Counter = Counter::new(
+    "wifitank_unknown_commands_total",
+    "",
+    "Drive command bytes that aren't a known command",
+);
+pub static COMMAND_WATCHDOG_TRIPS: Counter = Counter::new(
+    "wifitank_watchdog_trips_total",
+    "watchdog=\"command\"",
+    "Times a watchdog stopped the rover, the hardware watchdog counts the \
+     reset this boot followed",
+);
+pub static HARDWARE_WATCHDOG_TRIPS: Counter = Counter::new(
+    "wifitank_watchdog_trips_total",
+    "watchdog=\"hardware\"",
+    "Times a watchdog stopped the rover, the hardware watchdog counts the \
+     reset this boot followed",
+);
+pub static LOOP_LATENCY: Histogram<6> = Histogram::new(
+    "wifitank_drive_loop_latency_seconds",
+    "Time the drive loop took to handle each event",
+    [100, 500, 1_000, 5_000, 20_000, 100_000],
+);
+static HEAP_USED: Gauge = Gauge::new("wifitank_heap_used_bytes", "Heap in use");
+static HEAP_FREE: Gauge = Gauge::new("wifitank_heap_free_bytes", "Heap left");
+
+/// Writes every metric in the Prometheus text format.
+pub fn render(out: &mut impl Write) -> fmt::Result {
+    HEAP_USED.set(esp_alloc::HEAP.used() as u32);
+    HEAP_FREE.set(esp_alloc::HEAP.free() as u32);
+
+    let mut previous = "";
+    for counter in [
+        &UDP_PACKETS,
+        &ESPNOW_PACKETS,
+        &PARSE_ERRORS,
+        &UNKNOWN_COMMANDS,
+        &COMMAND_WATCHDOG_TRIPS,
+        &HARDWARE_WATCHDOG_TRIPS,
+    ] {
+        counter.render(out, &mut previous)?;
+    }
+    LOOP_LATENCY.render(out)?;
+    HEAP_USED.render(out)?;
+    HEAP_FREE.render(out)
+}
+
+/// Writes the `HELP` and `TYPE` lines, once per name.
+fn header(
+    out: &mut impl Write,
+    name: &'static str,
+    help: &str,
+    kind: &str,
+    previous: &mut &'static str,
+) -> fmt::Result {
+    if *previous == name {
+        return Ok(());
+    }
+    *previous = name;
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} {}", name, kind)
+}
+
+/// Microseconds written as seconds, Prometheus' unit for durations.
+struct Seconds(u64);
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:06}", self.0 / 1_000_000, self.0 % 1_000_000)
+    }
+}
+
+pub struct Counter {
+    name: &'static str,
+    /// Written between the braces, e.g. `transport="udp"`.
+    labels: &'static str,
+    help: &'static str,
+    value: AtomicU32,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, labels: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            labels,
+            help,
+            value: AtomicU32::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut impl Write, previous: &mut &'static str) -> fmt::Result {
+        header(out, self.name, self.help, "counter", previous)?;
+        let value = self.value.load(Ordering::Relaxed);
+        if self.labels.is_empty() {
+            writeln!(out, "{} {}", self.name, value)
+        } else {
+            writeln!(out, "{}{{{}}} {}", self.name, self.labels, value)
+        }
+    }
+}
+
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU32,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU32::new(0),
+        }
+    }
+
+    pub fn set(&self, value: u32) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut impl Write) -> fmt::Result {
+        header(out, self.name, self.help, "gauge", &mut "")?;
+        writeln!(out, "{} {}", self.name, self.value.load(Ordering::Relaxed))
+    }
+}
+
+struct Buckets<const N: usize> {
+    /// Per bucket, not cumulative, and then everything above the last bound.
+    counts: [u32; N],
+    over: u32,
+    sum_us: u64,
+}
+
+/// Histogram of durations, with bucket bounds in microseconds.
+pub struct Histogram<const N: usize> {
+    name: &'static str,
+    help: &'static str,
+    bounds_us: [u32; N],
+    buckets: Mutex<CriticalSectionRawMutex, RefCell<Buckets<N>>>,
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new(name: &'static str, help: &'static str, bounds_us: [u32; N]) -> Self {
+        Self {
+            name,
+            help,
+            bounds_us,
+            buckets: Mutex::new(RefCell::new(Buckets {
+                counts: [0; N],
+                over: 0,
+                sum_us: 0,
+            })),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let us = duration.as_micros();
+        self.buckets.lock(|buckets| {
+            let mut buckets = buckets.borrow_mut();
+            match self.bounds_us.iter().position(|&bound| us <= bound as u64) {
+                Some(i) => buckets.counts[i] += 1,
+                None => buckets.over += 1,
+            }
+            buckets.sum_us += us;
+        });
+    }
+
+    fn render(&self, out: &mut impl Write) -> fmt::Result {
+        let (counts, over, sum_us) = self.buckets.lock(|buckets| {
+            let buckets = buckets.borrow();
+            (buckets.counts, buckets.over, buckets.sum_us)
+        });
+        header(out, self.name, self.help, "histogram", &mut "")?;
+        let mut total = 0;
+        for (bound, count) in self.bounds_us.iter().zip(counts) {
+            total += count;
+            writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.name,
+                Seconds(*bound as u64),
+                total
+            )?;
+        }
+        total += over;
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", self.name, total)?;
+        writeln!(out, "{}_sum {}", self.name, Seconds(sum_us))?;
+        writeln!(out, "{}_count {}", self.name, total)
+    }
+}
@@ -1,4 +1,103 @@
-use esp_hal::gpio::Output;
+use esp_hal::gpio::AnyPin;
+use esp_hal::ledc::{
+    channel::{self, Channel, ChannelIFace},
+    timer::Timer,
+    HighSpeed, Ledc,
+};
+
+use crate::obstacle::Obstacle;
+use crate::settings::DriveConfig;
+use crate::telemetry;
+
+/// PWM output on one of the motor driver inputs, duty in percent.
+pub type PwmPin<'a> = Channel<'a, HighSpeed, AnyPin>;
+
+/// Sets up a motor driver input as a PWM channel, initially off.
+pub fn pwm_pin<'a>(
+    ledc: &'a Ledc<'a>,
+    timer: &'a Timer<'a, HighSpeed>,
+    number: channel::Number,
+    pin: AnyPin,
+) -> Result<PwmPin<'a>, channel::Error> {
+    let mut channel = ledc.get_channel(number, pin);
+    channel.configure(channel::config::Config {
+        timer,
+        duty_pct: 0,
+        pin_config: channel::config::PinConfig::PushPull,
+    })?;
+    Ok(channel)
+}
+
+/// Heading hold steering per degree off the heading, and at most, in percent
+/// of the slower side's duty (like the trim).
+const HEADING_GAIN: f32 = 2.0;
+const MAX_STEER: f32 = 30.0;
+
+/// Speed limits for drivers of different experience, picked from the
+/// gamepad. The instructor's `speed_cap` (see [`DriveConfig`]) applies on top.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeedProfile {
+    Turtle,
+    Normal,
+    Turbo,
+}
+
+impl SpeedProfile {
+    pub fn name(self) -> &'static str {
+        match self {
+            SpeedProfile::Turtle => "turtle",
+            SpeedProfile::Normal => "normal",
+            SpeedProfile::Turbo => "turbo",
+        }
+    }
+
+    /// Highest duty, in percent, also for line following.
+    fn max_duty(self) -> u8 {
+        match self {
+            SpeedProfile::Turtle => 40,
+            SpeedProfile::Normal => 70,
+            SpeedProfile::Turbo => 100,
+        }
+    }
+
+    /// How fast a movement speeds up, in percent of its duty per second.
+    fn ramp_per_sec(self) -> u32 {
+        match self {
+            SpeedProfile::Turtle => 100,
+            SpeedProfile::Normal => 250,
+            SpeedProfile::Turbo => 1000,
+        }
+    }
+
+    /// Scales `turn_speed`, in percent.
+    fn turn_scale(self) -> u8 {
+        match self {
+            SpeedProfile::Turtle => 50,
+            SpeedProfile::Normal => 75,
+            SpeedProfile::Turbo => 100,
+        }
+    }
+}
+
+/// High speed LEDC channels used by the motors, 0 to 7 (see `main.rs`).
+const MOTOR_CHANNELS: usize = 8;
+
+/// Forces every motor driver input low by switching the PWM outputs to their
+/// idle level, bypassing [`Motors`]. For the watchdog interrupt, when the
+/// loop that owns the motors is stuck.
+pub fn force_stop() {
+    let ledc = unsafe { &*esp_hal::peripherals::LEDC::PTR };
+    for channel in 0..MOTOR_CHANNELS {
+        ledc.hsch(channel)
+            .conf0()
+            .modify(|_, w| w.idle_lv().clear_bit().sig_out_en().clear_bit());
+    }
+}
+
+fn set_duty(pin: &PwmPin, duty: u8) {
+    // Only fails for duties above 100%.
+    let _ = pin.set_duty(duty.min(100));
+}
 
 enum MotorDriverState {
     Stopped,
@@ -7,19 +106,19 @@ enum MotorDriverState {
 }
 
 pub struct MotorDriver<'a> {
-    v_a_pin: Output<'a, esp_hal::gpio::AnyPin>,
-    v_b_pin: Output<'a, esp_hal::gpio::AnyPin>,
-    g_a_pin: Output<'a, esp_hal::gpio::AnyPin>,
-    g_b_pin: Output<'a, esp_hal::gpio::AnyPin>,
+    v_a_pin: PwmPin<'a>,
+    v_b_pin: PwmPin<'a>,
+    g_a_pin: PwmPin<'a>,
+    g_b_pin: PwmPin<'a>,
     state: MotorDriverState,
 }
 
 impl<'a> MotorDriver<'a> {
     pub fn new(
-        v_a_pin: Output<'a, esp_hal::gpio::AnyPin>,
-        v_b_pin: Output<'a, esp_hal::gpio::AnyPin>,
-        g_a_pin: Output<'a, esp_hal::gpio::AnyPin>,
-        g_b_pin: Output<'a, esp_hal::gpio::AnyPin>,
+        v_a_pin: PwmPin<'a>,
+        v_b_pin: PwmPin<'a>,
+        g_a_pin: PwmPin<'a>,
+        g_b_pin: PwmPin<'a>,
     ) -> Self {
         let mut motor_driver = Self {
             v_a_pin,
@@ -32,44 +131,78 @@ impl<'a> MotorDriver<'a> {
         motor_driver
     }
 
-    pub fn forward(&mut self) {
-        self.v_a_pin.set_high();
-        self.g_a_pin.set_low();
-        self.v_b_pin.set_high();
-        self.g_b_pin.set_low();
+    pub fn forward(&mut self, duty: u8) {
+        set_duty(&self.v_a_pin, duty);
+        set_duty(&self.g_a_pin, 0);
+        set_duty(&self.v_b_pin, duty);
+        set_duty(&self.g_b_pin, 0);
         self.state = MotorDriverState::Forward;
     }
 
-    pub fn backward(&mut self) {
-        self.v_a_pin.set_low();
-        self.g_a_pin.set_high();
-        self.v_b_pin.set_low();
-        self.g_b_pin.set_high();
+    pub fn backward(&mut self, duty: u8) {
+        set_duty(&self.v_a_pin, 0);
+        set_duty(&self.g_a_pin, duty);
+        set_duty(&self.v_b_pin, 0);
+        set_duty(&self.g_b_pin, duty);
         self.state = MotorDriverState::Reverse;
     }
 
     pub fn stop(&mut self) {
-        self.v_a_pin.set_low();
-        self.g_a_pin.set_low();
-        self.v_b_pin.set_low();
-        self.g_b_pin.set_low();
+        set_duty(&self.v_a_pin, 0);
+        set_duty(&self.g_a_pin, 0);
+        set_duty(&self.v_b_pin, 0);
+        set_duty(&self.g_b_pin, 0);
         self.state = MotorDriverState::Stopped;
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum MotorsState {
     Stopped,
     Forward,
     Reverse,
     Left,
     Right,
+    /// Following a line, see `line.rs`.
+    Line,
+}
+
+impl MotorsState {
+    fn name(self) -> &'static str {
+        match self {
+            MotorsState::Stopped => "stopped",
+            MotorsState::Forward => "forward",
+            MotorsState::Reverse => "reverse",
+            MotorsState::Left => "left",
+            MotorsState::Right => "right",
+            MotorsState::Line => "line",
+        }
+    }
 }
 
 pub struct Motors<'a> {
     left_motor: MotorDriver<'a>,
     right_motor: MotorDriver<'a>,
     state: MotorsState,
+    config: DriveConfig,
+    obstacle: Obstacle,
+    /// Cut out until the rover is upright again.
+    tilted: bool,
+    /// Heading to hold while driving straight, taken when the movement
+    /// started.
+    heading_target: Option<f32>,
+    /// Heading hold correction, added to the trim.
+    steer: i8,
+    /// The floor is missing under a cliff sensor.
+    floor_missing: bool,
+    /// Forward is refused after losing the floor, until the driver sends
+    /// another command with the floor back.
+    cliff_latched: bool,
+    /// Left and right duty while following a line.
+    line_duties: (u8, u8),
+    profile: SpeedProfile,
+    /// How far the current movement has sped up, in percent of its duty.
+    ramp: u8,
 }
 
 impl<'a> Motors<'a> {
@@ -78,53 +211,274 @@ impl<'a> Motors<'a> {
             left_motor,
             right_motor,
             state: MotorsState::Stopped,
+            config: DriveConfig::DEFAULT,
+            obstacle: Obstacle::Clear,
+            tilted: false,
+            heading_target: None,
+            steer: 0,
+            floor_missing: false,
+            cliff_latched: false,
+            line_duties: (0, 0),
+            profile: SpeedProfile::Normal,
+            ramp: 0,
         };
-        motors.stop();
+        motors.apply();
         motors
     }
 
-    pub fn forward(&mut self) {
+    /// Applies new speed limits and trim, including to the current movement.
+    pub fn configure(&mut self, config: DriveConfig) {
+        self.config = config;
+        self.apply();
+    }
+
+    /// Switches to another speed profile, including for the current
+    /// movement.
+    pub fn set_profile(&mut self, profile: SpeedProfile) {
+        if self.profile == profile {
+            return;
+        }
+        log::info!("Speed profile {}", profile.name());
+        self.profile = profile;
+        self.apply();
+        telemetry::update(|t| t.profile = profile.name());
+    }
+
+    /// Speeds the current movement up by the profile's ramp rate, called
+    /// regularly.
+    pub fn ramp_up(&mut self, elapsed_ms: u32) {
+        if !self.is_moving() || self.ramp == 100 {
+            return;
+        }
+        let step = (self.profile.ramp_per_sec() * elapsed_ms / 1000).max(1);
+        self.ramp = (self.ramp as u32 + step).min(100) as u8;
+        self.apply();
+    }
+
+    /// Slows or refuses forward motion, including the current movement.
+    pub fn set_obstacle(&mut self, obstacle: Obstacle) {
+        if self.obstacle == obstacle {
+            return;
+        }
         if self.state == MotorsState::Forward {
+            match obstacle {
+                Obstacle::Clear => log::info!("Way ahead clear"),
+                Obstacle::Near => log::info!("Obstacle ahead, slowing down"),
+                Obstacle::Blocked => log::warn!("Obstacle ahead, stopping"),
+            }
+        }
+        self.obstacle = obstacle;
+        self.apply();
+        telemetry::update(|t| t.obstacle = obstacle.name());
+    }
+
+    /// Brakes when the floor disappears, backing off and returning true so
+    /// the drive loop can end the back off with [`Motors::brake`].
+    pub fn set_floor_missing(&mut self, missing: bool) -> bool {
+        let lost = missing && !self.floor_missing;
+        self.floor_missing = missing;
+        if lost {
+            log::warn!("Floor lost, backing off");
+            self.cliff_latched = true;
+            self.set_state(MotorsState::Reverse);
+            // Backing off can't wait for the ramp.
+            self.ramp = 100;
+            self.apply();
+        }
+        lost
+    }
+
+    /// Stops without counting as a command from the driver.
+    pub fn brake(&mut self) {
+        self.set_state(MotorsState::Stopped);
+    }
+
+    /// Any command but forward means the driver let go of forward.
+    fn release_cliff(&mut self) {
+        if !self.floor_missing {
+            self.cliff_latched = false;
+        }
+    }
+
+    /// Cuts the motors while the rover is tipped over.
+    pub fn set_tilted(&mut self, tilted: bool) {
+        if self.tilted == tilted {
             return;
         }
-        self.state = MotorsState::Forward;
-        self.left_motor.forward();
-        self.right_motor.forward();
+        if tilted {
+            log::warn!("Rover tipped over, cutting the motors");
+        } else {
+            log::info!("Rover upright again");
+        }
+        self.tilted = tilted;
+        self.apply();
+        telemetry::update(|t| t.tilted = tilted);
     }
 
-    pub fn backward(&mut self) {
-        if self.state == MotorsState::Reverse {
+    /// Steers back to the heading the rover had when it started driving
+    /// straight, called regularly with the IMU heading.
+    pub fn hold_heading(&mut self, heading: Option<f32>) {
+        let straight = matches!(self.state, MotorsState::Forward | MotorsState::Reverse);
+        let (Some(heading), true, true) = (heading, straight, self.config.heading_hold) else {
+            self.heading_target = None;
+            self.set_steer(0);
             return;
+        };
+        let target = *self.heading_target.get_or_insert(heading);
+        let mut error = heading - target;
+        if error > 180.0 {
+            error -= 360.0;
+        } else if error < -180.0 {
+            error += 360.0;
         }
-        self.state = MotorsState::Reverse;
-        self.left_motor.backward();
-        self.right_motor.backward();
+        // Turned clockwise: going forward slow the left side, going
+        // backward the right.
+        let correction = (error * HEADING_GAIN).clamp(-MAX_STEER, MAX_STEER) as i8;
+        self.set_steer(match self.state {
+            MotorsState::Forward => -correction,
+            _ => correction,
+        });
     }
 
-    pub fn stop(&mut self) {
-        if self.state == MotorsState::Stopped {
+    fn set_steer(&mut self, steer: i8) {
+        if self.steer != steer {
+            self.steer = steer;
+            self.apply();
+        }
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.state != MotorsState::Stopped
+    }
+
+    /// Duty for the left and right side after applying the trim and heading
+    /// hold.
+    fn side_duties(&self, duty: u8) -> (u8, u8) {
+        let balance = (self.config.trim as i16 + self.steer as i16).clamp(-100, 100);
+        let trim = balance.unsigned_abs();
+        let trimmed = (duty as u16 * (100 - trim) / 100) as u8;
+        if balance >= 0 {
+            (duty, trimmed)
+        } else {
+            (trimmed, duty)
+        }
+    }
+
+    /// Duty after the speed profile, the instructor's cap and the ramp.
+    fn limit(&self, duty: u8) -> u8 {
+        let duty = duty.min(self.profile.max_duty()).min(self.config.speed_cap);
+        (duty as u16 * self.ramp as u16 / 100) as u8
+    }
+
+    /// `turn_speed` scaled by the speed profile.
+    fn turn_duty(&self) -> u8 {
+        (self.config.turn_speed as u16 * self.profile.turn_scale() as u16 / 100) as u8
+    }
+
+    /// Forward duty after slowing or stopping for an obstacle.
+    fn limit_forward(&self, duty: u8) -> u8 {
+        match self.obstacle {
+            Obstacle::Clear => duty,
+            Obstacle::Near => duty / 2,
+            Obstacle::Blocked => 0,
+        }
+    }
+
+    fn apply(&mut self) {
+        if self.tilted {
+            self.left_motor.stop();
+            self.right_motor.stop();
             return;
         }
-        self.state = MotorsState::Stopped;
-        self.left_motor.stop();
-        self.right_motor.stop();
+        match self.state {
+            MotorsState::Stopped => {
+                self.left_motor.stop();
+                self.right_motor.stop();
+            }
+            MotorsState::Forward => {
+                let duty = self.limit_forward(self.limit(self.config.max_speed));
+                let (left, right) = self.side_duties(duty);
+                self.left_motor.forward(left);
+                self.right_motor.forward(right);
+            }
+            MotorsState::Reverse => {
+                let (left, right) = self.side_duties(self.limit(self.config.max_speed));
+                self.left_motor.backward(left);
+                self.right_motor.backward(right);
+            }
+            MotorsState::Left => {
+                let (left, right) = self.side_duties(self.limit(self.turn_duty()));
+                self.left_motor.backward(left);
+                self.right_motor.forward(right);
+            }
+            MotorsState::Right => {
+                let (left, right) = self.side_duties(self.limit(self.turn_duty()));
+                self.left_motor.forward(left);
+                self.right_motor.backward(right);
+            }
+            MotorsState::Line => {
+                let (left, right) = self.line_duties;
+                self.left_motor
+                    .forward(self.limit_forward(self.limit(left)));
+                self.right_motor
+                    .forward(self.limit_forward(self.limit(right)));
+            }
+        }
     }
 
-    pub fn left(&mut self) {
-        if self.state == MotorsState::Left {
+    fn set_state(&mut self, state: MotorsState) {
+        if self.state == state {
             return;
         }
-        self.state = MotorsState::Left;
-        self.left_motor.backward();
-        self.right_motor.forward();
+        self.state = state;
+        // A new movement holds a new heading and speeds up from a standstill.
+        self.heading_target = None;
+        self.steer = 0;
+        self.ramp = 0;
+        self.apply();
+        telemetry::update(|t| t.motors = state.name());
     }
 
-    pub fn right(&mut self) {
-        if self.state == MotorsState::Right {
+    pub fn forward(&mut self) {
+        if self.cliff_latched {
+            self.set_state(MotorsState::Stopped);
+        } else {
+            self.set_state(MotorsState::Forward);
+        }
+    }
+
+    /// Drives forward with the given left and right duty, for line
+    /// following. Refused like forward after losing the floor.
+    pub fn follow(&mut self, left: u8, right: u8) {
+        if self.cliff_latched {
+            self.set_state(MotorsState::Stopped);
             return;
         }
-        self.state = MotorsState::Right;
-        self.left_motor.forward();
-        self.right_motor.backward();
+        self.line_duties = (left, right);
+        if self.state == MotorsState::Line {
+            self.apply();
+        } else {
+            self.set_state(MotorsState::Line);
+        }
+    }
+
+    pub fn backward(&mut self) {
+        self.release_cliff();
+        self.set_state(MotorsState::Reverse);
+    }
+
+    pub fn stop(&mut self) {
+        self.release_cliff();
+        self.set_state(MotorsState::Stopped);
+    }
+
+    pub fn left(&mut self) {
+        self.release_cliff();
+        self.set_state(MotorsState::Left);
+    }
+
+    pub fn right(&mut self) {
+        self.release_cliff();
+        self.set_state(MotorsState::Right);
     }
 }
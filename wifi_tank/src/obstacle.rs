@@ -0,0 +1,134 @@
+//! HC-SR04 ultrasonic rangefinders looking ahead of the rover.
+//!
+//! The sensors are pinged in turn and the nearest echo decides whether the
+//! way ahead is clear, near (forward is slowed) or blocked (forward is
+//! refused), using the distances in [`DriveConfig`]. Changes go to the drive
+//! loop as [`ControlRequest::Obstacle`], reversing and turning are never
+//! limited. A sensor that doesn't answer reads as nothing in range.
+//!
+//! The sensors run at 5V, so their echo pins need a divider (see `main.rs`
+//! for the pins).
+
+use embassy_time::{with_timeout, Duration, Instant, Ticker};
+use esp_hal::delay::Delay;
+use esp_hal::gpio::{AnyPin, Input, Output};
+use serde::Serialize;
+
+use crate::control::{self, ControlRequest};
+use crate::settings::DriveConfig;
+use crate::telemetry;
+
+pub const MAX_RANGEFINDERS: usize = 2;
+/// Beyond the sensor's range, readings this far are noise.
+pub const MAX_DISTANCE_CM: u16 = 400;
+/// The datasheet asks for at least 60ms between pings, which also lets the
+/// echo of one sensor die down before the next is pinged.
+const PING_INTERVAL: Duration = Duration::from_millis(60);
+/// Longer than the echo of anything in range.
+const ECHO_TIMEOUT: Duration = Duration::from_millis(30);
+/// Sound travels 1cm and back in about 58µs.
+const MICROS_PER_CM: u64 = 58;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Obstacle {
+    Clear,
+    /// Closer than `obstacle_slow_cm`.
+    Near,
+    /// Closer than `obstacle_stop_cm`.
+    Blocked,
+}
+
+impl Obstacle {
+    pub fn name(self) -> &'static str {
+        match self {
+            Obstacle::Clear => "clear",
+            Obstacle::Near => "near",
+            Obstacle::Blocked => "blocked",
+        }
+    }
+
+    fn at(distance_cm: Option<u16>, config: &DriveConfig) -> Self {
+        let within = |limit: u16| distance_cm.is_some_and(|cm| cm < limit);
+        if within(config.obstacle_stop_cm) {
+            Obstacle::Blocked
+        } else if within(config.obstacle_slow_cm) {
+            Obstacle::Near
+        } else {
+            Obstacle::Clear
+        }
+    }
+}
+
+/// One sensor's last reading, for telemetry.
+#[derive(Debug, Clone, Serialize)]
+pub struct Range {
+    pub sensor: &'static str,
+    /// `None` if nothing is in range.
+    pub cm: Option<u16>,
+}
+
+pub struct Rangefinder {
+    name: &'static str,
+    trigger: Output<'static, AnyPin>,
+    echo: Input<'static, AnyPin>,
+}
+
+impl Rangefinder {
+    pub fn new(
+        name: &'static str,
+        trigger: Output<'static, AnyPin>,
+        echo: Input<'static, AnyPin>,
+    ) -> Self {
+        Self {
+            name,
+            trigger,
+            echo,
+        }
+    }
+
+    /// Pings and times the echo, `None` if nothing came back in range.
+    async fn measure(&mut self) -> Option<u16> {
+        self.trigger.set_high();
+        Delay::new().delay_micros(10);
+        self.trigger.set_low();
+
+        with_timeout(ECHO_TIMEOUT, self.echo.wait_for_high())
+            .await
+            .ok()?;
+        let start = Instant::now();
+        with_timeout(ECHO_TIMEOUT, self.echo.wait_for_low())
+            .await
+            .ok()?;
+        let cm = start.elapsed().as_micros() / MICROS_PER_CM;
+        (cm <= MAX_DISTANCE_CM as u64).then_some(cm as u16)
+    }
+}
+
+#[embassy_executor::task]
+pub async fn obstacle_task(mut rangefinders: heapless::Vec<Rangefinder, MAX_RANGEFINDERS>) {
+    if rangefinders.is_empty() {
+        return;
+    }
+    let mut reported = Obstacle::Clear;
+    let mut ticker = Ticker::every(PING_INTERVAL);
+    loop {
+        let mut ranges = heapless::Vec::<Range, MAX_RANGEFINDERS>::new();
+        for rangefinder in rangefinders.iter_mut() {
+            ticker.next().await;
+            let cm = rangefinder.measure().await;
+            let _ = ranges.push(Range {
+                sensor: rangefinder.name,
+                cm,
+            });
+        }
+        let nearest = ranges.iter().filter_map(|range| range.cm).min();
+        telemetry::update(|t| t.ranges = ranges);
+
+        let obstacle = Obstacle::at(nearest, &control::config());
+        // Kept until the drive loop has taken it, so a full queue only
+        // delays the change.
+        if obstacle != reported && control::request(ControlRequest::Obstacle(obstacle)) {
+            reported = obstacle;
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Over-the-air updates into the inactive `ota_0`/`ota_1` app partition.
+//!
+//! The client connects over TCP and sends a header (`WTOTA1`, image size as
+//! little endian u32 and the SHA-256 of the image) followed by the image
+//! itself. The rover answers `OK` or `ERR <reason>` lines, verifies the
+//! written image, switches the boot partition in `otadata` and reboots.
+//!
+//! Rollback is handled here rather than in the bootloader, so it works with
+//! the default espflash bootloader: a freshly written image boots as `NEW`, is
+//! marked `PENDING_VERIFY` straight away and only becomes `VALID` once boot
+//! reaches the drive loop. Booting again while still pending means the new
+//! image never got that far, so we switch back to the previous partition.
+
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_time::Duration;
+use embedded_io_async::{Read, Write};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_storage::FlashStorage;
+use sha2::{Digest, Sha256};
+
+use crate::WifiDriver;
+
+pub const OTA_PORT: u16 = 8081;
+
+const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+const PARTITION_ENTRY_SIZE: usize = 32;
+const PARTITION_MAGIC: [u8; 2] = [0xaa, 0x50];
+const MAX_PARTITIONS: usize = 16;
+
+const SECTOR_SIZE: usize = 4096;
+const OTA_SELECT_ENTRY_SIZE: usize = 32;
+const IMAGE_MAGIC: u8 = 0xe9;
+const HEADER_MAGIC: &[u8; 6] = b"WTOTA1";
+
+const OTA_STATE_NEW: u32 = 0x0;
+const OTA_STATE_PENDING_VERIFY: u32 = 0x1;
+const OTA_STATE_VALID: u32 = 0x2;
+const OTA_STATE_ABORTED: u32 = 0x4;
+
+#[derive(Debug)]
+pub enum OtaError {
+    Flash,
+    NoOtaPartitions,
+    BadHeader,
+    EmptyImage,
+    TooLarge,
+    NotAnImage,
+    Network,
+    HashMismatch,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Partition {
+    offset: u32,
+    size: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OtaLayout {
+    otadata: Partition,
+    slots: [Partition; 2],
+}
+
+/// One of the two `esp_ota_select_entry_t` copies in `otadata`.
+#[derive(Debug, Clone, Copy)]
+struct SelectEntry {
+    seq: u32,
+    state: u32,
+}
+
+impl SelectEntry {
+    fn parse(bytes: &[u8; OTA_SELECT_ENTRY_SIZE]) -> Option<Self> {
+        let seq = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let state = u32::from_le_bytes(bytes[24..28].try_into().unwrap());
+        let crc = u32::from_le_bytes(bytes[28..32].try_into().unwrap());
+        // Sequence numbers start at 1, like the ESP-IDF bootloader we reject
+        // 0 (and erased flash) even with a matching CRC.
+        (seq != 0 && seq != 0xffff_ffff && crc == seq_crc(seq)).then_some(Self { seq, state })
+    }
+
+    fn to_bytes(self) -> [u8; OTA_SELECT_ENTRY_SIZE] {
+        let mut bytes = [0xff; OTA_SELECT_ENTRY_SIZE];
+        bytes[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.state.to_le_bytes());
+        bytes[28..32].copy_from_slice(&seq_crc(self.seq).to_le_bytes());
+        bytes
+    }
+
+    /// Index of the `ota_N` slot this entry boots.
+    fn slot(self) -> usize {
+        ((self.seq - 1) % 2) as usize
+    }
+}
+
+fn seq_crc(seq: u32) -> u32 {
+    esp_hal::rom::crc::crc32_le(!0, &seq.to_le_bytes())
+}
+
+fn read_layout(flash: &mut FlashStorage) -> Result<OtaLayout, OtaError> {
+    let mut otadata = None;
+    let mut slots = [None, None];
+    let mut entry = [0u8; PARTITION_ENTRY_SIZE];
+    for i in 0..MAX_PARTITIONS {
+        let offset = PARTITION_TABLE_OFFSET + (i * PARTITION_ENTRY_SIZE) as u32;
+        flash
+            .read(offset, &mut entry)
+            .map_err(|_| OtaError::Flash)?;
+        if entry[0..2] != PARTITION_MAGIC {
+            break;
+        }
+        let (kind, subtype) = (entry[2], entry[3]);
+        let partition = Partition {
+            offset: u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+            size: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+        };
+        match (kind, subtype) {
+            (0x01, 0x00) => otadata = Some(partition),
+            (0x00, 0x10) => slots[0] = Some(partition),
+            (0x00, 0x11) => slots[1] = Some(partition),
+            _ => {}
+        }
+    }
+    match (otadata, slots) {
+        (Some(otadata), [Some(slot0), Some(slot1)]) => Ok(OtaLayout {
+            otadata,
+            slots: [slot0, slot1],
+        }),
+        _ => Err(OtaError::NoOtaPartitions),
+    }
+}
+
+/// Reads both `otadata` entries, returning the active one (highest sequence
+/// number) and the index of the sector it lives in.
+fn read_active_entry(
+    flash: &mut FlashStorage,
+    layout: &OtaLayout,
+) -> Result<Option<(SelectEntry, usize)>, OtaError> {
+    let mut active: Option<(SelectEntry, usize)> = None;
+    for sector in 0..2 {
+        let mut bytes = [0u8; OTA_SELECT_ENTRY_SIZE];
+        flash
+            .read(
+                layout.otadata.offset + (sector * SECTOR_SIZE) as u32,
+                &mut bytes,
+            )
+            .map_err(|_| OtaError::Flash)?;
+        if let Some(entry) = SelectEntry::parse(&bytes) {
+            if active.map_or(true, |(a, _)| entry.seq > a.seq) {
+                active = Some((entry, sector));
+            }
+        }
+    }
+    Ok(active)
+}
+
+fn write_entry(
+    flash: &mut FlashStorage,
+    layout: &OtaLayout,
+    sector: usize,
+    entry: SelectEntry,
+) -> Result<(), OtaError> {
+    let offset = layout.otadata.offset + (sector * SECTOR_SIZE) as u32;
+    flash
+        .erase(offset, offset + SECTOR_SIZE as u32)
+        .map_err(|_| OtaError::Flash)?;
+    flash
+        .write(offset, &entry.to_bytes())
+        .map_err(|_| OtaError::Flash)
+}
+
+/// Makes `slot` the boot partition by writing a new entry with the next
+/// matching sequence number into the sector not holding the active entry.
+fn select_slot(
+    flash: &mut FlashStorage,
+    layout: &OtaLayout,
+    slot: usize,
+    state: u32,
+) -> Result<(), OtaError> {
+    let (seq, sector) = match read_active_entry(flash, layout)? {
+        Some((active, sector)) => {
+            let mut seq = active.seq + 1;
+            while (seq - 1) % 2 != slot as u32 {
+                seq += 1;
+            }
+            (seq, 1 - sector)
+        }
+        None => (slot as u32 + 1, 0),
+    };
+    write_entry(flash, layout, sector, SelectEntry { seq, state })
+}
+
+/// Runs early at boot to implement the rollback described in the module docs.
+pub fn check_boot() {
+    let mut flash = FlashStorage::new();
+    let Ok(layout) = read_layout(&mut flash) else {
+        log::info!("No OTA partitions, updates are disabled");
+        return;
+    };
+    let Ok(Some((mut entry, sector))) = read_active_entry(&mut flash, &layout) else {
+        return;
+    };
+    match entry.state {
+        OTA_STATE_NEW => {
+            log::info!("First boot of updated firmware in ota_{}", entry.slot());
+            entry.state = OTA_STATE_PENDING_VERIFY;
+            if write_entry(&mut flash, &layout, sector, entry).is_err() {
+                log::error!("Failed to mark firmware as pending verification");
+            }
+        }
+        OTA_STATE_PENDING_VERIFY => {
+            log::error!(
+                "Firmware in ota_{} never became ready, rolling back",
+                entry.slot()
+            );
+            entry.state = OTA_STATE_ABORTED;
+            let previous = 1 - entry.slot();
+            if write_entry(&mut flash, &layout, sector, entry).is_ok()
+                && select_slot(&mut flash, &layout, previous, OTA_STATE_VALID).is_ok()
+            {
+                esp_hal::reset::software_reset();
+            }
+            log::error!("Rollback failed, continuing with the current firmware");
+        }
+        _ => {}
+    }
+}
+
+/// Marks the running firmware as good, cancelling the automatic rollback.
+pub fn mark_valid() {
+    let mut flash = FlashStorage::new();
+    let Ok(layout) = read_layout(&mut flash) else {
+        return;
+    };
+    if let Ok(Some((mut entry, sector))) = read_active_entry(&mut flash, &layout) {
+        if entry.state == OTA_STATE_PENDING_VERIFY {
+            entry.state = OTA_STATE_VALID;
+            match write_entry(&mut flash, &layout, sector, entry) {
+                Ok(()) => log::info!("Firmware in ota_{} marked valid", entry.slot()),
+                Err(e) => log::error!("Failed to mark firmware valid: {:?}", e),
+            }
+        }
+    }
+}
+
+async fn receive_update(socket: &mut TcpSocket<'_>) -> Result<usize, OtaError> {
+    let mut flash = FlashStorage::new();
+    let layout = read_layout(&mut flash)?;
+    let running = match read_active_entry(&mut flash, &layout)? {
+        Some((entry, _)) => entry.slot(),
+        None => 0,
+    };
+    let target_slot = 1 - running;
+    let target = layout.slots[target_slot];
+
+    let mut header = [0u8; 42];
+    socket
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| OtaError::Network)?;
+    if &header[0..6] != HEADER_MAGIC {
+        return Err(OtaError::BadHeader);
+    }
+    let size = u32::from_le_bytes(header[6..10].try_into().unwrap()) as usize;
+    let expected_hash: [u8; 32] = header[10..42].try_into().unwrap();
+    if size == 0 {
+        return Err(OtaError::EmptyImage);
+    }
+    if size > target.size as usize {
+        return Err(OtaError::TooLarge);
+    }
+    log::info!("Receiving {} byte firmware into ota_{}", size, target_slot);
+    socket
+        .write_all(b"OK\n")
+        .await
+        .map_err(|_| OtaError::Network)?;
+
+    // Buffer a sector at a time so we only erase and write whole sectors.
+    let mut sector = [0xffu8; SECTOR_SIZE];
+    let mut hasher = Sha256::new();
+    let mut received = 0;
+    while received < size {
+        let filled = received % SECTOR_SIZE;
+        let want = (SECTOR_SIZE - filled).min(size - received);
+        let read = socket
+            .read(&mut sector[filled..filled + want])
+            .await
+            .map_err(|_| OtaError::Network)?;
+        if read == 0 {
+            return Err(OtaError::Network);
+        }
+        if received == 0 && sector[0] != IMAGE_MAGIC {
+            return Err(OtaError::NotAnImage);
+        }
+        hasher.update(&sector[filled..filled + read]);
+        received += read;
+
+        if received % SECTOR_SIZE == 0 || received == size {
+            let offset = target.offset + ((received - 1) / SECTOR_SIZE * SECTOR_SIZE) as u32;
+            flash
+                .erase(offset, offset + SECTOR_SIZE as u32)
+                .map_err(|_| OtaError::Flash)?;
+            flash.write(offset, &sector).map_err(|_| OtaError::Flash)?;
+            sector.fill(0xff);
+        }
+    }
+
+    if hasher.finalize().as_slice() != expected_hash {
+        return Err(OtaError::HashMismatch);
+    }
+
+    // Read the image back to make sure it actually made it to flash.
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    while offset < size {
+        let len = SECTOR_SIZE.min(size - offset);
+        flash
+            .read(target.offset + offset as u32, &mut sector[..len])
+            .map_err(|_| OtaError::Flash)?;
+        hasher.update(&sector[..len]);
+        offset += len;
+    }
+    if hasher.finalize().as_slice() != expected_hash {
+        return Err(OtaError::HashMismatch);
+    }
+
+    select_slot(&mut flash, &layout, target_slot, OTA_STATE_NEW)?;
+    Ok(target_slot)
+}
+
+/// Accepts firmware updates over TCP, one client at a time.
+#[embassy_executor::task]
+pub async fn ota_task(stack: &'static Stack<WifiDriver>) {
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 256];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(OTA_PORT).await {
+            log::warn!("OTA accept failed: {:?}", e);
+            continue;
+        }
+        log::info!("OTA client connected from {:?}", socket.remote_endpoint());
+
+        match receive_update(&mut socket).await {
+            Ok(slot) => {
+                log::info!("Firmware written to ota_{}, rebooting", slot);
+                let _ = socket.write_all(b"OK\n").await;
+                let _ = socket.flush().await;
+                socket.close();
+                embassy_time::Timer::after(Duration::from_millis(500)).await;
+                esp_hal::reset::software_reset();
+            }
+            Err(e) => {
+                log::error!("Firmware update failed: {:?}", e);
+                let mut message = heapless::String::<32>::new();
+                let _ = core::fmt::write(&mut message, format_args!("ERR {:?}\n", e));
+                let _ = socket.write_all(message.as_bytes()).await;
+                let _ = socket.flush().await;
+                socket.close();
+            }
+        }
+        socket.abort();
+    }
+}
@@ -0,0 +1,195 @@
+//! Logger that prints to the serial port like `esp_println` and also
+//! broadcasts every record as JSON on UDP port [`LOG_PORT`], for the client's
+//! `logs` mode. Records wait in a ring buffer until the network is up, the
+//! oldest are dropped when it is full and sending is rate limited so a
+//! logging loop can't flood the network.
+
+use core::cell::RefCell;
+use core::fmt::Write as _;
+use core::str::FromStr;
+
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Ipv4Address, Stack};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use serde::Serialize;
+
+use crate::WifiDriver;
+
+pub const LOG_PORT: u16 = 8091;
+const BUFFER_RECORDS: usize = 32;
+const MAX_MESSAGE_LENGTH: usize = 160;
+/// Token bucket: up to `RATE_BURST` records at once, refilled at
+/// `RATE_PER_SEC`.
+const RATE_BURST: u32 = 20;
+const RATE_PER_SEC: u32 = 10;
+
+struct Record {
+    level: log::Level,
+    target: heapless::String<32>,
+    uptime_ms: u64,
+    message: heapless::String<MAX_MESSAGE_LENGTH>,
+}
+
+#[derive(Serialize)]
+struct Packet<'a> {
+    device: &'a str,
+    board: &'a str,
+    level: &'a str,
+    target: &'a str,
+    uptime_ms: u64,
+    msg: &'a str,
+}
+
+struct Buffer {
+    records: heapless::Deque<Record, BUFFER_RECORDS>,
+    dropped: u32,
+}
+
+static BUFFER: Mutex<CriticalSectionRawMutex, RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer {
+    records: heapless::Deque::new(),
+    dropped: 0,
+}));
+static PENDING: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+struct RemoteLogger;
+
+impl log::Log for RemoteLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        esp_println::println!("{:<5} - {}", record.level(), record.args());
+
+        // A message too long for the buffer is cut short rather than lost.
+        let mut message = heapless::String::new();
+        let _ = write!(message, "{}", record.args());
+        let mut target = heapless::String::new();
+        for c in record.target().chars() {
+            if target.push(c).is_err() {
+                break;
+            }
+        }
+        let record = Record {
+            level: record.level(),
+            target,
+            uptime_ms: Instant::now().as_millis(),
+            message,
+        };
+        BUFFER.lock(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            if buffer.records.is_full() {
+                buffer.records.pop_front();
+                buffer.dropped += 1;
+            }
+            let _ = buffer.records.push_back(record);
+        });
+        PENDING.signal(());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: RemoteLogger = RemoteLogger;
+
+/// Installs the logger, at the level from `ESP_LOG` (default info).
+pub fn init() {
+    let level = option_env!("ESP_LOG")
+        .and_then(|level| log::LevelFilter::from_str(level.split(',').next()?).ok())
+        .unwrap_or(log::LevelFilter::Info);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+fn next_record() -> Option<(Record, u32)> {
+    BUFFER.lock(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        let record = buffer.records.pop_front()?;
+        Some((record, core::mem::take(&mut buffer.dropped)))
+    })
+}
+
+#[embassy_executor::task]
+pub async fn remote_log_task(stack: &'static Stack<WifiDriver>, device_name: &'static str) {
+    stack.wait_config_up().await;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(LOG_PORT) {
+        // Logged to the serial port only, as the socket is what's missing.
+        log::error!("Failed to bind the log port, not forwarding logs: {:?}", e);
+        return;
+    }
+
+    let mut packet = [0u8; 384];
+    let mut tokens = RATE_BURST;
+    let mut refilled = Instant::now();
+    loop {
+        let Some((record, dropped)) = next_record() else {
+            PENDING.wait().await;
+            continue;
+        };
+
+        let refill = (refilled.elapsed().as_millis() * u64::from(RATE_PER_SEC) / 1000) as u32;
+        if refill > 0 {
+            tokens = (tokens + refill).min(RATE_BURST);
+            refilled = Instant::now();
+        }
+        if tokens == 0 {
+            Timer::after(Duration::from_millis(1000 / u64::from(RATE_PER_SEC))).await;
+            tokens = 1;
+            refilled = Instant::now();
+        }
+        tokens -= 1;
+
+        // Use the subnet broadcast address, it follows DHCP renewals.
+        let broadcast = stack
+            .config_v4()
+            .and_then(|config| config.address.broadcast())
+            .unwrap_or(Ipv4Address::BROADCAST);
+        let destination = IpEndpoint::new(broadcast.into(), LOG_PORT);
+
+        // Not logged, a failing send would only produce more records to send.
+        let mut dropped_message = heapless::String::<40>::new();
+        if dropped > 0 {
+            let _ = write!(dropped_message, "{} log records dropped", dropped);
+            let notice = Packet {
+                device: device_name,
+                board: "rover",
+                level: "WARN",
+                target: "remote_log",
+                uptime_ms: record.uptime_ms,
+                msg: &dropped_message,
+            };
+            if let Ok(len) = serde_json_core::to_slice(&notice, &mut packet) {
+                let _ = socket.send_to(&packet[..len], destination).await;
+            }
+        }
+        let json = Packet {
+            device: device_name,
+            board: "rover",
+            level: record.level.as_str(),
+            target: &record.target,
+            uptime_ms: record.uptime_ms,
+            msg: &record.message,
+        };
+        if let Ok(len) = serde_json_core::to_slice(&json, &mut packet) {
+            let _ = socket.send_to(&packet[..len], destination).await;
+        }
+    }
+}
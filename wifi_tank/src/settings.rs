@@ -0,0 +1,670 @@
+use core::str::FromStr;
+
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize};
+
+use crate::obstacle::MAX_DISTANCE_CM;
+
+// We reuse the default `nvs` partition (0x9000, 24KB) as nothing else on the
+// no_std side touches it. Only the first sector is used.
+const SETTINGS_OFFSET: u32 = 0x9000;
+const SETTINGS_MAGIC: u32 = 0x4b4e_5457; // "WTNK"
+/// New fields are appended from this version on (see [`Settings::encode`]),
+/// so it only changes when an existing field has to.
+const SETTINGS_VERSION: u8 = 13;
+/// The version before `speed_cap`, which went into the middle of the drive
+/// configuration. Decoding skips it for this version only.
+const SETTINGS_VERSION_NO_SPEED_CAP: u8 = 12;
+const HEADER_SIZE: usize = 11; // magic (4) + version (1) + length (2) + crc (4)
+const SECTOR_SIZE: usize = 4096;
+const MAX_PAYLOAD_SIZE: usize = SECTOR_SIZE - HEADER_SIZE;
+
+/// Used as hostname and BLE name until a device name is provisioned, can be
+/// overridden at build time with `DEVICE_NAME`.
+pub const DEFAULT_DEVICE_NAME: &str = match option_env!("DEVICE_NAME") {
+    Some(name) => name,
+    None => "wifitank",
+};
+const _: () = assert!(
+    is_valid_device_name(DEFAULT_DEVICE_NAME),
+    "DEVICE_NAME must be 1 to 32 letters, digits and -"
+);
+const _: () = assert!(
+    env!("WIFI_SSID").len() <= 32 && env!("WIFI_PASSWORD").len() <= 64,
+    "WIFI_SSID must be at most 32 bytes and WIFI_PASSWORD at most 64"
+);
+pub const MAX_NETWORKS: usize = 8;
+/// Go to sleep after this long without commands, unless configured otherwise.
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u32 = 600;
+/// Short PINs could be guessed one HTTP request at a time.
+pub const MIN_INSTRUCTOR_PIN_LENGTH: usize = 6;
+
+/// Whether a device name is usable as a hostname: ASCII letters, digits and
+/// `-`, at most 32 bytes. A `const fn` so the build-time default is checked
+/// at compile time.
+const fn is_valid_device_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes.len() > 32 {
+        return false;
+    }
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_alphanumeric() && bytes[i] != b'-' {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+pub fn parse_device_name(name: &str) -> Option<heapless::String<32>> {
+    if !is_valid_device_name(name) {
+        return None;
+    }
+    heapless::String::from_str(name).ok()
+}
+
+/// Checks an instructor PIN is long enough to resist guessing and fits.
+pub fn parse_instructor_pin(pin: &str) -> Option<heapless::String<16>> {
+    if pin.len() < MIN_INSTRUCTOR_PIN_LENGTH {
+        return None;
+    }
+    heapless::String::from_str(pin).ok()
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Flash,
+    Encode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WifiCredentials {
+    pub ssid: heapless::String<32>,
+    pub password: heapless::String<64>,
+}
+
+impl WifiCredentials {
+    pub fn new(ssid: &str, password: &str) -> Option<Self> {
+        Some(Self {
+            ssid: heapless::String::from_str(ssid).ok()?,
+            password: heapless::String::from_str(password).ok()?,
+        })
+    }
+
+    /// Credentials baked in at build time from `.cargo/config.toml`.
+    pub fn build_default() -> Self {
+        Self::new(env!("WIFI_SSID"), env!("WIFI_PASSWORD"))
+            .expect("WIFI_SSID or WIFI_PASSWORD too long")
+    }
+}
+
+/// How the rover gets its IPv4 address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum IpConfig {
+    Dhcp,
+    Static(StaticIp),
+}
+
+impl IpConfig {
+    /// From `STATIC_IP`, `STATIC_GATEWAY` and `STATIC_DNS` in
+    /// `.cargo/config.toml`, DHCP if `STATIC_IP` is empty or invalid.
+    pub fn build_default() -> Self {
+        let non_empty = |value: &'static str| (!value.is_empty()).then_some(value);
+        let Some(address) = non_empty(env!("STATIC_IP")) else {
+            return Self::Dhcp;
+        };
+        match StaticIp::parse(
+            address,
+            non_empty(env!("STATIC_GATEWAY")),
+            non_empty(env!("STATIC_DNS")),
+        ) {
+            Some(static_ip) => Self::Static(static_ip),
+            None => {
+                log::error!("Invalid build-time static IP configuration, using DHCP");
+                Self::Dhcp
+            }
+        }
+    }
+}
+
+impl core::fmt::Display for IpConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Dhcp => write!(f, "DHCP"),
+            Self::Static(static_ip) => write!(f, "static {}", static_ip),
+        }
+    }
+}
+
+/// Fixed IPv4 configuration, used instead of DHCP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StaticIp {
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: Option<[u8; 4]>,
+    pub dns: Option<[u8; 4]>,
+}
+
+fn parse_ipv4(text: &str) -> Option<[u8; 4]> {
+    let mut octets = [0u8; 4];
+    let mut parts = text.split('.');
+    for octet in &mut octets {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(octets)
+}
+
+impl StaticIp {
+    /// Parses `address[/prefix]` (the prefix defaults to 24) and the optional
+    /// gateway and DNS server addresses.
+    pub fn parse(address: &str, gateway: Option<&str>, dns: Option<&str>) -> Option<Self> {
+        let (address, prefix_len) = match address.split_once('/') {
+            Some((address, prefix)) => (address, prefix.parse().ok()?),
+            None => (address, 24),
+        };
+        if prefix_len > 32 {
+            return None;
+        }
+        // Missing is fine, present but malformed is not.
+        let optional = |text: Option<&str>| match text {
+            Some(text) => parse_ipv4(text).map(Some),
+            None => Some(None),
+        };
+        Some(Self {
+            address: parse_ipv4(address)?,
+            prefix_len,
+            gateway: optional(gateway)?,
+            dns: optional(dns)?,
+        })
+    }
+}
+
+impl core::fmt::Display for StaticIp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d] = self.address;
+        write!(f, "{}.{}.{}.{}/{}", a, b, c, d, self.prefix_len)?;
+        if let Some([a, b, c, d]) = self.gateway {
+            write!(f, " gateway {}.{}.{}.{}", a, b, c, d)?;
+        }
+        if let Some([a, b, c, d]) = self.dns {
+            write!(f, " dns {}.{}.{}.{}", a, b, c, d)?;
+        }
+        Ok(())
+    }
+}
+
+/// Driving parameters, tunable over HTTP (see `http.rs`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DriveConfig {
+    /// Stop the motors after this long without commands, 0 disables.
+    pub watchdog_ms: u32,
+    /// Duty cycle when driving forward or backward, in percent.
+    pub max_speed: u8,
+    /// Duty cycle when turning on the spot, in percent.
+    pub turn_speed: u8,
+    /// Balances the two sides, positive slows the right side and negative
+    /// the left, in percent.
+    pub trim: i8,
+    /// Refuse to drive forward with an obstacle closer than this, in cm, 0
+    /// disables.
+    pub obstacle_stop_cm: u16,
+    /// Drive forward at half speed with an obstacle closer than this, in cm,
+    /// 0 disables.
+    pub obstacle_slow_cm: u16,
+    /// Keep the heading with the IMU while driving forward or backward.
+    pub heading_hold: bool,
+    /// Cut the motors when the rover tips further than this, in degrees, 0
+    /// disables.
+    pub tilt_limit_deg: u8,
+    /// Brake and back off when the floor disappears under the front
+    /// corners, needs the cliff sensors fitted (see `cliff.rs`).
+    pub cliff_sensors: bool,
+    /// Duty cycle when following a line, in percent, see `line.rs`.
+    pub line_speed: u8,
+    /// Line following PID gains, steering in percent duty per unit of line
+    /// position (-1 to 1), its integral (per second) and its rate of change
+    /// (per second).
+    pub line_kp: f32,
+    pub line_ki: f32,
+    pub line_kd: f32,
+    /// How far the camera gimbal pans either way, in degrees, see
+    /// `gimbal.rs`.
+    pub pan_limit_deg: u8,
+    /// How far the camera gimbal tilts up and down, in degrees.
+    pub tilt_up_deg: u8,
+    pub tilt_down_deg: u8,
+    /// How fast the camera gimbal turns, in degrees per second.
+    pub gimbal_speed_dps: u16,
+    /// Highest duty cycle whatever the speed profile, in percent. Only
+    /// changed over HTTP with the instructor PIN (see `http.rs`), so an
+    /// instructor can cap the rover for a new driver.
+    pub speed_cap: u8,
+}
+
+impl DriveConfig {
+    pub const DEFAULT: Self = Self {
+        watchdog_ms: 500,
+        max_speed: 100,
+        turn_speed: 100,
+        trim: 0,
+        obstacle_stop_cm: 20,
+        obstacle_slow_cm: 50,
+        heading_hold: true,
+        tilt_limit_deg: 45,
+        cliff_sensors: false,
+        line_speed: 40,
+        line_kp: 40.0,
+        line_ki: 0.0,
+        line_kd: 2.0,
+        pan_limit_deg: 90,
+        tilt_up_deg: 45,
+        tilt_down_deg: 30,
+        gimbal_speed_dps: 90,
+        speed_cap: 100,
+    };
+
+    pub fn is_valid(&self) -> bool {
+        self.max_speed <= 100
+            && self.speed_cap <= 100
+            && self.turn_speed <= 100
+            && (-50..=50).contains(&self.trim)
+            && self.obstacle_stop_cm <= MAX_DISTANCE_CM
+            && self.obstacle_slow_cm <= MAX_DISTANCE_CM
+            && self.tilt_limit_deg <= 90
+            && self.line_speed <= 100
+            && [self.line_kp, self.line_ki, self.line_kd]
+                .iter()
+                .all(|gain| (0.0..=1000.0).contains(gain))
+            && [self.pan_limit_deg, self.tilt_up_deg, self.tilt_down_deg]
+                .iter()
+                .all(|limit| *limit <= 90)
+            && (1..=360).contains(&self.gimbal_speed_dps)
+    }
+}
+
+impl Default for DriveConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Everything the rover persists across reboots.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// Provisioned networks, highest priority first.
+    pub networks: heapless::Vec<WifiCredentials, MAX_NETWORKS>,
+    /// Provisioned device name, `None` means [`DEFAULT_DEVICE_NAME`].
+    pub device_name: Option<heapless::String<32>>,
+    /// Seconds without commands before deep sleep, `None` means
+    /// [`DEFAULT_IDLE_TIMEOUT_SECS`] and 0 disables it.
+    pub idle_timeout_secs: Option<u32>,
+    /// Seconds after which to wake from deep sleep, 0 only wakes on the
+    /// button.
+    pub wake_interval_secs: u32,
+    pub drive: DriveConfig,
+    /// Device ID of the paired camera, see `beacon.rs`.
+    pub partner: Option<heapless::String<32>>,
+    /// Stored IPv4 configuration, `None` means the build-time one.
+    pub ip_config: Option<IpConfig>,
+    /// Needed to change the speed cap over HTTP, set on the serial console.
+    /// `None` means the cap can't be changed over HTTP at all.
+    pub instructor_pin: Option<heapless::String<16>>,
+}
+
+/// Longest postcard encoding of a `heapless::String<N>`, with N < 128.
+const fn encoded_string(capacity: usize) -> usize {
+    1 + capacity
+}
+
+/// Longest postcard encoding of [`Settings`], with varints taking up to 3
+/// bytes for a `u16` and 5 for a `u32`.
+const MAX_ENCODED_SIZE: usize = {
+    let network = encoded_string(32) + encoded_string(64);
+    let drive = 5 + 1 + 1 + 1 + 3 + 3 + 1 + 1 + 1 + 1 + 3 * 4 + 1 + 1 + 1 + 3 + 1;
+    let static_ip = 4 + 1 + (1 + 4) + (1 + 4);
+    (1 + MAX_NETWORKS * network)
+        + (1 + encoded_string(32))
+        + (1 + 5)
+        + 5
+        + drive
+        + (1 + encoded_string(32))
+        + (1 + 1 + static_ip)
+        + (1 + encoded_string(16))
+};
+const _: () = assert!(MAX_ENCODED_SIZE <= MAX_PAYLOAD_SIZE);
+
+/// Writes settings fields one after another, each in postcard.
+struct Encoder<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Encoder<'_> {
+    fn field<T: Serialize>(&mut self, value: &T) -> Result<(), SettingsError> {
+        self.len += postcard::to_slice(value, &mut self.buffer[self.len..])
+            .map_err(|_| SettingsError::Encode)?
+            .len();
+        Ok(())
+    }
+}
+
+/// Reads fields written by an [`Encoder`], giving the default for the ones
+/// appended after the payload was written.
+struct Decoder<'a> {
+    payload: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn field<T: Deserialize<'a>>(&mut self, default: T) -> Result<T, postcard::Error> {
+        if self.payload.is_empty() {
+            return Ok(default);
+        }
+        let (value, rest) = postcard::take_from_bytes(self.payload)?;
+        self.payload = rest;
+        Ok(value)
+    }
+}
+
+impl Settings {
+    /// Writes the fields in their stored order. Up to version 13 that is the
+    /// order of the structs, with the drive configuration flattened in the
+    /// middle, so `speed_cap` comes before `partner` even though version 12
+    /// already had `partner` and `ip_config`. Fields added since go at the
+    /// end, so payloads from older firmware still decode and get defaults for
+    /// what they lack.
+    fn encode(&self, out: &mut Encoder) -> Result<(), SettingsError> {
+        out.field(&self.networks)?;
+        out.field(&self.device_name)?;
+        out.field(&self.idle_timeout_secs)?;
+        out.field(&self.wake_interval_secs)?;
+        let drive = &self.drive;
+        out.field(&drive.watchdog_ms)?;
+        out.field(&drive.max_speed)?;
+        out.field(&drive.turn_speed)?;
+        out.field(&drive.trim)?;
+        out.field(&drive.obstacle_stop_cm)?;
+        out.field(&drive.obstacle_slow_cm)?;
+        out.field(&drive.heading_hold)?;
+        out.field(&drive.tilt_limit_deg)?;
+        out.field(&drive.cliff_sensors)?;
+        out.field(&drive.line_speed)?;
+        out.field(&drive.line_kp)?;
+        out.field(&drive.line_ki)?;
+        out.field(&drive.line_kd)?;
+        out.field(&drive.pan_limit_deg)?;
+        out.field(&drive.tilt_up_deg)?;
+        out.field(&drive.tilt_down_deg)?;
+        out.field(&drive.gimbal_speed_dps)?;
+        out.field(&drive.speed_cap)?;
+        out.field(&self.partner)?;
+        out.field(&self.ip_config)?;
+        out.field(&self.instructor_pin)
+    }
+
+    /// Reads what [`Settings::encode`] wrote, in the same order.
+    fn decode(input: &mut Decoder, version: u8) -> Result<Self, postcard::Error> {
+        let drive = DriveConfig::DEFAULT;
+        // Struct expressions are evaluated in the order they are written.
+        Ok(Self {
+            networks: input.field(heapless::Vec::new())?,
+            device_name: input.field(None)?,
+            idle_timeout_secs: input.field(None)?,
+            wake_interval_secs: input.field(0)?,
+            drive: DriveConfig {
+                watchdog_ms: input.field(drive.watchdog_ms)?,
+                max_speed: input.field(drive.max_speed)?,
+                turn_speed: input.field(drive.turn_speed)?,
+                trim: input.field(drive.trim)?,
+                obstacle_stop_cm: input.field(drive.obstacle_stop_cm)?,
+                obstacle_slow_cm: input.field(drive.obstacle_slow_cm)?,
+                heading_hold: input.field(drive.heading_hold)?,
+                tilt_limit_deg: input.field(drive.tilt_limit_deg)?,
+                cliff_sensors: input.field(drive.cliff_sensors)?,
+                line_speed: input.field(drive.line_speed)?,
+                line_kp: input.field(drive.line_kp)?,
+                line_ki: input.field(drive.line_ki)?,
+                line_kd: input.field(drive.line_kd)?,
+                pan_limit_deg: input.field(drive.pan_limit_deg)?,
+                tilt_up_deg: input.field(drive.tilt_up_deg)?,
+                tilt_down_deg: input.field(drive.tilt_down_deg)?,
+                gimbal_speed_dps: input.field(drive.gimbal_speed_dps)?,
+                speed_cap: if version == SETTINGS_VERSION_NO_SPEED_CAP {
+                    drive.speed_cap
+                } else {
+                    input.field(drive.speed_cap)?
+                },
+            },
+            partner: input.field(None)?,
+            ip_config: input.field(None)?,
+            instructor_pin: input.field(None)?,
+        })
+    }
+
+    pub fn device_name(&self) -> &str {
+        self.device_name.as_deref().unwrap_or(DEFAULT_DEVICE_NAME)
+    }
+
+    pub fn ip_config(&self) -> IpConfig {
+        self.ip_config
+            .clone()
+            .unwrap_or_else(IpConfig::build_default)
+    }
+
+    pub fn idle_timeout_secs(&self) -> u32 {
+        self.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS)
+    }
+
+    /// Provisioned networks in priority order, followed by the build-time
+    /// default as a last resort.
+    pub fn known_networks(&self) -> heapless::Vec<WifiCredentials, { MAX_NETWORKS + 1 }> {
+        let mut networks: heapless::Vec<_, { MAX_NETWORKS + 1 }> =
+            self.networks.iter().cloned().collect();
+        let default = WifiCredentials::build_default();
+        if !networks.iter().any(|n| n.ssid == default.ssid) {
+            let _ = networks.push(default);
+        }
+        networks
+    }
+
+    /// Adds (or updates) a network as the highest priority, dropping the
+    /// lowest priority one if the list is full.
+    pub fn add_network(&mut self, credentials: WifiCredentials) {
+        self.forget_network(&credentials.ssid);
+        if self.networks.is_full() {
+            self.networks.pop();
+        }
+        let _ = self.networks.insert(0, credentials);
+    }
+
+    pub fn forget_network(&mut self, ssid: &str) -> bool {
+        let before = self.networks.len();
+        self.networks.retain(|n| n.ssid != ssid);
+        self.networks.len() != before
+    }
+}
+
+pub struct SettingsStore {
+    flash: FlashStorage,
+}
+
+impl SettingsStore {
+    pub fn new() -> Self {
+        Self {
+            flash: FlashStorage::new(),
+        }
+    }
+
+    /// Loads the stored settings, falling back to defaults if the sector is
+    /// blank, corrupt or was written by an incompatible firmware version.
+    /// Settings from older firmware get defaults for the fields added since.
+    pub fn load(&mut self) -> Settings {
+        let mut buffer = [0u8; HEADER_SIZE + MAX_PAYLOAD_SIZE];
+        if self.flash.read(SETTINGS_OFFSET, &mut buffer).is_err() {
+            log::warn!("Failed to read settings from flash, using defaults");
+            return Settings::default();
+        }
+
+        let magic = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+        let version = buffer[4];
+        let len = u16::from_le_bytes([buffer[5], buffer[6]]) as usize;
+        let crc = u32::from_le_bytes([buffer[7], buffer[8], buffer[9], buffer[10]]);
+        if magic != SETTINGS_MAGIC || len > MAX_PAYLOAD_SIZE {
+            log::info!("No stored settings found, using defaults");
+            return Settings::default();
+        }
+        if version != SETTINGS_VERSION && version != SETTINGS_VERSION_NO_SPEED_CAP {
+            log::warn!(
+                "Stored settings are version {}, which can't be read, using defaults",
+                version
+            );
+            return Settings::default();
+        }
+
+        let payload = &buffer[HEADER_SIZE..HEADER_SIZE + len];
+        if esp_hal::rom::crc::crc32_le(!0, payload) != crc {
+            log::warn!("Stored settings failed CRC check, using defaults");
+            return Settings::default();
+        }
+
+        match Settings::decode(&mut Decoder { payload }, version) {
+            Ok(settings) => settings,
+            Err(e) => {
+                log::warn!("Failed to decode stored settings: {:?}", e);
+                Settings::default()
+            }
+        }
+    }
+
+    pub fn save(&mut self, settings: &Settings) -> Result<(), SettingsError> {
+        let mut buffer = [0u8; HEADER_SIZE + MAX_PAYLOAD_SIZE];
+        let mut encoder = Encoder {
+            buffer: &mut buffer[HEADER_SIZE..],
+            len: 0,
+        };
+        settings.encode(&mut encoder)?;
+        let len = encoder.len;
+        let crc = esp_hal::rom::crc::crc32_le(!0, &buffer[HEADER_SIZE..HEADER_SIZE + len]);
+
+        buffer[0..4].copy_from_slice(&SETTINGS_MAGIC.to_le_bytes());
+        buffer[4] = SETTINGS_VERSION;
+        buffer[5..7].copy_from_slice(&(len as u16).to_le_bytes());
+        buffer[7..11].copy_from_slice(&crc.to_le_bytes());
+
+        self.flash
+            .write(SETTINGS_OFFSET, &buffer[..HEADER_SIZE + len])
+            .map_err(|_| SettingsError::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Settings {
+        let mut settings = Settings {
+            device_name: parse_device_name("lab-rover"),
+            idle_timeout_secs: Some(300),
+            wake_interval_secs: 3600,
+            drive: DriveConfig {
+                max_speed: 80,
+                trim: -5,
+                line_kp: 12.5,
+                gimbal_speed_dps: 120,
+                speed_cap: 40,
+                ..DriveConfig::DEFAULT
+            },
+            partner: heapless::String::from_str("cam-0123abcd").ok(),
+            ip_config: StaticIp::parse("192.168.1.50/24", Some("192.168.1.1"), None)
+                .map(IpConfig::Static),
+            instructor_pin: parse_instructor_pin("482915"),
+            ..Settings::default()
+        };
+        settings.add_network(WifiCredentials::new("Home", "secret").unwrap());
+        settings
+    }
+
+    fn decode(payload: &[u8], version: u8) -> Settings {
+        Settings::decode(&mut Decoder { payload }, version).unwrap()
+    }
+
+    /// The fields version 12 firmware wrote, without `speed_cap`.
+    fn encode_version_12(settings: &Settings, out: &mut Encoder) -> Result<(), SettingsError> {
+        let drive = &settings.drive;
+        out.field(&settings.networks)?;
+        out.field(&settings.device_name)?;
+        out.field(&settings.idle_timeout_secs)?;
+        out.field(&settings.wake_interval_secs)?;
+        out.field(&drive.watchdog_ms)?;
+        out.field(&drive.max_speed)?;
+        out.field(&drive.turn_speed)?;
+        out.field(&drive.trim)?;
+        out.field(&drive.obstacle_stop_cm)?;
+        out.field(&drive.obstacle_slow_cm)?;
+        out.field(&drive.heading_hold)?;
+        out.field(&drive.tilt_limit_deg)?;
+        out.field(&drive.cliff_sensors)?;
+        out.field(&drive.line_speed)?;
+        out.field(&drive.line_kp)?;
+        out.field(&drive.line_ki)?;
+        out.field(&drive.line_kd)?;
+        out.field(&drive.pan_limit_deg)?;
+        out.field(&drive.tilt_up_deg)?;
+        out.field(&drive.tilt_down_deg)?;
+        out.field(&drive.gimbal_speed_dps)?;
+        out.field(&settings.partner)?;
+        out.field(&settings.ip_config)
+    }
+
+    #[test]
+    fn round_trip() {
+        let settings = sample();
+        let mut buffer = [0u8; MAX_PAYLOAD_SIZE];
+        let mut encoder = Encoder {
+            buffer: &mut buffer,
+            len: 0,
+        };
+        settings.encode(&mut encoder).unwrap();
+        let len = encoder.len;
+
+        let decoded = decode(&buffer[..len], SETTINGS_VERSION);
+        assert_eq!(decoded.networks[0].ssid, "Home");
+        assert_eq!(decoded.networks[0].password, "secret");
+        assert_eq!(decoded.device_name, settings.device_name);
+        assert_eq!(decoded.idle_timeout_secs, Some(300));
+        assert_eq!(decoded.wake_interval_secs, 3600);
+        assert_eq!(decoded.drive, settings.drive);
+        assert_eq!(decoded.partner, settings.partner);
+        assert_eq!(decoded.ip_config, settings.ip_config);
+        assert_eq!(decoded.instructor_pin, settings.instructor_pin);
+    }
+
+    #[test]
+    fn version_12_gets_the_default_speed_cap() {
+        let settings = sample();
+        let mut buffer = [0u8; MAX_PAYLOAD_SIZE];
+        let mut encoder = Encoder {
+            buffer: &mut buffer,
+            len: 0,
+        };
+        encode_version_12(&settings, &mut encoder).unwrap();
+        let len = encoder.len;
+
+        let decoded = decode(&buffer[..len], SETTINGS_VERSION_NO_SPEED_CAP);
+        assert_eq!(decoded.networks[0].ssid, "Home");
+        assert_eq!(decoded.device_name, settings.device_name);
+        assert_eq!(
+            decoded.drive,
+            DriveConfig {
+                speed_cap: DriveConfig::DEFAULT.speed_cap,
+                ..settings.drive
+            }
+        );
+        // Read from after the drive configuration, so only right if
+        // `speed_cap` was skipped.
+        assert_eq!(decoded.partner, settings.partner);
+        assert_eq!(decoded.ip_config, settings.ip_config);
+        assert_eq!(decoded.instructor_pin, None);
+    }
+}
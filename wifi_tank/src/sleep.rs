@@ -0,0 +1,137 @@
+//! Deep sleep to save the power bank when the rover is not being driven.
+//!
+//! The rover wakes on a button pulling [`WAKE_PIN`] low or, if a wake
+//! interval is configured, periodically on the RTC timer.
+
+use core::time::Duration;
+
+use esp_hal::{
+    gpio::GpioPin,
+    rtc_cntl::{
+        get_reset_reason, get_wakeup_cause,
+        sleep::{Ext0WakeupSource, TimerWakeupSource, WakeupLevel},
+        Rtc, SleepSource, SocResetReason,
+    },
+    Cpu,
+};
+
+use crate::connection;
+use crate::motors::Motors;
+use crate::telemetry;
+use crate::watchdog;
+
+/// RTC capable pin with the wake button (to GND, with a pull-up to 3V3).
+pub const WAKE_PIN: u8 = 33;
+/// The motor driver inputs on digital pads (GPIO 19, 21, 22 and 23), as
+/// their bits in `RTC_IO_DIG_PAD_HOLD_REG` (ESP-IDF's `GPIO_HOLD_MASK`).
+const MOTOR_DIGITAL_PADS: u32 = 1 << 12 | 1 << 14 | 1 << 15 | 1 << 16;
+
+/// Why the board started, reported in telemetry.
+fn wake_cause() -> &'static str {
+    match get_reset_reason(Cpu::ProCpu) {
+        Some(SocResetReason::CoreDeepSleep) => match get_wakeup_cause() {
+            SleepSource::Ext0 => "button",
+            SleepSource::Timer => "timer",
+            _ => "deep_sleep",
+        },
+        Some(SocResetReason::ChipPowerOn) => "power_on",
+        _ => "reset",
+    }
+}
+
+pub fn record_wake_cause() {
+    let cause = wake_cause();
+    log::info!("Wake cause: {}", cause);
+    telemetry::update(|t| t.wake_cause = cause);
+}
+
+/// Latches the motor driver inputs through deep sleep, or releases them.
+/// Otherwise the pins float while asleep and the H-bridges may switch on.
+fn hold_motor_pins(hold: bool) {
+    let rtc_cntl = unsafe { &*esp_hal::peripherals::LPWR::PTR };
+    let rtc_io = unsafe { &*esp_hal::peripherals::RTC_IO::PTR };
+    // GPIO 25, 26, 13 and 12 are RTC pads with their own hold bits.
+    rtc_cntl.hold_force().modify(|_, w| {
+        w.pdac1_hold_force()
+            .bit(hold)
+            .pdac2_hold_force()
+            .bit(hold)
+            .touch_pad4_hold_force()
+            .bit(hold)
+            .touch_pad5_hold_force()
+            .bit(hold)
+    });
+    rtc_io.dig_pad_hold().modify(|r, w| unsafe {
+        w.bits(if hold {
+            r.bits() | MOTOR_DIGITAL_PADS
+        } else {
+            r.bits() & !MOTOR_DIGITAL_PADS
+        })
+    });
+    // Digital pads only hold in deep sleep with autohold enabled.
+    rtc_cntl.dig_iso().modify(|_, w| {
+        if hold {
+            w.dg_pad_force_unhold()
+                .clear_bit()
+                .dg_pad_autohold_en()
+                .set_bit()
+        } else {
+            w.dg_pad_autohold_en()
+                .clear_bit()
+                .clr_dg_pad_autohold()
+                .set_bit()
+        }
+    });
+}
+
+/// Lets go of the motor pins held through the last deep sleep, call before
+/// setting up the motor PWM.
+pub fn release_motor_pins() {
+    hold_motor_pins(false);
+}
+
+pub struct DeepSleep {
+    rtc: Rtc<'static>,
+    button: GpioPin<WAKE_PIN>,
+    wake_interval: Option<Duration>,
+}
+
+impl DeepSleep {
+    pub fn new(
+        rtc: Rtc<'static>,
+        button: GpioPin<WAKE_PIN>,
+        wake_interval: Option<Duration>,
+    ) -> Self {
+        Self {
+            rtc,
+            button,
+            wake_interval,
+        }
+    }
+
+    /// Stops the motors, powers down the radio and sleeps until woken, with
+    /// the motor pins held low.
+    pub async fn enter(&mut self, motors: &mut Motors) -> ! {
+        watchdog::stop();
+        // Zero duty, so every motor pin is low when it is latched.
+        motors.stop();
+        connection::shutdown().await;
+        hold_motor_pins(true);
+
+        let button = Ext0WakeupSource::new(&mut self.button, WakeupLevel::Low);
+        match self.wake_interval {
+            Some(interval) => {
+                log::info!(
+                    "Entering deep sleep, waking on button or in {}s",
+                    interval.as_secs()
+                );
+                let timer = TimerWakeupSource::new(interval);
+                self.rtc.sleep_deep(&[&button, &timer])
+            }
+            None => {
+                log::info!("Entering deep sleep, waking on button");
+                self.rtc.sleep_deep(&[&button])
+            }
+        }
+    }
+}
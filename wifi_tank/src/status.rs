@@ -0,0 +1,156 @@
+//! Boot state machine: Init → WifiConnecting → NetUp → Ready, or Fault when
+//! something fails. The state is reported in telemetry and blinked on the
+//! status LED so a rover that isn't answering can still be diagnosed.
+//!
+//! Recoverable faults are retried by whoever hit them and the state moves on
+//! once the retry succeeds. Fatal faults (see [`fatal`]) blink their code for
+//! a while and then reset the board, which is the only way to retry them.
+
+use core::cell::Cell;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::{AnyPin, Level, Output};
+
+use crate::telemetry;
+
+/// How long a fatal fault is shown before resetting.
+const FATAL_RESET_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The radio or Wi-Fi driver failed to initialise.
+    WifiInit,
+    /// The Wi-Fi driver failed to start.
+    WifiStart,
+    /// The executor ran out of task slots.
+    Spawn,
+    /// The control socket could not be bound.
+    SocketBind,
+    /// The network came up without an IPv4 address.
+    NoAddress,
+    /// The motor PWM timer could not be configured.
+    Pwm,
+}
+
+impl Fault {
+    pub fn name(self) -> &'static str {
+        match self {
+            Fault::WifiInit => "wifi_init",
+            Fault::WifiStart => "wifi_start",
+            Fault::Spawn => "spawn",
+            Fault::SocketBind => "socket_bind",
+            Fault::NoAddress => "no_address",
+            Fault::Pwm => "pwm",
+        }
+    }
+
+    /// Number of blinks in the LED code.
+    fn blinks(self) -> u8 {
+        match self {
+            Fault::WifiInit => 1,
+            Fault::WifiStart => 2,
+            Fault::Spawn => 3,
+            Fault::SocketBind => 4,
+            Fault::NoAddress => 5,
+            Fault::Pwm => 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BootState {
+    Init,
+    WifiConnecting,
+    /// On the network, but not yet listening for the client.
+    NetUp,
+    Ready,
+    Fault(Fault),
+}
+
+impl BootState {
+    pub fn name(self) -> &'static str {
+        match self {
+            BootState::Init => "init",
+            BootState::WifiConnecting => "wifi_connecting",
+            BootState::NetUp => "net_up",
+            BootState::Ready => "ready",
+            BootState::Fault(_) => "fault",
+        }
+    }
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, Cell<BootState>> =
+    Mutex::new(Cell::new(BootState::Init));
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn get() -> BootState {
+    STATE.lock(|state| state.get())
+}
+
+pub fn set(new: BootState) {
+    let old = STATE.lock(|state| state.replace(new));
+    if old == new {
+        return;
+    }
+    match new {
+        BootState::Fault(fault) => log::error!("Fault: {}", fault.name()),
+        _ => log::info!("State: {} -> {}", old.name(), new.name()),
+    }
+    telemetry::update(|t| {
+        t.state = new.name();
+        if let BootState::Fault(fault) = new {
+            t.fault = Some(fault.name());
+        }
+    });
+    CHANGED.signal(());
+}
+
+/// Shows a fault that can't be retried in place, then resets the board.
+pub async fn fatal(fault: Fault) -> ! {
+    set(BootState::Fault(fault));
+    Timer::after(FATAL_RESET_DELAY).await;
+    esp_hal::reset::software_reset();
+    unreachable!()
+}
+
+async fn flash(led: &mut Output<'static, AnyPin>, on_ms: u64, off_ms: u64) {
+    led.set_low();
+    Timer::after(Duration::from_millis(on_ms)).await;
+    led.set_high();
+    Timer::after(Duration::from_millis(off_ms)).await;
+}
+
+/// One round of the LED pattern for `state`.
+async fn blink(led: &mut Output<'static, AnyPin>, state: BootState) {
+    match state {
+        BootState::Init => Timer::after(Duration::from_secs(1)).await,
+        BootState::WifiConnecting => flash(led, 500, 500).await,
+        BootState::NetUp => flash(led, 100, 100).await,
+        BootState::Ready => {
+            led.set_low();
+            Timer::after(Duration::from_secs(1)).await;
+        }
+        BootState::Fault(fault) => {
+            for _ in 0..fault.blinks() {
+                flash(led, 200, 300).await;
+            }
+            Timer::after(Duration::from_millis(1500)).await;
+        }
+    }
+}
+
+/// Drives the onboard LED of the Lolin D32 Pro (GPIO 5), lit when the pin is
+/// low.
+#[embassy_executor::task]
+pub async fn led_task(mut led: Output<'static, AnyPin>) {
+    led.set_level(Level::High);
+    loop {
+        let state = get();
+        if let Either::Second(()) = select(blink(&mut led, state), CHANGED.wait()).await {
+            led.set_high();
+        }
+    }
+}
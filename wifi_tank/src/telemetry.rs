@@ -0,0 +1,132 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use serde::Serialize;
+
+use crate::obstacle::{Range, MAX_RANGEFINDERS};
+
+/// Snapshot of the rover state sent back to the controller client as JSON.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Telemetry {
+    /// Boot state, see `status.rs`: `init`, `wifi_connecting`, `net_up`,
+    /// `ready` or `fault`.
+    pub state: &'static str,
+    /// The last fault, kept after recovering from it.
+    pub fault: Option<&'static str>,
+    /// SSID of the network we are connected to.
+    pub network: Option<heapless::String<32>>,
+    /// Signal strength of the connected access point in dBm.
+    pub rssi: Option<i8>,
+    /// Random for every boot, so the controller notices the rover restarted
+    /// (0 until the radio is up), see `entropy.rs`.
+    pub session: u32,
+    /// Our hostname, also used for mDNS and BLE.
+    pub hostname: &'static str,
+    /// Our IPv4 address and prefix, once we have one.
+    pub ip: Option<heapless::String<18>>,
+    /// `dhcp` or `static`.
+    pub ip_mode: &'static str,
+    /// Why the rover last started: `power_on`, `reset`, `button` or `timer`.
+    pub wake_cause: &'static str,
+    /// Why the board last reset: `power_on`, `deep_sleep`, `software`,
+    /// `panic`, `brownout`, `watchdog` or `other`, see `crash.rs`.
+    pub reset_reason: &'static str,
+    /// What the motors are doing: `stopped`, `forward`, `reverse`, `left`,
+    /// `right` or `line` (following a line).
+    pub motors: &'static str,
+    /// Speed profile: `turtle`, `normal` or `turbo`, see `motors.rs`.
+    pub profile: &'static str,
+    /// `clear`, `near` (forward is slowed) or `blocked` (forward is
+    /// refused), see `obstacle.rs`.
+    pub obstacle: &'static str,
+    /// Latest rangefinder readings.
+    pub ranges: heapless::Vec<Range, MAX_RANGEFINDERS>,
+    /// Clockwise from where the rover pointed at boot, in degrees, from the
+    /// IMU (see `imu.rs`).
+    pub heading: Option<u16>,
+    /// Nose up is positive, in degrees.
+    pub pitch: Option<i16>,
+    /// Right side down is positive, in degrees.
+    pub roll: Option<i16>,
+    /// The motors are cut because the rover tipped past the tilt limit.
+    pub tilted: bool,
+    /// The last safety event (`cliff_left`, `cliff_right` or `cliff_both`,
+    /// see `cliff.rs`).
+    pub safety: Option<&'static str>,
+    /// Safety events since boot, to tell a repeated event from the last one.
+    pub safety_events: u16,
+}
+
+static TELEMETRY: Mutex<CriticalSectionRawMutex, RefCell<Telemetry>> =
+    Mutex::new(RefCell::new(Telemetry {
+        state: "init",
+        fault: None,
+        network: None,
+        rssi: None,
+        session: 0,
+        hostname: "",
+        ip: None,
+        ip_mode: "",
+        wake_cause: "",
+        reset_reason: "",
+        motors: "stopped",
+        profile: "normal",
+        obstacle: "clear",
+        ranges: heapless::Vec::new(),
+        heading: None,
+        pitch: None,
+        roll: None,
+        tilted: false,
+        safety: None,
+        safety_events: 0,
+    }));
+
+/// Updates the shared telemetry in place.
+pub fn update(f: impl FnOnce(&mut Telemetry)) {
+    TELEMETRY.lock(|telemetry| f(&mut telemetry.borrow_mut()));
+}
+
+pub fn snapshot() -> Telemetry {
+    TELEMETRY.lock(|telemetry| telemetry.borrow().clone())
+}
+
+/// Serializes the current telemetry into `buffer`, returning the JSON bytes.
+pub fn to_json(buffer: &mut [u8]) -> Option<&[u8]> {
+    let len = serde_json_core::to_slice(&snapshot(), buffer).ok()?;
+    Some(&buffer[..len])
+}
+
+/// The part of the telemetry needed while driving, for links where the whole
+/// of it doesn't fit in a packet (ESP-NOW).
+#[derive(Serialize)]
+struct DriveTelemetry<'a> {
+    session: u32,
+    state: &'static str,
+    fault: Option<&'static str>,
+    motors: &'static str,
+    profile: &'static str,
+    obstacle: &'static str,
+    ranges: &'a [Range],
+    tilted: bool,
+    safety: Option<&'static str>,
+    safety_events: u16,
+}
+
+/// Serializes the driving telemetry into `buffer`, returning the JSON bytes.
+pub fn to_drive_json(buffer: &mut [u8]) -> Option<&[u8]> {
+    let telemetry = snapshot();
+    let drive = DriveTelemetry {
+        session: telemetry.session,
+        state: telemetry.state,
+        fault: telemetry.fault,
+        motors: telemetry.motors,
+        profile: telemetry.profile,
+        obstacle: telemetry.obstacle,
+        ranges: &telemetry.ranges,
+        tilted: telemetry.tilted,
+        safety: telemetry.safety,
+        safety_events: telemetry.safety_events,
+    };
+    let len = serde_json_core::to_slice(&drive, buffer).ok()?;
+    Some(&buffer[..len])
+}
@@ -0,0 +1,153 @@
+//! The gamepad client's UDP transport: single byte commands in, telemetry
+//! JSON out to whoever sent the last command. Tuning messages (`T` and a
+//! JSON drive configuration update) adjust the configuration on the fly,
+//! accessory messages (`X` and a text command, see `accessories.rs`) drive
+//! the lights and buzzer.
+
+use core::fmt::Write as _;
+
+use embassy_futures::select::{select, Either};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::Stack;
+use embassy_time::{Duration, Ticker, Timer};
+use esp_wifi::wifi::WifiState;
+
+use crate::accessories::{self, AccessoryCommand, ACCESSORY_MESSAGE};
+use crate::control::{self, ControlRequest, DriveCommand};
+use crate::status::{self, BootState, Fault};
+use crate::{crash, metrics, telemetry, WifiDriver};
+
+pub const CONTROL_PORT: u16 = 8080;
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(500);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// First byte of a tuning message, followed by the same JSON as `POST /config`.
+const TUNING_MESSAGE: u8 = b'T';
+
+#[embassy_executor::task]
+pub async fn udp_control_task(stack: &'static Stack<WifiDriver>) {
+    // The stack can come up without an IPv4 address, keep waiting for one
+    // rather than giving up.
+    let address = loop {
+        stack.wait_config_up().await;
+        match stack.config_v4() {
+            Some(config) => break config.address,
+            None => {
+                status::set(BootState::Fault(Fault::NoAddress));
+                Timer::after(RETRY_INTERVAL).await;
+            }
+        }
+    };
+    status::set(BootState::NetUp);
+    log::info!("IP Address appears to be: {}", address);
+    let mut ip = heapless::String::new();
+    let _ = write!(ip, "{}", address);
+    telemetry::update(|t| t.ip = Some(ip));
+
+    let mut udp_rx_meta = [PacketMetadata::EMPTY; 16];
+    let mut udp_rx_buffer = [0; 1024];
+    let mut udp_tx_meta = [PacketMetadata::EMPTY; 16];
+    let mut udp_tx_buffer = [0; 1024];
+    let mut msg_buffer = [0; 256];
+    let mut telemetry_buffer = [0; 512];
+
+    let mut udp_socket = UdpSocket::new(
+        stack,
+        &mut udp_rx_meta,
+        &mut udp_rx_buffer,
+        &mut udp_tx_meta,
+        &mut udp_tx_buffer,
+    );
+
+    while let Err(e) = udp_socket.bind(CONTROL_PORT) {
+        log::error!("Failed to bind control port: {:?}", e);
+        status::set(BootState::Fault(Fault::SocketBind));
+        Timer::after(RETRY_INTERVAL).await;
+    }
+    status::set(BootState::Ready);
+
+    let mut controller_addr = None;
+    let mut telemetry_ticker = Ticker::every(TELEMETRY_INTERVAL);
+    loop {
+        // Bind the event first so the receive future is dropped before we
+        // use the socket to send.
+        let event = select(
+            udp_socket.recv_from(&mut msg_buffer),
+            telemetry_ticker.next(),
+        )
+        .await;
+        let (rx_size, from_addr) = match event {
+            Either::First(Ok(received)) => received,
+            Either::First(Err(e)) => {
+                log::warn!("Failed to receive command: {:?}", e);
+                continue;
+            }
+            Either::Second(()) => {
+                // Back on the network after the connection task lost it.
+                if status::get() == BootState::WifiConnecting
+                    && esp_wifi::wifi::get_wifi_state() == WifiState::StaConnected
+                    && stack.is_config_up()
+                {
+                    status::set(BootState::Ready);
+                }
+                if let (Some(addr), Some(json)) =
+                    (controller_addr, telemetry::to_json(&mut telemetry_buffer))
+                {
+                    if let Err(e) = udp_socket.send_to(json, addr).await {
+                        log::warn!("Failed to send telemetry: {:?}", e);
+                    }
+                }
+                continue;
+            }
+        };
+        if controller_addr != Some(from_addr) {
+            // Tell every new controller about a crash before this boot.
+            if let Some(json) = crash::to_json(&mut telemetry_buffer) {
+                if let Err(e) = udp_socket.send_to(json, from_addr).await {
+                    log::warn!("Failed to send crash report: {:?}", e);
+                }
+            }
+        }
+        controller_addr = Some(from_addr);
+        metrics::UDP_PACKETS.inc();
+        if rx_size == 0 {
+            log::info!("Received empty message from {}", from_addr);
+            metrics::PARSE_ERRORS.inc();
+            continue;
+        }
+        // Tuning messages carry a drive configuration update, applied
+        // until the next reboot.
+        if let [TUNING_MESSAGE, json @ ..] = &msg_buffer[..rx_size] {
+            match control::update_config(json, false) {
+                Ok(config) => log::info!("Tuned drive configuration: {:?}", config),
+                Err(e) => log::warn!("Rejected tuning: {}", e),
+            }
+            continue;
+        }
+        if let [ACCESSORY_MESSAGE, text @ ..] = &msg_buffer[..rx_size] {
+            match AccessoryCommand::parse(text) {
+                Some(command) => {
+                    if !accessories::request(command) {
+                        log::warn!("Accessories busy, dropping {:?}", command);
+                    }
+                }
+                None => {
+                    log::info!("Unknown accessory command {:?}", text);
+                    metrics::PARSE_ERRORS.inc();
+                }
+            }
+            continue;
+        }
+        let response = msg_buffer[rx_size - 1];
+        match DriveCommand::parse(response) {
+            Some(command) => {
+                if !control::request(ControlRequest::Drive(command)) {
+                    log::warn!("Drive loop busy, dropping {:?}", command);
+                }
+            }
+            None => {
+                log::info!("Unknown command {}", response as char);
+                metrics::UNKNOWN_COMMANDS.inc();
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Hardware watchdog for the drive loop, using the RTC watchdog.
+//!
+//! The drive loop in `main.rs` feeds it at least once a second. If the
+//! executor stalls for [`TIMEOUT`] the first stage interrupt forces the motor
+//! outputs low, logs the last known state and saves it for the crash report,
+//! then the second stage resets the board [`RESET_DELAY`] later.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use esp_hal::macros::handler;
+use esp_hal::prelude::*;
+use esp_hal::rtc_cntl::{Rtc, Rwdt, RwdtStage, RwdtStageAction};
+
+use crate::{crash, motors, status, telemetry};
+
+const TIMEOUT: u64 = 3000;
+const RESET_DELAY: u64 = 500;
+
+static RWDT: Mutex<CriticalSectionRawMutex, RefCell<Option<Rwdt>>> = Mutex::new(RefCell::new(None));
+
+/// Takes the watchdog out of `rtc` and sets it up, [`start`] enables it.
+pub fn init(rtc: &mut Rtc<'static>) {
+    rtc.set_interrupt_handler(stalled);
+    let mut rwdt = core::mem::take(&mut rtc.rwdt);
+    rwdt.set_timeout(RwdtStage::Stage0, TIMEOUT.millis());
+    rwdt.listen();
+    rwdt.set_timeout(RwdtStage::Stage1, RESET_DELAY.millis());
+    rwdt.set_stage_action(RwdtStage::Stage1, RwdtStageAction::ResetSystem);
+    RWDT.lock(|watchdog| *watchdog.borrow_mut() = Some(rwdt));
+}
+
+/// Enables the watchdog, once the drive loop is about to run.
+pub fn start() {
+    RWDT.lock(|watchdog| {
+        if let Some(rwdt) = watchdog.borrow_mut().as_mut() {
+            rwdt.enable();
+            log::info!("Watchdog started, {}ms timeout", TIMEOUT);
+        }
+    });
+}
+
+pub fn feed() {
+    RWDT.lock(|watchdog| {
+        if let Some(rwdt) = watchdog.borrow_mut().as_mut() {
+            rwdt.feed();
+        }
+    });
+}
+
+/// Stops the watchdog, before deep sleep.
+pub fn stop() {
+    RWDT.lock(|watchdog| {
+        if let Some(rwdt) = watchdog.borrow_mut().as_mut() {
+            rwdt.disable();
+        }
+    });
+}
+
+#[handler]
+fn stalled() {
+    motors::force_stop();
+    let last = telemetry::snapshot();
+    esp_println::println!(
+        "Watchdog: drive loop stalled, motors were {} in state {}, forced off and resetting",
+        last.motors,
+        status::get().name()
+    );
+    crash::record_watchdog(format_args!(
+        "drive loop stalled, motors were {} in state {}",
+        last.motors,
+        status::get().name()
+    ));
+    RWDT.lock(|watchdog| {
+        if let Some(rwdt) = watchdog.borrow_mut().as_mut() {
+            rwdt.clear_interrupt();
+        }
+    });
+}
@@ -0,0 +1,173 @@
+//! Finds rovers and cameras from the beacons they broadcast every second, see
+//! `wifi_tank/src/beacon.rs` and `esp32cam/src/beacon.rs`.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+
+pub const BEACON_PORT: u16 = 8090;
+/// How long to listen per round, a bit more than the beacon interval.
+const GATHER_TIME: Duration = Duration::from_millis(1500);
+/// How long to keep looking for the rover's camera before driving without it.
+const CAMERA_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Rover,
+    Camera,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Beacon {
+    pub role: Role,
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    /// Control port, UDP for the rover and HTTP for the camera.
+    pub port: u16,
+    pub partner: Option<String>,
+}
+
+/// A board we received a beacon from.
+#[derive(Debug, Clone)]
+pub struct Board {
+    pub beacon: Beacon,
+    /// Where to reach the board's control port.
+    pub addr: SocketAddr,
+}
+
+impl Board {
+    fn matches(&self, filter: &str) -> bool {
+        self.beacon.id == filter || self.beacon.name == filter
+    }
+
+    fn is_paired_with(&self, other: &Board) -> bool {
+        self.beacon.partner.as_deref() == Some(other.beacon.id.as_str())
+            || other.beacon.partner.as_deref() == Some(self.beacon.id.as_str())
+    }
+}
+
+/// Listens for beacons, remembering the latest one from every board.
+pub struct Discovery {
+    socket: UdpSocket,
+    boards: BTreeMap<String, Board>,
+}
+
+impl Discovery {
+    pub async fn new() -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            socket: UdpSocket::bind(("0.0.0.0", BEACON_PORT)).await?,
+            boards: BTreeMap::new(),
+        })
+    }
+
+    /// Collects beacons for `duration`.
+    pub async fn gather(&mut self, duration: Duration) {
+        let mut buffer = [0u8; 512];
+        let deadline = tokio::time::Instant::now() + duration;
+        while let Ok(Ok((len, from))) =
+            tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buffer)).await
+        {
+            let Ok(beacon) = serde_json::from_slice::<Beacon>(&buffer[..len]) else {
+                continue;
+            };
+            let addr = SocketAddr::new(from.ip(), beacon.port);
+            self.boards
+                .insert(beacon.id.clone(), Board { beacon, addr });
+        }
+    }
+
+    pub fn boards(&self) -> impl Iterator<Item = &Board> {
+        self.boards.values()
+    }
+
+    fn with_role(&self, role: Role) -> Vec<&Board> {
+        self.boards().filter(|b| b.beacon.role == role).collect()
+    }
+
+    /// Waits for a rover (matching `filter` by ID or name if given), asking
+    /// the user to choose when there are several.
+    pub async fn choose_rover(&mut self, filter: Option<&str>) -> Result<Board, Box<dyn Error>> {
+        let rovers = loop {
+            self.gather(GATHER_TIME).await;
+            let rovers: Vec<Board> = self
+                .with_role(Role::Rover)
+                .into_iter()
+                .filter(|b| filter.is_none_or(|f| b.matches(f)))
+                .cloned()
+                .collect();
+            if !rovers.is_empty() {
+                break rovers;
+            }
+        };
+        if rovers.len() == 1 {
+            return Ok(rovers.into_iter().next().unwrap());
+        }
+
+        println!("Found several rovers:");
+        for (index, rover) in rovers.iter().enumerate() {
+            println!(
+                "  {}: {} ({}, {}, firmware {})",
+                index, rover.beacon.name, rover.beacon.id, rover.addr, rover.beacon.version
+            );
+        }
+        loop {
+            print!("Choose a rover: ");
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            match line
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| rovers.get(i))
+            {
+                Some(rover) => return Ok(rover.clone()),
+                None => println!("Enter a number between 0 and {}", rovers.len() - 1),
+            }
+        }
+    }
+
+    /// Finds the camera paired with `rover`, or the only camera if none are
+    /// paired. Gives up after a while so we can still drive without video.
+    pub async fn find_camera(&mut self, rover: &Board) -> Option<Board> {
+        let deadline = tokio::time::Instant::now() + CAMERA_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            let cameras = self.with_role(Role::Camera);
+            if let Some(camera) = cameras.iter().find(|c| c.is_paired_with(rover)) {
+                return Some((*camera).clone());
+            }
+            if let [camera] = cameras.as_slice() {
+                if camera.beacon.partner.is_none() {
+                    return Some((*camera).clone());
+                }
+            }
+            self.gather(GATHER_TIME).await;
+        }
+        None
+    }
+}
+
+/// Prints the boards found, for the `discover` subcommand.
+pub fn print_boards<'a>(boards: impl Iterator<Item = &'a Board>) {
+    for board in boards {
+        let role = match board.beacon.role {
+            Role::Rover => "rover",
+            Role::Camera => "camera",
+        };
+        print!(
+            "{:<7} {} id {} at {} firmware {}",
+            role, board.beacon.name, board.beacon.id, board.addr, board.beacon.version
+        );
+        if let Some(partner) = &board.beacon.partner {
+            print!(" paired with {}", partner);
+        }
+        println!();
+    }
+}
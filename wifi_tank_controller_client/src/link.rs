@@ -0,0 +1,126 @@
+//! How drive commands reach the rover: UDP over Wi-Fi, or through the
+//! ESP-NOW dongle (`wifi_tank_dongle`) on a serial port when there is no
+//! network.
+
+use std::error::Error;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, WriteHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::telemetry::Message;
+
+/// The dongle's USB serial baud rate, the ESP32 default.
+const DONGLE_BAUD_RATE: u32 = 115_200;
+
+pub enum Link {
+    Udp {
+        socket: UdpSocket,
+        peer: SocketAddr,
+    },
+    Dongle {
+        writer: WriteHalf<SerialStream>,
+        lines: mpsc::UnboundedReceiver<String>,
+    },
+}
+
+impl Link {
+    pub async fn udp(bind: &str, peer: SocketAddr) -> Result<Self, Box<dyn Error>> {
+        Ok(Link::Udp {
+            socket: UdpSocket::bind(bind).await?,
+            peer,
+        })
+    }
+
+    pub fn dongle(port: &str) -> Result<Self, Box<dyn Error>> {
+        let serial = tokio_serial::new(port, DONGLE_BAUD_RATE).open_native_async()?;
+        let (reader, writer) = tokio::io::split(serial);
+        let (sender, lines) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = reader.next_line().await {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Link::Dongle { writer, lines })
+    }
+
+    /// Sends one command byte (`F`, `B`, `L`, `R`, `N`, `Q`, `A`, or `1` to
+    /// `3` for the speed profile).
+    pub async fn send(&mut self, command: u8) -> Result<(), Box<dyn Error>> {
+        match self {
+            Link::Udp { socket, peer } => {
+                socket.send_to(&[command], *peer).await?;
+            }
+            Link::Dongle { writer, .. } => {
+                writer.write_all(&[command, b'\n']).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends an accessory command, e.g. `light 60`, `tone horn` or
+    /// `led ff8000`.
+    pub async fn send_accessory(&mut self, command: &str) -> Result<(), Box<dyn Error>> {
+        let mut message = vec![b'X'];
+        message.extend_from_slice(command.as_bytes());
+        match self {
+            Link::Udp { socket, peer } => {
+                socket.send_to(&message, *peer).await?;
+            }
+            Link::Dongle { writer, .. } => {
+                message.push(b'\n');
+                writer.write_all(&message).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a drive configuration update, the same JSON as the rover's
+    /// `POST /config`, applied until the rover reboots.
+    pub async fn send_tuning(&mut self, json: &str) -> Result<(), Box<dyn Error>> {
+        match self {
+            Link::Udp { socket, peer } => {
+                let mut message = vec![b'T'];
+                message.extend_from_slice(json.as_bytes());
+                socket.send_to(&message, *peer).await?;
+                Ok(())
+            }
+            Link::Dongle { .. } => Err("tuning is only supported over Wi-Fi".into()),
+        }
+    }
+
+    /// Returns the next message already received, without waiting.
+    pub fn try_message(&mut self) -> Option<Message> {
+        match self {
+            Link::Udp { socket, peer } => {
+                let mut buffer = [0u8; 1024];
+                while let Ok((len, from)) = socket.try_recv_from(&mut buffer) {
+                    if from != *peer {
+                        continue;
+                    }
+                    if let Some(message) = Message::parse(&buffer[..len]) {
+                        return Some(message);
+                    }
+                }
+            }
+            Link::Dongle { lines, .. } => {
+                // The dongle's log output shares the port, telemetry is
+                // always a JSON object on its own line.
+                while let Ok(line) = lines.try_recv() {
+                    if !line.starts_with('{') {
+                        continue;
+                    }
+                    if let Some(message) = Message::parse(line.as_bytes()) {
+                        return Some(message);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
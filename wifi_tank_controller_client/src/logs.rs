@@ -0,0 +1,58 @@
+//! Receives the log records both boards broadcast, see
+//! `wifi_tank/src/remote_log.rs` and `esp32cam/src/remote_log.rs`.
+
+use std::error::Error;
+
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+
+pub const LOG_PORT: u16 = 8091;
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    device: String,
+    board: String,
+    level: String,
+    target: String,
+    uptime_ms: u64,
+    msg: String,
+}
+
+impl Record {
+    fn print(&self) {
+        // ANSI colours, the same as the serial monitor uses.
+        let colour = match self.level.as_str() {
+            "ERROR" => "\x1b[31m",
+            "WARN" => "\x1b[33m",
+            "INFO" => "\x1b[32m",
+            _ => "\x1b[2m",
+        };
+        println!(
+            "{:>10.3} {:<7} {:<16} {}{:<5}\x1b[0m {}: {}",
+            self.uptime_ms as f64 / 1000.0,
+            self.board,
+            self.device,
+            colour,
+            self.level,
+            self.target,
+            self.msg
+        );
+    }
+}
+
+/// Prints records until interrupted, only from the device with this name if
+/// given.
+pub async fn follow(device: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let socket = UdpSocket::bind(("0.0.0.0", LOG_PORT)).await?;
+    println!("Listening for logs on UDP port {}", LOG_PORT);
+    let mut buffer = [0u8; 2048];
+    loop {
+        let (len, _) = socket.recv_from(&mut buffer).await?;
+        let Ok(record) = serde_json::from_slice::<Record>(&buffer[..len]) else {
+            continue;
+        };
+        if device.is_none_or(|device| record.device == device) {
+            record.print();
+        }
+    }
+}
@@ -1,14 +1,244 @@
+mod discovery;
+mod link;
+mod logs;
+mod mdns;
+mod provision;
+mod telemetry;
+mod update;
+
+use clap::{Parser, Subcommand, ValueEnum};
 use core::str;
-use std::env;
 use std::error::Error;
-use tokio::net::UdpSocket;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(
+    about = "Gamepad controller for the Wifi tank rover",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    /// Local address to bind the UDP control socket to
+    #[arg(default_value = "0.0.0.0:8080")]
+    bind: String,
+
+    /// Drive the rover with this device ID or name
+    #[arg(long)]
+    rover: Option<String>,
+
+    /// Drive over ESP-NOW through the dongle on this serial port
+    #[arg(long, conflicts_with = "rover")]
+    dongle: Option<String>,
+
+    /// Drive configuration JSON to send at the start, e.g. line following
+    /// gains: '{"line_kp": 50, "line_kd": 3}'
+    #[arg(long, conflicts_with = "dongle")]
+    tuning: Option<String>,
 
-const TARGET_HOSTNAME: &str = "wifitank:8080";
-const CAMERA_HOSTNAME: &str = "espressif:80";
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Drive the rover with a gamepad (the default)
+    Drive {
+        #[arg(default_value = "0.0.0.0:8080")]
+        bind: String,
+        /// Drive the rover with this device ID or name
+        #[arg(long)]
+        rover: Option<String>,
+        /// Drive over ESP-NOW through the dongle on this serial port
+        #[arg(long, conflicts_with = "rover")]
+        dongle: Option<String>,
+        /// Drive configuration JSON to send at the start
+        #[arg(long, conflicts_with = "dongle")]
+        tuning: Option<String>,
+    },
+    /// Send Wi-Fi credentials to a board over BLE
+    Provision {
+        ssid: String,
+        password: String,
+        /// New device name, also used as its hostname
+        #[arg(long)]
+        name: Option<String>,
+        /// Only provision the board advertising this BLE name
+        #[arg(long)]
+        device: Option<String>,
+        /// Seconds to scan for the board
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Erase the network stored on a board over BLE
+    Forget {
+        /// Only target the board advertising this BLE name
+        #[arg(long)]
+        device: Option<String>,
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// List the rovers and cameras advertised over mDNS and beacons
+    Discover {
+        /// Seconds to browse for
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+    /// Print the log records both boards broadcast over UDP
+    Logs {
+        /// Only show records from the board with this device name
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Push a firmware image (from `espflash save-image`) to a board over Wi-Fi
+    Update {
+        #[arg(value_enum)]
+        board: Board,
+        image: PathBuf,
+        /// Board hostname or address, defaults to the usual hostname
+        #[arg(long)]
+        host: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Board {
+    Rover,
+    Camera,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    use gilrs::{Button, Event, Gilrs};
+    let cli = Cli::parse();
+    match cli.command {
+        None => {
+            drive(
+                &cli.bind,
+                cli.rover.as_deref(),
+                cli.dongle.as_deref(),
+                cli.tuning.as_deref(),
+            )
+            .await
+        }
+        Some(Command::Drive {
+            bind,
+            rover,
+            dongle,
+            tuning,
+        }) => {
+            drive(
+                &bind,
+                rover.as_deref(),
+                dongle.as_deref(),
+                tuning.as_deref(),
+            )
+            .await
+        }
+        Some(Command::Provision {
+            ssid,
+            password,
+            name,
+            device,
+            timeout,
+        }) => {
+            let request = provision::ProvisioningRequest {
+                ssid,
+                password,
+                name,
+            };
+            request.validate()?;
+            let mut transport =
+                provision::BleTransport::connect(device.as_deref(), Duration::from_secs(timeout))
+                    .await?;
+            let status = provision::provision(&mut transport, &request).await;
+            transport.disconnect().await.ok();
+            println!("Board status: {}", status?);
+            Ok(())
+        }
+        Some(Command::Forget { device, timeout }) => {
+            let mut transport =
+                provision::BleTransport::connect(device.as_deref(), Duration::from_secs(timeout))
+                    .await?;
+            let status = provision::forget(&mut transport).await;
+            transport.disconnect().await.ok();
+            println!("Board status: {}", status?);
+            Ok(())
+        }
+        Some(Command::Discover { timeout }) => {
+            let duration = Duration::from_secs(timeout);
+            let mut discovery = discovery::Discovery::new().await?;
+            let (boards, ()) = tokio::join!(mdns::browse(duration), discovery.gather(duration));
+            println!("Over mDNS:");
+            mdns::print_boards(&boards?);
+            println!("From beacons:");
+            discovery::print_boards(discovery.boards());
+            Ok(())
+        }
+        Some(Command::Logs { device }) => logs::follow(device.as_deref()).await,
+        Some(Command::Update { board, image, host }) => {
+            let image = std::fs::read(image)?;
+            match board {
+                Board::Rover => {
+                    let host = host.as_deref().unwrap_or("wifitank");
+                    let addr = format!("{}:{}", host, update::ROVER_OTA_PORT);
+                    update::update_rover(&addr, &image).await?;
+                }
+                Board::Camera => {
+                    let host = host.as_deref().unwrap_or("espressif");
+                    update::update_camera(&format!("{}:80", host), &image).await?;
+                }
+            }
+            println!("Update complete, the board is rebooting");
+            Ok(())
+        }
+    }
+}
+
+/// Headlight brightness steps the East button cycles through, in percent.
+const HEADLIGHT_LEVELS: [u8; 3] = [0, 30, 100];
+/// RGB LED colours the West button cycles through, `status` shows the
+/// rover's boot state.
+const LED_COLOURS: [&str; 5] = ["status", "ff0000", "00ff00", "0000ff", "ffffff"];
+
+/// Speed profiles from slowest to fastest, with the command byte that picks
+/// each.
+const SPEED_PROFILES: [(&str, u8); 3] = [("turtle", b'1'), ("normal", b'2'), ("turbo", b'3')];
+
+/// Right stick deflection below this is ignored, worn sticks don't return
+/// exactly to center.
+const STICK_DEAD_ZONE: f32 = 0.15;
+
+/// Short rumble for when one of the rover's cutoffs or safety stops engages,
+/// `None` if the gamepad has no force feedback.
+fn obstacle_rumble(gilrs: &mut gilrs::Gilrs, id: gilrs::GamepadId) -> Option<gilrs::ff::Effect> {
+    use gilrs::ff::{BaseEffect, BaseEffectType, EffectBuilder, Repeat, Replay, Ticks};
+
+    if !gilrs.gamepad(id).is_ff_supported() {
+        println!("Gamepad has no force feedback, obstacles won't rumble");
+        return None;
+    }
+    let duration = Ticks::from_ms(300);
+    EffectBuilder::new()
+        .add_effect(BaseEffect {
+            kind: BaseEffectType::Strong { magnitude: 60_000 },
+            scheduling: Replay {
+                play_for: duration,
+                ..Default::default()
+            },
+            envelope: Default::default(),
+        })
+        .gamepads(&[id])
+        .repeat(Repeat::For(duration))
+        .finish(gilrs)
+        .ok()
+}
+
+async fn drive(
+    addr: &str,
+    rover: Option<&str>,
+    dongle: Option<&str>,
+    tuning: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    use gilrs::{Axis, Button, Event, EventType, Gilrs};
 
     let mut gilrs = Gilrs::new().unwrap();
 
@@ -31,65 +261,173 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "0.0.0.0:8080".to_string());
-
-    println!("Waiting for connection to rover: {}", TARGET_HOSTNAME);
+    let mut link = match dongle {
+        // No network, so no discovery and no camera either.
+        Some(port) => {
+            println!("Driving through the ESP-NOW dongle on {}", port);
+            link::Link::dongle(port)?
+        }
+        None => {
+            println!("Looking for rovers");
+            let mut discovery = discovery::Discovery::new().await?;
+            let rover = discovery.choose_rover(rover).await?;
+            println!(
+                "Connected to rover {} ({}): {}",
+                rover.beacon.name, rover.beacon.id, rover.addr
+            );
+            let link = link::Link::udp(addr, rover.addr).await?;
 
-    let socket = UdpSocket::bind(&addr).await?;
-    let peer = loop {
-        if let Ok(p) = tokio::net::lookup_host(TARGET_HOSTNAME).await {
-            if let Some(pi) = p.into_iter().next() {
-                break pi;
+            println!("Looking for the rover's camera");
+            match discovery.find_camera(&rover).await {
+                Some(camera) => {
+                    println!(
+                        "Connected to camera {}: {}",
+                        camera.beacon.name, camera.addr
+                    );
+                    let cam_url = format!("http://{}", camera.addr);
+                    std::process::Command::new("firefox")
+                        .arg("-kiosk")
+                        .arg(cam_url.as_str())
+                        .spawn()
+                        .ok();
+                }
+                None => println!("No camera found, driving without video"),
             }
+            link
         }
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
     };
-    println!("Connected to rover:  {}", peer);
-
-    // TODO: Move to other thread?
-    println!("Waiting for connection to camera: {}", TARGET_HOSTNAME);
-    let camera_peer = loop {
-        if let Ok(p) = tokio::net::lookup_host(CAMERA_HOSTNAME).await {
-            if let Some(pi) = p.into_iter().next() {
-                break pi;
+
+    if let Some(tuning) = tuning {
+        link.send_tuning(tuning).await?;
+        println!("Sent tuning {}", tuning);
+    }
+
+    let rumble = obstacle_rumble(&mut gilrs, active_gamepad.expect("Gamepad not found!"));
+    let mut telemetry = telemetry::Telemetry::default();
+    let mut following = false;
+    let mut headlights = 0;
+    let mut led_colour = 0;
+    let mut looking = false;
+    loop {
+        while let Some(message) = link.try_message() {
+            match message {
+                telemetry::Message::Telemetry(update) => {
+                    update.print_changes(&telemetry);
+                    if (update.obstacle_ahead() && !telemetry.obstacle_ahead())
+                        || (update.tilted && !telemetry.tilted)
+                        || update.safety_event_since(&telemetry)
+                    {
+                        if let Some(rumble) = &rumble {
+                            rumble.play().ok();
+                        }
+                    }
+                    telemetry = *update;
+                }
+                telemetry::Message::Crash(report) => report.print(),
             }
         }
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-    };
-    println!("Connected to camera: {}", camera_peer);
-    let cam_url = format!("http://{}", CAMERA_HOSTNAME.split(':').next().unwrap());
-    std::process::Command::new("firefox")
-        .arg("-kiosk")
-        .arg(cam_url.as_str())
-        .spawn()
-        .ok();
 
-    loop {
         while let Some(Event {
             id, event, time, ..
         }) = gilrs.next_event()
         // TODO: Make this real async?
         {
             println!("{:?} New event from {}: {:?}", time, id, event);
+            let EventType::ButtonPressed(button, _) = event else {
+                continue;
+            };
+            let accessory = match button {
+                Button::Start => {
+                    following = !following;
+                    println!(
+                        "Line following {}",
+                        if following { "started" } else { "stopped" }
+                    );
+                    None
+                }
+                // The shoulder buttons step through the speed profiles, from
+                // the one the rover reports.
+                Button::LeftTrigger | Button::RightTrigger => {
+                    let current = SPEED_PROFILES
+                        .iter()
+                        .position(|(name, _)| telemetry.profile.as_deref() == Some(*name))
+                        .unwrap_or(1);
+                    let next = if button == Button::RightTrigger {
+                        (current + 1).min(SPEED_PROFILES.len() - 1)
+                    } else {
+                        current.saturating_sub(1)
+                    };
+                    let (name, command) = SPEED_PROFILES[next];
+                    println!("Asking for speed profile {}", name);
+                    link.send(command).await?;
+                    None
+                }
+                Button::RightThumb => Some("center".to_string()),
+                Button::South => Some("tone horn".to_string()),
+                Button::North => Some("tone chirp".to_string()),
+                Button::East => {
+                    headlights = (headlights + 1) % HEADLIGHT_LEVELS.len();
+                    Some(format!("light {}", HEADLIGHT_LEVELS[headlights]))
+                }
+                Button::West => {
+                    led_colour = (led_colour + 1) % LED_COLOURS.len();
+                    Some(format!("led {}", LED_COLOURS[led_colour]))
+                }
+                _ => None,
+            };
+            if let Some(accessory) = accessory {
+                link.send_accessory(&accessory).await?;
+            }
         }
 
         {
             let gamepad = gilrs.gamepad(active_gamepad.expect("Gamepad not found!"));
+            // The rover hands back control on any manual command, so stop
+            // asking it to follow the line.
+            let manual = [
+                Button::DPadUp,
+                Button::DPadDown,
+                Button::DPadLeft,
+                Button::DPadRight,
+            ]
+            .into_iter()
+            .any(|button| gamepad.is_pressed(button));
+            if manual && following {
+                following = false;
+                println!("Line following stopped, manual control");
+            }
+
+            // The rover turns the camera gimbal while the right stick is
+            // held, and stops shortly after the last `look`.
+            let stick = [Axis::RightStickX, Axis::RightStickY].map(|axis| {
+                let value = gamepad.value(axis);
+                if value.abs() < STICK_DEAD_ZONE {
+                    0
+                } else {
+                    (value * 100.0).round() as i8
+                }
+            });
+            if stick != [0, 0] || looking {
+                link.send_accessory(&format!("look {} {}", stick[0], stick[1]))
+                    .await?;
+                looking = stick != [0, 0];
+            }
+
             if gamepad.is_pressed(Button::DPadUp) {
-                socket.send_to(b"F", &peer).await?;
+                link.send(b'F').await?;
             } else if gamepad.is_pressed(Button::DPadDown) {
-                socket.send_to(b"B", &peer).await?;
+                link.send(b'B').await?;
             } else if gamepad.is_pressed(Button::DPadLeft) {
-                socket.send_to(b"L", &peer).await?;
+                link.send(b'L').await?;
             } else if gamepad.is_pressed(Button::DPadRight) {
-                socket.send_to(b"R", &peer).await?;
+                link.send(b'R').await?;
             } else if gamepad.is_pressed(Button::Select) {
-                socket.send_to(b"Q", &peer).await?;
+                link.send(b'Q').await?;
                 break;
+            } else if following {
+                link.send(b'A').await?;
             } else {
-                socket.send_to(b"N", &peer).await?;
+                link.send(b'N').await?;
             }
         }
 
@@ -0,0 +1,90 @@
+//! Finds rovers and cameras on the local network with mDNS/DNS-SD, see
+//! `wifi_tank/src/mdns.rs` and `esp32cam/src/mdns.rs`.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+
+/// The rover's UDP control service.
+const CONTROL_SERVICE: &str = "_wifitank._udp.local.";
+/// HTTP servers, both boards run one (with a `board` TXT record).
+const HTTP_SERVICE: &str = "_http._tcp.local.";
+
+#[derive(Debug, Clone, Default)]
+pub struct Board {
+    /// `rover` or `camera`, from the `board` TXT record.
+    pub kind: String,
+    /// mDNS hostname, like `wifitank.local.`.
+    pub hostname: String,
+    pub addresses: Vec<IpAddr>,
+    pub control_port: Option<u16>,
+    pub http_port: Option<u16>,
+}
+
+/// Browses for `duration` and returns every board found, keyed by hostname.
+pub async fn browse(duration: Duration) -> Result<Vec<Board>, Box<dyn Error>> {
+    let daemon = ServiceDaemon::new()?;
+    let control = daemon.browse(CONTROL_SERVICE)?;
+    let http = daemon.browse(HTTP_SERVICE)?;
+
+    let mut boards: BTreeMap<String, Board> = BTreeMap::new();
+    let deadline = tokio::time::Instant::now() + duration;
+    loop {
+        let event = tokio::select! {
+            event = control.recv_async() => event,
+            event = http.recv_async() => event,
+            _ = tokio::time::sleep_until(deadline) => break,
+        };
+        // The receivers only fail once the daemon has stopped, and would
+        // then fail straight away every time.
+        let Ok(event) = event else {
+            break;
+        };
+        let ServiceEvent::ServiceResolved(info) = event else {
+            continue;
+        };
+        // Other HTTP servers on the network don't have the TXT record.
+        let Some(kind) = info.get_property_val_str("board") else {
+            continue;
+        };
+        let board = boards.entry(info.get_hostname().to_string()).or_default();
+        board.kind = kind.to_string();
+        board.hostname = info.get_hostname().to_string();
+        board.addresses = info.get_addresses().iter().copied().collect();
+        board.addresses.sort();
+        if info.get_type() == CONTROL_SERVICE {
+            board.control_port = Some(info.get_port());
+        } else {
+            board.http_port = Some(info.get_port());
+        }
+    }
+    daemon.shutdown().ok();
+    Ok(boards.into_values().collect())
+}
+
+/// Prints the boards found by [`browse`].
+pub fn print_boards(boards: &[Board]) {
+    if boards.is_empty() {
+        println!("No rovers or cameras found");
+        return;
+    }
+    for board in boards {
+        let addresses: Vec<String> = board.addresses.iter().map(|a| a.to_string()).collect();
+        print!(
+            "{:<7} {} ({})",
+            board.kind,
+            board.hostname,
+            addresses.join(", ")
+        );
+        if let Some(port) = board.control_port {
+            print!(" control port {}", port);
+        }
+        if let Some(port) = board.http_port {
+            print!(" http port {}", port);
+        }
+        println!();
+    }
+}
@@ -0,0 +1,331 @@
+//! Host side of the BLE provisioning GATT service implemented by both boards.
+//!
+//! The service is a handful of write characteristics plus a readable status,
+//! see the README for the UUIDs. Values are written in the order SSID,
+//! password, (optional) device name and finally a commit command, after which
+//! the board reports its status and reboots onto the new network.
+
+use std::error::Error;
+use std::time::Duration;
+
+use btleplug::api::{
+    Central, Characteristic, Manager as _, Peripheral as _, ScanFilter, WriteType,
+};
+use btleplug::platform::{Manager, Peripheral};
+use uuid::Uuid;
+
+pub const SERVICE_UUID: Uuid = Uuid::from_u128(0x7e5a0001_2f4b_4c3a_9b8e_1f2d3c4b5a60);
+pub const SSID_UUID: Uuid = Uuid::from_u128(0x7e5a0002_2f4b_4c3a_9b8e_1f2d3c4b5a60);
+pub const PASSWORD_UUID: Uuid = Uuid::from_u128(0x7e5a0003_2f4b_4c3a_9b8e_1f2d3c4b5a60);
+pub const NAME_UUID: Uuid = Uuid::from_u128(0x7e5a0004_2f4b_4c3a_9b8e_1f2d3c4b5a60);
+pub const COMMAND_UUID: Uuid = Uuid::from_u128(0x7e5a0005_2f4b_4c3a_9b8e_1f2d3c4b5a60);
+pub const STATUS_UUID: Uuid = Uuid::from_u128(0x7e5a0006_2f4b_4c3a_9b8e_1f2d3c4b5a60);
+
+pub const COMMAND_COMMIT: u8 = 0x01;
+pub const COMMAND_FORGET: u8 = 0x02;
+
+const MAX_SSID_LENGTH: usize = 32;
+const MAX_PASSWORD_LENGTH: usize = 64;
+const MAX_NAME_LENGTH: usize = 32;
+
+/// The GATT operations the provisioning protocol needs, so the protocol can
+/// run against a real BLE peripheral or an in-memory mock.
+pub trait ProvisioningTransport {
+    async fn write(&mut self, characteristic: Uuid, value: &[u8]) -> Result<(), Box<dyn Error>>;
+    async fn read(&mut self, characteristic: Uuid) -> Result<Vec<u8>, Box<dyn Error>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct ProvisioningRequest {
+    pub ssid: String,
+    pub password: String,
+    pub name: Option<String>,
+}
+
+impl ProvisioningRequest {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.ssid.is_empty() || self.ssid.len() > MAX_SSID_LENGTH {
+            return Err(format!("SSID must be between 1 and {} bytes", MAX_SSID_LENGTH).into());
+        }
+        if self.password.len() > MAX_PASSWORD_LENGTH {
+            return Err(format!("Password must be at most {} bytes", MAX_PASSWORD_LENGTH).into());
+        }
+        if let Some(name) = &self.name {
+            if name.is_empty()
+                || name.len() > MAX_NAME_LENGTH
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                return Err(format!(
+                    "Device name must be 1 to {} characters of a-z, 0-9 and -",
+                    MAX_NAME_LENGTH
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends the credentials and commits them, returning the status reported by
+/// the board.
+pub async fn provision<T: ProvisioningTransport>(
+    transport: &mut T,
+    request: &ProvisioningRequest,
+) -> Result<String, Box<dyn Error>> {
+    request.validate()?;
+    transport.write(SSID_UUID, request.ssid.as_bytes()).await?;
+    transport
+        .write(PASSWORD_UUID, request.password.as_bytes())
+        .await?;
+    if let Some(name) = &request.name {
+        transport.write(NAME_UUID, name.as_bytes()).await?;
+    }
+    transport.write(COMMAND_UUID, &[COMMAND_COMMIT]).await?;
+    read_status(transport).await
+}
+
+/// Asks the board to erase its stored network and fall back to its defaults.
+pub async fn forget<T: ProvisioningTransport>(transport: &mut T) -> Result<String, Box<dyn Error>> {
+    transport.write(COMMAND_UUID, &[COMMAND_FORGET]).await?;
+    read_status(transport).await
+}
+
+async fn read_status<T: ProvisioningTransport>(
+    transport: &mut T,
+) -> Result<String, Box<dyn Error>> {
+    let status = String::from_utf8(transport.read(STATUS_UUID).await?)?;
+    if let Some(err) = status.strip_prefix("error:") {
+        return Err(format!("Board rejected provisioning: {}", err.trim()).into());
+    }
+    Ok(status)
+}
+
+pub struct BleTransport {
+    peripheral: Peripheral,
+    characteristics: Vec<Characteristic>,
+}
+
+impl BleTransport {
+    /// Scans for a board advertising the provisioning service, optionally
+    /// filtered by its advertised name, and connects to the first match.
+    pub async fn connect(name: Option<&str>, timeout: Duration) -> Result<Self, Box<dyn Error>> {
+        let manager = Manager::new().await?;
+        let adapter = manager
+            .adapters()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or("No Bluetooth adapter found")?;
+
+        adapter
+            .start_scan(ScanFilter {
+                services: vec![SERVICE_UUID],
+            })
+            .await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        let peripheral = 'scan: loop {
+            for peripheral in adapter.peripherals().await? {
+                let Some(properties) = peripheral.properties().await? else {
+                    continue;
+                };
+                if !properties.services.contains(&SERVICE_UUID) {
+                    continue;
+                }
+                let local_name = properties.local_name.unwrap_or_default();
+                if name.is_none_or(|name| local_name == name) {
+                    println!("Found {} ({})", local_name, properties.address);
+                    break 'scan peripheral;
+                }
+            }
+            if tokio::time::Instant::now() > deadline {
+                adapter.stop_scan().await.ok();
+                return Err("No board advertising the provisioning service was found".into());
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        };
+        adapter.stop_scan().await.ok();
+
+        peripheral.connect().await?;
+        peripheral.discover_services().await?;
+        let characteristics = peripheral
+            .characteristics()
+            .into_iter()
+            .filter(|c| c.service_uuid == SERVICE_UUID)
+            .collect();
+        Ok(Self {
+            peripheral,
+            characteristics,
+        })
+    }
+
+    fn characteristic(&self, uuid: Uuid) -> Result<&Characteristic, Box<dyn Error>> {
+        self.characteristics
+            .iter()
+            .find(|c| c.uuid == uuid)
+            .ok_or_else(|| format!("Board is missing characteristic {}", uuid).into())
+    }
+
+    pub async fn disconnect(self) -> Result<(), Box<dyn Error>> {
+        Ok(self.peripheral.disconnect().await?)
+    }
+}
+
+impl ProvisioningTransport for BleTransport {
+    async fn write(&mut self, characteristic: Uuid, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        let characteristic = self.characteristic(characteristic)?;
+        self.peripheral
+            .write(characteristic, value, WriteType::WithResponse)
+            .await?;
+        Ok(())
+    }
+
+    async fn read(&mut self, characteristic: Uuid) -> Result<Vec<u8>, Box<dyn Error>> {
+        let characteristic = self.characteristic(characteristic)?;
+        Ok(self.peripheral.read(characteristic).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Records writes and answers status reads from a queue.
+    #[derive(Default)]
+    struct MockTransport {
+        writes: Vec<(Uuid, Vec<u8>)>,
+        statuses: VecDeque<&'static str>,
+    }
+
+    impl MockTransport {
+        fn answering(status: &'static str) -> Self {
+            Self {
+                statuses: VecDeque::from([status]),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl ProvisioningTransport for MockTransport {
+        async fn write(
+            &mut self,
+            characteristic: Uuid,
+            value: &[u8],
+        ) -> Result<(), Box<dyn Error>> {
+            self.writes.push((characteristic, value.to_vec()));
+            Ok(())
+        }
+
+        async fn read(&mut self, characteristic: Uuid) -> Result<Vec<u8>, Box<dyn Error>> {
+            assert_eq!(characteristic, STATUS_UUID);
+            let status = self.statuses.pop_front().ok_or("no status queued")?;
+            Ok(status.as_bytes().to_vec())
+        }
+    }
+
+    fn request(ssid: &str, password: &str, name: Option<&str>) -> ProvisioningRequest {
+        ProvisioningRequest {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+            name: name.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn provision_writes_credentials_then_commits() {
+        let mut transport = MockTransport::answering("ok: connecting to Home");
+        let status = provision(&mut transport, &request("Home", "secret", Some("rover-1")))
+            .await
+            .unwrap();
+        assert_eq!(status, "ok: connecting to Home");
+        assert_eq!(
+            transport.writes,
+            vec![
+                (SSID_UUID, b"Home".to_vec()),
+                (PASSWORD_UUID, b"secret".to_vec()),
+                (NAME_UUID, b"rover-1".to_vec()),
+                (COMMAND_UUID, vec![COMMAND_COMMIT]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn provision_sends_maximum_length_name() {
+        // Longer than fits a BLE scan response, the board shortens it there.
+        let name = "n".repeat(MAX_NAME_LENGTH);
+        let mut transport = MockTransport::answering("ok: saved, rebooting");
+        provision(&mut transport, &request("Home", "secret", Some(&name)))
+            .await
+            .unwrap();
+        assert!(transport
+            .writes
+            .contains(&(NAME_UUID, name.as_bytes().to_vec())));
+    }
+
+    #[tokio::test]
+    async fn provision_without_name_skips_it() {
+        let mut transport = MockTransport::answering("ok");
+        provision(&mut transport, &request("Home", "", None))
+            .await
+            .unwrap();
+        assert!(transport.writes.iter().all(|(uuid, _)| *uuid != NAME_UUID));
+        assert_eq!(
+            transport.writes.last(),
+            Some(&(COMMAND_UUID, vec![COMMAND_COMMIT]))
+        );
+    }
+
+    #[tokio::test]
+    async fn provision_rejects_invalid_request_without_writing() {
+        let mut transport = MockTransport::default();
+        assert!(provision(&mut transport, &request("", "secret", None))
+            .await
+            .is_err());
+        assert!(transport.writes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn forget_sends_forget_command() {
+        let mut transport = MockTransport::answering("ok: forgotten");
+        let status = forget(&mut transport).await.unwrap();
+        assert_eq!(status, "ok: forgotten");
+        assert_eq!(transport.writes, vec![(COMMAND_UUID, vec![COMMAND_FORGET])]);
+    }
+
+    #[tokio::test]
+    async fn error_status_is_an_error() {
+        let mut transport = MockTransport::answering("error: SSID not set");
+        let err = forget(&mut transport).await.unwrap_err();
+        assert_eq!(err.to_string(), "Board rejected provisioning: SSID not set");
+
+        let mut transport = MockTransport::answering("error: bad password");
+        assert!(provision(&mut transport, &request("Home", "secret", None))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn validate_ssid_length() {
+        assert!(request("", "", None).validate().is_err());
+        assert!(request(&"s".repeat(32), "", None).validate().is_ok());
+        assert!(request(&"s".repeat(33), "", None).validate().is_err());
+    }
+
+    #[test]
+    fn validate_password_length() {
+        assert!(request("Home", "", None).validate().is_ok());
+        assert!(request("Home", &"p".repeat(64), None).validate().is_ok());
+        assert!(request("Home", &"p".repeat(65), None).validate().is_err());
+    }
+
+    #[test]
+    fn validate_device_name() {
+        assert!(request("Home", "", Some("rover-1")).validate().is_ok());
+        assert!(request("Home", "", Some("")).validate().is_err());
+        assert!(request("Home", "", Some("rover 1")).validate().is_err());
+        assert!(request("Home", "", Some(&"n".repeat(33)))
+            .validate()
+            .is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use serde::Deserialize;
+
+/// Telemetry the rover sends back to the controller as JSON, see
+/// `wifi_tank/src/telemetry.rs`. Unknown fields are ignored and missing ones
+/// default so older or newer firmware still parses.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Telemetry {
+    /// Random for every boot of the rover, 0 until its radio is up.
+    pub session: Option<u32>,
+    pub state: Option<String>,
+    pub fault: Option<String>,
+    pub network: Option<String>,
+    pub rssi: Option<i8>,
+    pub hostname: Option<String>,
+    pub ip: Option<String>,
+    pub ip_mode: Option<String>,
+    pub wake_cause: Option<String>,
+    pub reset_reason: Option<String>,
+    /// Speed profile, `turtle`, `normal` or `turbo`.
+    pub profile: Option<String>,
+    /// `clear`, `near` (forward is slowed) or `blocked` (forward is refused).
+    pub obstacle: Option<String>,
+    pub ranges: Vec<Range>,
+    /// From the IMU, in degrees.
+    pub heading: Option<u16>,
+    pub pitch: Option<i16>,
+    pub roll: Option<i16>,
+    /// The motors are cut because the rover tipped over.
+    pub tilted: bool,
+    /// The last safety event, like `cliff_left`.
+    pub safety: Option<String>,
+    /// Safety events since the rover booted.
+    pub safety_events: u16,
+}
+
+/// One rangefinder reading.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Range {
+    pub sensor: String,
+    /// `None` if nothing is in range.
+    pub cm: Option<u16>,
+}
+
+/// Crash report the rover sends once to each new controller, see
+/// `wifi_tank/src/crash.rs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CrashReport {
+    pub reason: String,
+    pub message: Option<String>,
+    #[serde(default)]
+    pub backtrace: Vec<u32>,
+}
+
+impl CrashReport {
+    pub fn print(&self) {
+        println!("Rover crashed before this boot: {}", self.reason);
+        if let Some(message) = &self.message {
+            println!("  {}", message);
+        }
+        if !self.backtrace.is_empty() {
+            let addresses: Vec<String> = self
+                .backtrace
+                .iter()
+                .map(|address| format!("0x{:08x}", address))
+                .collect();
+            println!("  backtrace: {}", addresses.join(" "));
+        }
+    }
+}
+
+/// Everything the rover sends to the controller.
+pub enum Message {
+    Telemetry(Box<Telemetry>),
+    Crash(CrashReport),
+}
+
+impl Message {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct Crash {
+            crash: CrashReport,
+        }
+        // Check for the crash report first, telemetry accepts any object.
+        if let Ok(Crash { crash }) = serde_json::from_slice(data) {
+            return Some(Message::Crash(crash));
+        }
+        Telemetry::parse(data).map(|telemetry| Message::Telemetry(Box::new(telemetry)))
+    }
+}
+
+impl Telemetry {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+
+    /// Whether a safety event happened since the previous telemetry.
+    pub fn safety_event_since(&self, previous: &Telemetry) -> bool {
+        self.safety_events != previous.safety_events && self.safety.is_some()
+    }
+
+    /// Whether the rover is slowing or refusing forward motion.
+    pub fn obstacle_ahead(&self) -> bool {
+        matches!(self.obstacle.as_deref(), Some("near" | "blocked"))
+    }
+
+    /// Prints the fields that changed compared to the previous telemetry.
+    pub fn print_changes(&self, previous: &Telemetry) {
+        if let (Some(session), Some(before)) = (self.session, previous.session) {
+            if session != before {
+                println!("Rover restarted");
+            }
+        }
+        if self.state != previous.state {
+            if let Some(state) = &self.state {
+                println!("Rover state {}", state);
+            }
+        }
+        if self.fault != previous.fault {
+            if let Some(fault) = &self.fault {
+                println!("Rover fault: {}", fault);
+            }
+        }
+        if self.network != previous.network {
+            match &self.network {
+                Some(network) => println!("Rover connected to network {}", network),
+                None => println!("Rover lost its network connection"),
+            }
+        }
+        if (&self.hostname, &self.ip, &self.ip_mode)
+            != (&previous.hostname, &previous.ip, &previous.ip_mode)
+        {
+            if let (Some(hostname), Some(ip)) = (&self.hostname, &self.ip) {
+                let mode = self.ip_mode.as_deref().unwrap_or("unknown");
+                println!("Rover is {} at {} ({})", hostname, ip, mode);
+            }
+        }
+        if self.profile != previous.profile {
+            if let Some(profile) = &self.profile {
+                println!("Speed profile {}", profile);
+            }
+        }
+        if self.obstacle != previous.obstacle {
+            match self.obstacle.as_deref() {
+                Some("near") => println!("Obstacle ahead, forward slowed"),
+                Some("blocked") => println!("Obstacle ahead, forward refused"),
+                Some("clear") if previous.obstacle_ahead() => println!("Way ahead clear"),
+                _ => {}
+            }
+        }
+        if self.ranges != previous.ranges && !self.ranges.is_empty() {
+            let ranges: Vec<String> = self
+                .ranges
+                .iter()
+                .map(|range| match range.cm {
+                    Some(cm) => format!("{} {} cm", range.sensor, cm),
+                    None => format!("{} clear", range.sensor),
+                })
+                .collect();
+            println!("Distances: {}", ranges.join(", "));
+        }
+        if self.tilted != previous.tilted {
+            if self.tilted {
+                println!(
+                    "Rover tipped over (pitch {}°, roll {}°), motors cut",
+                    self.pitch.unwrap_or_default(),
+                    self.roll.unwrap_or_default()
+                );
+            } else {
+                println!("Rover upright again");
+            }
+        }
+        if self.safety_event_since(previous) {
+            if let Some(safety) = &self.safety {
+                println!("Rover safety stop: {}", safety);
+            }
+        }
+        if self.reset_reason != previous.reset_reason {
+            if let Some(reason) = &self.reset_reason {
+                println!("Rover last reset by {}", reason);
+            }
+        }
+        if self.wake_cause != previous.wake_cause {
+            if let Some(cause) = &self.wake_cause {
+                println!("Rover started by {}", cause);
+            }
+        }
+    }
+}
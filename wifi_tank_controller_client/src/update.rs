@@ -0,0 +1,81 @@
+//! Pushes firmware images to the boards, see `wifi_tank/src/ota.rs` and
+//! `esp32cam/src/ota.rs` for the other side.
+//!
+//! Images are the raw app binaries produced by `espflash save-image`.
+
+use std::error::Error;
+use std::io::Write as _;
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+pub const ROVER_OTA_PORT: u16 = 8081;
+
+const HEADER_MAGIC: &[u8; 6] = b"WTOTA1";
+const IMAGE_MAGIC: u8 = 0xe9;
+const CHUNK_SIZE: usize = 4096;
+
+fn check_image(image: &[u8]) -> Result<(), Box<dyn Error>> {
+    if image.first() != Some(&IMAGE_MAGIC) {
+        return Err("not an ESP app image, create one with `espflash save-image`".into());
+    }
+    Ok(())
+}
+
+async fn read_reply(reader: &mut BufReader<TcpStream>) -> Result<(), Box<dyn Error>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    match line.trim() {
+        "OK" => Ok(()),
+        "" => Err("rover closed the connection".into()),
+        reply => Err(format!("rover rejected the update: {}", reply).into()),
+    }
+}
+
+/// Sends `image` to the rover's OTA task, which reboots into it on success.
+pub async fn update_rover(addr: &str, image: &[u8]) -> Result<(), Box<dyn Error>> {
+    check_image(image)?;
+    let mut header = Vec::with_capacity(42);
+    header.extend_from_slice(HEADER_MAGIC);
+    header.extend_from_slice(&u32::try_from(image.len())?.to_le_bytes());
+    header.extend_from_slice(&Sha256::digest(image));
+
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+    stream.get_mut().write_all(&header).await?;
+    read_reply(&mut stream).await?;
+
+    for (i, chunk) in image.chunks(CHUNK_SIZE).enumerate() {
+        stream.get_mut().write_all(chunk).await?;
+        let sent = (i * CHUNK_SIZE + chunk.len()) * 100 / image.len();
+        print!("\rSent {}%", sent);
+        std::io::stdout().flush().ok();
+    }
+    println!();
+    println!("Waiting for the rover to verify the image");
+    read_reply(&mut stream).await?;
+    Ok(())
+}
+
+/// Uploads `image` to the camera's `/update` endpoint.
+pub async fn update_camera(addr: &str, image: &[u8]) -> Result<(), Box<dyn Error>> {
+    check_image(image)?;
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = format!(
+        "POST /update HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        addr,
+        image.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(image).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let status = response.lines().next().unwrap_or_default();
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("camera rejected the update: {} {}", status, body).into());
+    }
+    println!("{}", body);
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! USB dongle that bridges the client's serial port to the rover over ESP-NOW,
+//! for driving where there is no Wi-Fi network.
+//!
+//! Every line from the host is either a single command byte (`F`, `B`, `L`,
+//! `R`, `N`, `Q`, `A`, `1` to `3`, the same as over UDP), an accessory message (`X` and a
+//! text command, e.g. `Xtone horn`) or `channel <n>` to follow a rover that
+//! is connected to an access point on another channel. Telemetry from
+//! the rover is written back as one JSON object per line, log lines never
+//! start with `{` so the client can tell them apart.
+//!
+//! The frame format is shared with `wifi_tank/src/espnow.rs`.
+
+#![no_std]
+#![no_main]
+
+use core::mem::MaybeUninit;
+
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use esp_backtrace as _;
+use esp_hal::{prelude::*, rng::Rng, timer::timg::TimerGroup, uart::UartRx};
+use esp_wifi::esp_now::{EspNow, EspNowManager, EspNowSender, PeerInfo, BROADCAST_ADDRESS};
+
+use esp_alloc as _;
+
+const FRAME_MAGIC: [u8; 2] = *b"WT";
+const FRAME_COMMAND: u8 = b'C';
+const FRAME_ACCESSORY: u8 = b'X';
+const FRAME_TELEMETRY: u8 = b'T';
+const MAX_LINE_LENGTH: usize = 32;
+
+fn init_heap() {
+    const HEAP_SIZE: usize = 64 * 1024;
+    static mut HEAP: MaybeUninit<[u8; HEAP_SIZE]> = MaybeUninit::uninit();
+
+    unsafe {
+        esp_alloc::HEAP.add_region(esp_alloc::HeapRegion::new(
+            HEAP.as_mut_ptr() as *mut u8,
+            HEAP_SIZE,
+            esp_alloc::MemoryCapability::Internal.into(),
+        ));
+    }
+}
+
+/// Handles one line from the host. Commands go to the rover once we have
+/// heard from it, broadcast until then.
+async fn handle_line(
+    line: &str,
+    manager: &EspNowManager<'_>,
+    sender: &mut EspNowSender<'_>,
+    rover: Option<[u8; 6]>,
+) {
+    let line = line.trim();
+    if let Some(channel) = line.strip_prefix("channel ") {
+        match channel.trim().parse::<u8>() {
+            Ok(channel @ 1..=13) => match manager.set_channel(channel) {
+                Ok(()) => log::info!("Switched to channel {}", channel),
+                Err(e) => log::warn!("Failed to switch channel: {:?}", e),
+            },
+            _ => log::warn!("Usage: channel <1-13>"),
+        }
+        return;
+    }
+    let destination = rover.unwrap_or(BROADCAST_ADDRESS);
+    if let Some(text) = line.strip_prefix('X').filter(|text| !text.is_empty()) {
+        let mut frame = heapless::Vec::<u8, { MAX_LINE_LENGTH + 2 }>::new();
+        let _ = frame.extend_from_slice(&FRAME_MAGIC);
+        let _ = frame.push(FRAME_ACCESSORY);
+        let _ = frame.extend_from_slice(text.as_bytes());
+        if let Err(e) = sender.send_async(&destination, &frame).await {
+            log::warn!("Failed to send accessory message: {:?}", e);
+        }
+        return;
+    }
+    let [command] = line.as_bytes() else {
+        if !line.is_empty() {
+            log::warn!("Unknown input {}", line);
+        }
+        return;
+    };
+    let frame = [FRAME_MAGIC[0], FRAME_MAGIC[1], FRAME_COMMAND, *command];
+    if let Err(e) = sender.send_async(&destination, &frame).await {
+        log::warn!("Failed to send command: {:?}", e);
+    }
+}
+
+#[esp_hal_embassy::main]
+async fn main(_spawner: Spawner) {
+    let peripherals = esp_hal::init(esp_hal::Config::default());
+    let io = esp_hal::gpio::Io::new(peripherals.GPIO, peripherals.IO_MUX);
+
+    init_heap();
+    let timg0 = TimerGroup::new(peripherals.TIMG0);
+    esp_hal_embassy::init(timg0.timer0);
+    esp_println::logger::init_logger_from_env();
+
+    let timg1 = TimerGroup::new(peripherals.TIMG1);
+    let wifi_init = esp_wifi::init(
+        esp_wifi::EspWifiInitFor::Wifi,
+        timg1.timer0,
+        Rng::new(peripherals.RNG),
+        peripherals.RADIO_CLK,
+    )
+    .unwrap();
+    let esp_now = EspNow::new(&wifi_init, peripherals.WIFI).unwrap();
+    log::info!(
+        "ESP-NOW dongle ready on channel 1, MAC {:02x?}",
+        esp_hal::efuse::Efuse::get_mac_address()
+    );
+    let (manager, mut sender, mut receiver) = esp_now.split();
+
+    let mut rx = UartRx::new_async(peripherals.UART0, io.pins.gpio3).unwrap();
+    let mut buffer = [0u8; 32];
+    let mut line = heapless::Vec::<u8, MAX_LINE_LENGTH>::new();
+    let mut rover: Option<[u8; 6]> = None;
+    loop {
+        match select(rx.read_async(&mut buffer), receiver.receive_async()).await {
+            Either::First(Ok(read)) => {
+                for &byte in &buffer[..read] {
+                    match byte {
+                        b'\r' | b'\n' => {
+                            if let Ok(text) = core::str::from_utf8(&line) {
+                                handle_line(text, &manager, &mut sender, rover).await;
+                            }
+                            line.clear();
+                        }
+                        _ => {
+                            if line.push(byte).is_err() {
+                                log::warn!("Line too long, discarding");
+                                line.clear();
+                            }
+                        }
+                    }
+                }
+            }
+            Either::First(Err(e)) => log::warn!("Serial read error: {:?}", e),
+            Either::Second(received) => {
+                let Some((kind, payload)) = received
+                    .data()
+                    .strip_prefix(&FRAME_MAGIC)
+                    .and_then(|frame| frame.split_first())
+                else {
+                    continue;
+                };
+                if *kind != FRAME_TELEMETRY {
+                    continue;
+                }
+                let source = received.info.src_address;
+                if rover != Some(source) {
+                    log::info!("Rover found at {:02x?}", source);
+                    if !manager.peer_exists(&source) {
+                        let _ = manager.add_peer(PeerInfo {
+                            peer_address: source,
+                            lmk: None,
+                            channel: None,
+                            encrypt: false,
+                        });
+                    }
+                    rover = Some(source);
+                }
+                if let Ok(json) = core::str::from_utf8(payload) {
+                    esp_println::println!("{}", json);
+                }
+            }
+        }
+    }
+}